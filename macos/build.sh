@@ -0,0 +1,25 @@
+#!/bin/sh
+# Builds the module and the tools for Intel and Apple silicon Macs and joins them in universal
+# binaries in target/universal. Copy pam_oauth2_df.so to /usr/local/lib/pam, as /usr/lib/pam is
+# read-only, and name it by its full path in /etc/pam.d/sshd or /etc/pam.d/sudo.
+set -eu
+
+targets="x86_64-apple-darwin aarch64-apple-darwin"
+for target in $targets; do
+    cargo build --release --target "$target" "$@"
+done
+
+mkdir -p target/universal
+universal() {
+    output=$1
+    name=$2
+    set --
+    for target in $targets; do
+        set -- "$@" "target/$target/release/$name"
+    done
+    lipo -create -output "target/universal/$output" "$@"
+}
+universal pam_oauth2_df.so libpam_oauth2_df.dylib
+universal ssh-oauth2 ssh-oauth2
+universal ssh-oauth2-keys ssh-oauth2-keys
+universal ssh-oauth2-brokerd ssh-oauth2-brokerd
@@ -0,0 +1,91 @@
+//! The local accounts: their passwd entries, and their email addresses for matching tokens with
+//! `match_by=email`.
+
+use std::{
+    ffi::{CStr, CString},
+    fs, io,
+    path::PathBuf,
+};
+
+/// The passwd entry of a local account.
+pub(crate) struct Account {
+    pub(crate) uid: libc::uid_t,
+    pub(crate) gid: libc::gid_t,
+    pub(crate) home: PathBuf,
+}
+
+/// The account of `user`, which must exist.
+pub(crate) fn passwd(user: &str) -> io::Result<Account> {
+    lookup(user)?.ok_or_else(|| io::Error::other(format!("unknown user: {}", user)))
+}
+
+/// The account of `user`, `None` when there is none.
+pub(crate) fn lookup(user: &str) -> io::Result<Option<Account>> {
+    let name = CString::new(user).map_err(io::Error::other)?;
+    // SAFETY: `name` is a valid NUL-terminated string and the returned entry is only read
+    // before any other passwd lookup can overwrite it.
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Ok(None);
+    }
+    // SAFETY: checked for NULL above; pw_dir is NUL-terminated.
+    Ok(Some(unsafe {
+        Account {
+            uid: (*passwd).pw_uid,
+            gid: (*passwd).pw_gid,
+            home: PathBuf::from(
+                CStr::from_ptr((*passwd).pw_dir)
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+        }
+    }))
+}
+
+/// Email address of `user`: the `email_map` entry, or else the first comma-separated field of
+/// the GECOS field that looks like an address.
+pub(crate) fn email_of(user: &str, email_map: Option<&str>) -> io::Result<Option<String>> {
+    if let Some(email_map) = email_map {
+        return Ok(entries(&fs::read_to_string(email_map)?)
+            .find(|(name, _)| *name == user)
+            .map(|(_, email)| email.to_string()));
+    }
+    let Ok(name) = CString::new(user) else {
+        return Ok(None);
+    };
+    // SAFETY: `name` is a valid NUL-terminated string and the returned entry is only read
+    // before any other passwd lookup can overwrite it.
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Ok(None);
+    }
+    // SAFETY: checked for NULL above.
+    let gecos = unsafe { (*passwd).pw_gecos };
+    if gecos.is_null() {
+        return Ok(None);
+    }
+    // SAFETY: checked for NULL above; pw_gecos is NUL-terminated.
+    let gecos = unsafe { CStr::from_ptr(gecos) }.to_string_lossy();
+    Ok(gecos
+        .split(',')
+        .map(str::trim)
+        .find(|field| field.contains('@'))
+        .map(str::to_string))
+}
+
+/// Account of `email` in `email_map`, for logins that leave the user to the token.
+pub(crate) fn user_of(email: &str, email_map: &str) -> io::Result<Option<String>> {
+    Ok(entries(&fs::read_to_string(email_map)?)
+        .find(|(_, address)| address.eq_ignore_ascii_case(email))
+        .map(|(name, _)| name.to_string()))
+}
+
+fn entries(text: &str) -> impl Iterator<Item = (&str, &str)> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?, fields.next()?))
+        })
+}
@@ -0,0 +1,192 @@
+//! Audit trail of the login attempts: one JSON object per attempt, appended to a file or sent to
+//! a datagram socket for SIEM ingestion, and counted in the metrics.
+
+use crate::{
+    config::{Config, Flow},
+    error::Error,
+    hooks,
+    i18n::Reason,
+    login_hook::LoginHooks,
+    metrics::Metrics,
+};
+use log::warn;
+use pam::{
+    constants::PamResultCode,
+    items::{RHost, Service, User},
+    module::PamHandle,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    ffi::CStr,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::{ToSocketAddrs, UdpSocket},
+    os::unix::{fs::OpenOptionsExt, net::UnixDatagram},
+    time::Instant,
+};
+
+/// One line of the audit log, also given to the login hooks.
+#[derive(Debug, Serialize)]
+pub(crate) struct Record<'a> {
+    /// Unix time at which the attempt started.
+    pub(crate) timestamp: u64,
+    pub(crate) user: Option<String>,
+    pub(crate) rhost: Option<String>,
+    pub(crate) service: Option<String>,
+    /// Provider name when `providers` are configured, or else the issuer.
+    pub(crate) idp: Option<&'a str>,
+    /// PAM result, e.g. `PAM_SUCCESS`.
+    pub(crate) result: String,
+    /// [`Error::class`] of what failed the attempt, or that an offline login got past.
+    pub(crate) error: Option<&'a str>,
+    pub(crate) duration_ms: u128,
+}
+
+/// A login attempt in progress, written to `audit_log` and the metrics and handed to the login
+/// hooks once it has finished.
+pub(crate) struct Attempt {
+    audit_log: Option<String>,
+    metrics: Option<Metrics>,
+    hooks: Option<LoginHooks>,
+    timestamp: u64,
+    started: Instant,
+    idp: Option<String>,
+    flow: Flow,
+    error: Option<&'static str>,
+    reason: Option<Reason>,
+    /// Verified claims of the token, once the login got that far.
+    claims: Option<Value>,
+}
+
+impl Attempt {
+    pub(crate) fn start(config: &Config) -> Self {
+        let mut attempt = Self {
+            audit_log: config.audit_log.clone(),
+            metrics: Metrics::from_config(config),
+            hooks: None,
+            timestamp: hooks::unix_time(),
+            started: Instant::now(),
+            idp: None,
+            flow: config.flow,
+            error: None,
+            reason: None,
+            claims: None,
+        };
+        attempt.set_config(config);
+        attempt
+    }
+
+    /// Records the provider, flow and hooks picked for the attempt.
+    pub(crate) fn set_config(&mut self, config: &Config) {
+        self.idp = config.provider.clone().or_else(|| config.issuer());
+        self.flow = config.flow;
+        self.hooks = LoginHooks::from_config(config);
+    }
+
+    /// Records the verified claims of the token, for the login hooks.
+    pub(crate) fn set_claims(&mut self, claims: &Value) {
+        self.claims = Some(claims.clone());
+    }
+
+    /// Records the class of `err` and the reason to tell the user.
+    pub(crate) fn note(&mut self, err: &Error) {
+        self.error = Some(err.class());
+        self.reason = Reason::of(err);
+    }
+
+    /// Records and reports `err`, for use as `pam_try!(result.map_err(|err| attempt.fail(err)))`.
+    pub(crate) fn fail(&mut self, err: Error) -> PamResultCode {
+        self.note(&err);
+        err.report()
+    }
+
+    /// Records a failure that is not an [`Error`], such as a token of another user.
+    pub(crate) fn fail_with(&mut self, class: &'static str, reason: Reason) {
+        self.error = Some(class);
+        self.reason = Some(reason);
+    }
+
+    /// [`Error::class`] of what failed the attempt, if it did.
+    pub(crate) fn error(&self) -> Option<&'static str> {
+        self.error
+    }
+
+    /// Why the attempt failed, if the user is to be told.
+    pub(crate) fn reason(&self) -> Option<Reason> {
+        self.reason
+    }
+
+    /// Writes the record of the attempt ending with `result`. Failures are only logged, as the
+    /// login itself has been decided.
+    pub(crate) async fn finish(self, pamh: &PamHandle, result: &PamResultCode) {
+        let error = match self.error {
+            None if !matches!(result, PamResultCode::PAM_SUCCESS) => Some("pam"),
+            error => error,
+        };
+        let duration = self.started.elapsed();
+        if let Some(metrics) = &self.metrics {
+            metrics.record(self.flow.as_str(), error, duration).await;
+        }
+        if self.audit_log.is_none() && self.hooks.is_none() {
+            return;
+        }
+        let record = Record {
+            timestamp: self.timestamp,
+            user: item(pamh.get_item::<User>().ok().flatten().map(|user| user.0)),
+            rhost: item(pamh.get_item::<RHost>().ok().flatten().map(|rhost| rhost.0)),
+            service: item(
+                pamh.get_item::<Service>()
+                    .ok()
+                    .flatten()
+                    .map(|service| service.0),
+            ),
+            idp: self.idp.as_deref(),
+            result: format!("{:?}", result),
+            error,
+            duration_ms: duration.as_millis(),
+        };
+        if let Some(audit_log) = &self.audit_log {
+            if let Err(err) = write(audit_log, &record) {
+                warn!("Failed to write the audit record to {}: {}", audit_log, err);
+            }
+        }
+        if let Some(hooks) = &self.hooks {
+            let success = matches!(result, PamResultCode::PAM_SUCCESS);
+            hooks.run(success, &record, self.claims.as_ref()).await;
+        }
+    }
+}
+
+fn item(value: Option<&CStr>) -> Option<String> {
+    value.map(|value| value.to_string_lossy().into_owned())
+}
+
+/// Sends the record to `udp://<host>:<port>` or `unix:<path>`, or appends it to the file.
+fn write(audit_log: &str, record: &Record) -> io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    if let Some(addr) = audit_log.strip_prefix("udp://") {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+        let local = if addr.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        UdpSocket::bind(local)?.send_to(&line, addr)?;
+    } else if let Some(path) = audit_log.strip_prefix("unix:") {
+        UnixDatagram::unbound()?.send_to(&line, path)?;
+    } else {
+        line.push(b'\n');
+        // A single write keeps the lines of concurrent logins apart.
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(audit_log)?
+            .write_all(&line)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! Loopback redirect of the authorization code flow for native apps (RFC 8252, section 7.3),
+//! protected with PKCE (RFC 7636).
+
+use crate::error::{Error, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use log::{debug, warn};
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{collections::HashMap, io, net, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time,
+};
+use url::Url;
+
+const CALLBACK_PATH: &str = "/callback";
+
+/// Time a connected browser gets to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Authorization request waiting for the browser to be redirected back to a local port.
+#[derive(Debug)]
+pub struct AuthorizationRequest {
+    /// URL to open in a browser on the same machine.
+    pub authorize_url: String,
+    pub(crate) redirect_uri: String,
+    state: String,
+    /// PKCE secret sent with the code; only its S256 challenge is in the authorize URL.
+    pub(crate) code_verifier: String,
+    listener: net::TcpListener,
+}
+
+impl AuthorizationRequest {
+    /// Listens on `port` of the loopback interface, any free port for 0, and builds the URL of
+    /// the authorization endpoint `authorize_url` with `params`.
+    pub(crate) fn new(authorize_url: &str, port: u16, params: &[(&str, &str)]) -> Result<Self> {
+        let listener = net::TcpListener::bind(("127.0.0.1", port))
+            .map_err(|err| Error::Config(format!("failed to listen on port {}: {}", port, err)))?;
+        let port = listener
+            .local_addr()
+            .map_err(|err| Error::Config(format!("failed to listen: {}", err)))?
+            .port();
+        let redirect_uri = format!("http://127.0.0.1:{}{}", port, CALLBACK_PATH);
+        let state = random_token()?;
+        let code_verifier = random_token()?;
+        let code_challenge =
+            URL_SAFE_NO_PAD.encode(digest(&SHA256, code_verifier.as_bytes()).as_ref());
+        let mut url = Url::parse(authorize_url)
+            .map_err(|err| Error::Config(format!("invalid authorize_url: {}", err)))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("redirect_uri", &redirect_uri)
+            .append_pair("state", &state)
+            .append_pair("code_challenge", &code_challenge)
+            .append_pair("code_challenge_method", "S256")
+            .extend_pairs(params);
+        Ok(Self {
+            authorize_url: url.into(),
+            redirect_uri,
+            state,
+            code_verifier,
+            listener,
+        })
+    }
+
+    /// Waits up to `timeout` for the redirect and returns the authorization code, giving up with
+    /// `expired_token`.
+    pub(crate) async fn wait_for_code(&self, timeout: Duration) -> Result<String> {
+        let failed = |err: io::Error| Error::Config(format!("failed to listen: {}", err));
+        self.listener.set_nonblocking(true).map_err(failed)?;
+        let listener =
+            TcpListener::from_std(self.listener.try_clone().map_err(failed)?).map_err(failed)?;
+        let redirect = async {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => match self.handle(stream).await {
+                        Ok(Some(result)) => return result,
+                        Ok(None) => {}
+                        Err(err) => debug!("Redirect connection error: {}", err),
+                    },
+                    Err(err) => debug!("Redirect connection error: {}", err),
+                }
+            }
+        };
+        time::timeout(timeout, redirect).await.unwrap_or_else(|_| {
+            Err(Error::idp(
+                "expired_token".to_string(),
+                Some("no redirect arrived before the authorization timed out".to_string()),
+            ))
+        })
+    }
+
+    /// Answers one browser request; `None` for requests other than the redirect, such as the
+    /// favicon, and for redirects with another `state`, which any local process can send.
+    async fn handle(&self, mut stream: TcpStream) -> io::Result<Option<Result<String>>> {
+        let mut request_line = String::new();
+        time::timeout(
+            READ_TIMEOUT,
+            BufReader::new(&mut stream).read_line(&mut request_line),
+        )
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        let target = request_line.split_whitespace().nth(1).unwrap_or_default();
+        let url = match Url::parse(&format!("http://127.0.0.1{}", target)) {
+            Ok(url) if url.path() == CALLBACK_PATH => url,
+            _ => {
+                respond(&mut stream, "404 Not Found", "Not found.").await?;
+                return Ok(None);
+            }
+        };
+        let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        if query.get("state") != Some(&self.state) {
+            warn!("Ignoring an authorization response with an unexpected state");
+            respond(&mut stream, "400 Bad Request", "Unexpected state.").await?;
+            return Ok(None);
+        }
+        let result = if let Some(error) = query.get("error") {
+            Err(Error::idp(
+                error.clone(),
+                query.get("error_description").cloned(),
+            ))
+        } else {
+            query
+                .get("code")
+                .cloned()
+                .ok_or_else(|| Error::Token("authorization response has no code".to_string()))
+        };
+        let text = match &result {
+            Ok(_) => "Login complete. You can close this window.",
+            Err(_) => "Login failed. You can close this window.",
+        };
+        respond(&mut stream, "200 OK", text).await?;
+        Ok(Some(result))
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, text: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        text.len(),
+        text
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+/// 256 random bits, base64url encoded.
+pub(crate) fn random_token() -> Result<String> {
+    let mut bytes = [0; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::Config("no random numbers available".to_string()))?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
@@ -0,0 +1,120 @@
+//! Keys and principals for sshd's `AuthorizedKeysCommand` and `AuthorizedPrincipalsCommand`,
+//! taken from the claims of a valid token, so that key and certificate logins are vouched for by
+//! the identity provider as well.
+
+use crate::{
+    claims,
+    config::Config,
+    device_flow::{DeviceFlowClient, TokenResponse},
+    error::{Error, Result},
+    groups,
+    hooks::{self, CachedToken},
+    token_store::TokenStore,
+};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// OpenSSH public keys in the `ssh_keys_claim` of `user`'s token.
+///
+/// The token is `access_token` when given, checked by introspection or at the userinfo endpoint,
+/// and otherwise the one cached by the last login, refreshed once it has expired.
+pub async fn authorized_keys(
+    config: &Config,
+    user: &str,
+    access_token: Option<&str>,
+) -> Result<Vec<String>> {
+    let claims = verified_claims(config, user, access_token).await?;
+    Ok(strings(claims.get(&config.ssh_keys_claim)))
+}
+
+/// Certificate principals of `user`: the `principals_claim` of the token as for
+/// [`authorized_keys`], or the user alone.
+pub async fn authorized_principals(
+    config: &Config,
+    user: &str,
+    access_token: Option<&str>,
+) -> Result<Vec<String>> {
+    let claims = verified_claims(config, user, access_token).await?;
+    Ok(match &config.principals_claim {
+        Some(claim) => strings(claims.get(claim)),
+        None => vec![user.to_string()],
+    })
+}
+
+/// Claims of the token for `user`, checked against the username and the authorization policy
+/// like a login.
+async fn verified_claims(config: &Config, user: &str, access_token: Option<&str>) -> Result<Value> {
+    let config = config.for_user(user, &groups::of_user(user))?;
+    let (token, claims) = match access_token {
+        Some(access_token) => {
+            let (_, client) = DeviceFlowClient::from_providers(&config).await?;
+            let token = TokenResponse {
+                access_token: access_token.to_string(),
+                refresh_token: None,
+                token_type: "Bearer".to_string(),
+                id_token: None,
+                scope: None,
+                session_state: None,
+                expires_in: None,
+                extra: BTreeMap::new(),
+            };
+            let claims = client.claims(&token).await?;
+            (token, claims)
+        }
+        None => cached_claims(&config, user).await?,
+    };
+    if !claims::matches_user(&config, &token, &claims, user) {
+        return Err(Error::Authorization(format!(
+            "token does not belong to {}",
+            user
+        )));
+    }
+    claims::authorize(&config, &claims)?;
+    Ok(claims)
+}
+
+async fn cached_claims(config: &Config, user: &str) -> Result<(TokenResponse, Value)> {
+    if !config.token_cache {
+        return Err(Error::Config(
+            "token_cache is required without an access token".to_string(),
+        ));
+    }
+    let token_store = TokenStore::new(&config.token_cache_dir, &config.token_cache_key);
+    let cached: CachedToken = token_store
+        .load(user)?
+        .ok_or_else(|| Error::Authorization(format!("no cached token for {}", user)))?;
+    if cached
+        .expires_at
+        .is_none_or(|expires_at| expires_at > hooks::unix_time())
+    {
+        return Ok((cached.token, cached.claims));
+    }
+    let refresh_token = cached
+        .token
+        .refresh_token
+        .as_deref()
+        .ok_or_else(|| Error::Authorization(format!("cached token of {} has expired", user)))?;
+    let config = Config {
+        provider: cached.provider.clone(),
+        ..config.clone()
+    };
+    let (config, client) = DeviceFlowClient::from_providers(&config).await?;
+    let token = client.refresh_token(refresh_token).await?;
+    let claims = client.claims(&token).await?;
+    let cached = CachedToken::new(token, claims, config.provider);
+    token_store.store(user, &cached)?;
+    Ok((cached.token, cached.claims))
+}
+
+/// A string claim or the strings of an array claim.
+fn strings(claim: Option<&Value>) -> Vec<String> {
+    match claim {
+        Some(Value::String(value)) => vec![value.clone()],
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,93 @@
+//! `ssh-oauth2-brokerd`, the broker that runs the device flow for the PAM modules configured with
+//! `broker=<socket>`, so that sshd makes no requests to the identity provider itself.
+
+use log::LevelFilter;
+use pam_oauth2_df::{init_stderr_logger, run_broker, Config, Error, Result};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, ErrorKind},
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::Path,
+    process::ExitCode,
+};
+use tokio::net::UnixListener;
+
+/// Socket the broker listens on without `socket=`.
+const DEFAULT_SOCKET: &str = "/run/ssh-oauth2/broker.sock";
+
+const USAGE: &str = "\
+Usage: ssh-oauth2-brokerd [socket=<path>] [<key>=<value>]...
+
+Listens on the unix socket, /run/ssh-oauth2/broker.sock by default, and runs the device flow of
+the logins of the PAM modules configured with broker=<path>. The other arguments are those of
+the module, e.g. config=/etc/pam_oauth2.toml with the provider and the client secrets, which the
+module then does not need. Only root can connect to the socket.";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    match run(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("ssh-oauth2-brokerd: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: &[String]) -> Result<()> {
+    let mut args: HashMap<&str, &str> = args
+        .iter()
+        .map(|arg| arg.split_once('=').unwrap_or((arg, "")))
+        .collect();
+    let socket = args.remove("socket").unwrap_or(DEFAULT_SOCKET);
+    let config = Config::from_args(&args)?;
+    init_stderr_logger(config.log_level().max(LevelFilter::Info));
+
+    let listener = listen(Path::new(socket))
+        .map_err(|err| Error::Config(format!("failed to listen on {}: {}", socket, err)))?;
+    log::info!("Listening on {}", socket);
+    run_broker(listener, config)
+        .await
+        .map_err(|err| Error::Network(err.to_string()))
+}
+
+/// Binds `socket` for root only, replacing the one a previous broker left. The directory of the
+/// socket must be root's alone, so that no one else can replace the socket either.
+fn listen(socket: &Path) -> io::Result<UnixListener> {
+    let dir = match socket.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    let euid = unsafe { libc::geteuid() };
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != euid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::other(format!(
+            "{} is not a directory of uid {} with mode 0700",
+            dir.display(),
+            euid
+        )));
+    }
+    match fs::remove_file(socket) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    // The socket is created with mode 0600 rather than changed to it once others could connect.
+    // SAFETY: umask has no preconditions; the broker starts no other threads creating files
+    // meanwhile.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    // SAFETY: as above.
+    unsafe { libc::umask(umask) };
+    listener
+}
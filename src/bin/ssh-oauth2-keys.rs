@@ -0,0 +1,66 @@
+//! `AuthorizedKeysCommand` and `AuthorizedPrincipalsCommand` for sshd, printing the SSH keys or
+//! certificate principals of a user from the claims of their OAuth token.
+
+use log::LevelFilter;
+use pam_oauth2_df::{authorized_keys, authorized_principals, init_stderr_logger, Config, Result};
+use std::{collections::HashMap, env, process::ExitCode};
+
+/// Access token to check instead of the cached one.
+const ACCESS_TOKEN_ENV: &str = "OAUTH2_ACCESS_TOKEN";
+
+const USAGE: &str = "\
+Usage: ssh-oauth2-keys [--principals] <user> [<key>=<value>]...
+
+Takes the same arguments as the PAM module after the user, e.g. in sshd_config
+
+    AuthorizedKeysCommand /usr/bin/ssh-oauth2-keys %u config=/etc/pam_oauth2.toml
+    AuthorizedKeysCommandUser root
+
+and prints the keys in the ssh_keys_claim of the user's token, one per line; with --principals,
+for AuthorizedPrincipalsCommand, the principals_claim or the user. The token is the one cached
+by the last login (token_cache), or the access token in $OAUTH2_ACCESS_TOKEN.";
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    let principals = args.first().is_some_and(|arg| arg == "--principals");
+    if principals {
+        args.remove(0);
+    }
+    let Some(user) = (!args.is_empty()).then(|| args.remove(0)) else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    match run(&user, principals, &args).await {
+        Ok(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("ssh-oauth2-keys: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(user: &str, principals: bool, args: &[String]) -> Result<Vec<String>> {
+    let args: HashMap<&str, &str> = args
+        .iter()
+        .map(|arg| arg.split_once('=').unwrap_or((arg, "")))
+        .collect();
+    let config = Config::from_args(&args)?;
+    init_stderr_logger(config.log_level().max(LevelFilter::Warn));
+
+    let access_token = env::var(ACCESS_TOKEN_ENV).ok();
+    if principals {
+        authorized_principals(&config, user, access_token.as_deref()).await
+    } else {
+        authorized_keys(&config, user, access_token.as_deref()).await
+    }
+}
@@ -0,0 +1,127 @@
+//! Runs the device flow with the PAM module configuration and prints the tokens and claims, so the
+//! identity provider setup can be checked without going through sshd.
+
+use log::LevelFilter;
+use pam_oauth2_df::{init_stderr_logger, Config, DeviceFlowClient, Flow, Result, TokenResponse};
+use serde_json::json;
+use std::{collections::HashMap, env, process::ExitCode, time::Duration};
+
+/// Time to complete `flow=authorization_code` without `max_auth_time`.
+const AUTHORIZATION_CODE_TIMEOUT: Duration = Duration::from_secs(300);
+
+const USAGE: &str = "\
+Usage: ssh-oauth2 [<key>=<value>]...
+
+Takes the same arguments as the PAM module, with service=<name> for the [service.<name>]
+section of the configuration file, e.g.
+
+    ssh-oauth2 config=/etc/pam_oauth2.toml service=sshd
+    ssh-oauth2 issuer=https://idp.example.com/realms/ssh client_id=ssh debug
+
+prints the verification URI to stderr and, once the login completes, the tokens and the verified
+claims as JSON to stdout.";
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    match run(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("ssh-oauth2: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: &[String]) -> Result<()> {
+    let args: HashMap<&str, &str> = args
+        .iter()
+        .map(|arg| arg.split_once('=').unwrap_or((arg, "")))
+        .collect();
+    let config = Config::from_args(&args)?;
+    init_stderr_logger(config.log_level().max(LevelFilter::Info));
+
+    let (config, client) = DeviceFlowClient::from_providers(&config).await?;
+    if let Some(provider) = &config.provider {
+        eprintln!("Using provider {}", provider);
+    }
+    let token = match config.flow {
+        Flow::Device => device_flow(&config, &client).await?,
+        Flow::AuthorizationCode => {
+            let request = client.authorize_browser(config.redirect_port)?;
+            eprintln!(
+                "Please open {} in a browser to login.",
+                request.authorize_url
+            );
+            let timeout = config
+                .max_auth_time
+                .map_or(AUTHORIZATION_CODE_TIMEOUT, Duration::from_secs);
+            client.complete_authorization(&request, timeout).await?
+        }
+        Flow::Ciba => {
+            // The PAM module asks for the login user; here it is the one running the command.
+            let user = env::var("USER").unwrap_or_default();
+            let login_hint = template(&config.login_hint, &user);
+            let binding_message = config
+                .binding_message
+                .as_deref()
+                .map(|message| template(message, &user));
+            let auth = client
+                .authorize_backchannel(&login_hint, binding_message.as_deref())
+                .await?;
+            eprintln!("Please approve the login request sent to {}.", login_hint);
+            client.poll_backchannel_token(&auth).await?
+        }
+    };
+    let claims = client.claims(&token).await?;
+    let token = if config.exchange_audience.is_some() || !config.exchange_scope.is_empty() {
+        client
+            .exchange_token(
+                &token,
+                config.exchange_audience.as_deref(),
+                &config.exchange_scope,
+            )
+            .await?
+    } else {
+        token
+    };
+    let username_field = config.username_field(&token, &claims);
+    match config.username(&token, &claims) {
+        Some(username) => eprintln!("PAM user: {} (from {})", username, username_field),
+        None => eprintln!(
+            "The claims have no {} claim; the PAM module would reject this login",
+            username_field
+        ),
+    }
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&json!({ "token": token, "claims": claims }))
+            .unwrap_or_default()
+    );
+    Ok(())
+}
+
+fn template(template: &str, user: &str) -> String {
+    template.replace("{user}", user)
+}
+
+async fn device_flow(config: &Config, client: &DeviceFlowClient) -> Result<TokenResponse> {
+    let device_auth = client.authorize_device().await?;
+    let messages = config.messages()?;
+    let qr_options = config.qr_options();
+    let qr_options = (!config.no_qr).then_some(&qr_options);
+    let instructions = match &config.message_template {
+        Some(template) => device_auth.render(template, qr_options),
+        None => device_auth
+            .instructions(&messages, qr_options)
+            .or_else(|| device_auth.instructions(&messages, None))
+            .unwrap_or_default(),
+    };
+    eprintln!("{}", instructions);
+    eprintln!("Waiting for the authorization to complete...");
+    client.poll_token(&device_auth).await
+}
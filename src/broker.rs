@@ -0,0 +1,255 @@
+//! `ssh-oauth2-brokerd`, which runs the device flow of the logins for the PAM module over a unix
+//! socket, so that sshd's PAM stack makes no requests to the provider and the client secrets
+//! live only in the broker.
+//!
+//! The module sends a [`Request`] as a line of JSON. The broker answers with a line for the
+//! device authorization and, once the user has logged in, a line with the verified token; or
+//! with an error line at any point. A module that closes the connection cancels the flow.
+
+use crate::{
+    config::Config,
+    dbus::Signals,
+    device_flow::{DeviceAuthResponse, DeviceFlowClient, TokenResponse},
+    error::{Error, Result},
+    groups,
+    hooks::{self, PamContext},
+};
+use log::{info, warn};
+use pam::conv::Conv;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{fmt, sync::Arc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    net::{unix::OwnedReadHalf, UnixListener, UnixStream},
+};
+
+/// The login the module asks the broker for.
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+    /// The PAM user, when the application knows it.
+    user: Option<String>,
+    #[serde(flatten)]
+    context: PamContext,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Reply {
+    /// The device authorization to show, without its device code.
+    Device(DeviceAuthResponse),
+    Token(Login),
+    Error {
+        class: String,
+        message: String,
+        /// The OAuth error of an `idp` error.
+        error: Option<String>,
+        description: Option<String>,
+    },
+}
+
+/// A login the broker completed.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Login {
+    pub(crate) token: TokenResponse,
+    /// The verified claims of the token.
+    pub(crate) claims: Value,
+    /// The provider of `providers` that the broker chose.
+    pub(crate) provider: Option<String>,
+}
+
+impl From<&Error> for Reply {
+    fn from(err: &Error) -> Self {
+        let (error, description) = match err {
+            Error::IdP { error, description } => (Some(error.clone()), description.clone()),
+            _ => (None, None),
+        };
+        Self::Error {
+            class: err.class().to_string(),
+            message: err.to_string(),
+            error,
+            description,
+        }
+    }
+}
+
+/// The error the broker reported, as close as its class allows.
+fn error(
+    class: &str,
+    message: String,
+    error: Option<String>,
+    description: Option<String>,
+) -> Error {
+    match class {
+        "idp" => Error::idp(error.unwrap_or_default(), description),
+        "config" => Error::Config(message),
+        "token" => Error::Token(message),
+        "authorization" => Error::Authorization(message),
+        "cancelled" => Error::Cancelled,
+        _ => Error::Network(message),
+    }
+}
+
+/// Runs the device flow of `user` through the broker of `config`, showing the instructions in
+/// the conversation.
+pub(crate) async fn device_flow(
+    conv: &Conv<'_>,
+    config: &Config,
+    context: &PamContext,
+    user: Option<&str>,
+) -> Result<Login> {
+    let socket = config.broker.as_deref().unwrap_or_default();
+    // An unreachable broker is an unreachable provider, so `offline_login` applies.
+    let unreachable =
+        |err: &dyn fmt::Display| Error::Network(format!("broker {}: {}", socket, err));
+    let stream = UnixStream::connect(socket)
+        .await
+        .map_err(|err| unreachable(&err))?;
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let request = Request {
+        user: user.map(str::to_string),
+        context: context.clone(),
+    };
+    send(&mut write, &request)
+        .await
+        .map_err(|err| unreachable(&err))?;
+    let device = match receive(&mut lines).await? {
+        Reply::Device(device) => device,
+        _ => return Err(unreachable(&"unexpected reply")),
+    };
+    info!(
+        "Device authorization started by the broker: user_code={}",
+        device.user_code
+    );
+    hooks::show_device_auth(conv, config, &device)?;
+    let signals = Signals::from_config(config, context, user);
+    if let Some(signals) = &signals {
+        signals.started(&device).await;
+    }
+    // Dropping the connection with the poller, once the login is over or abandoned, lets the
+    // broker stop polling.
+    let poller = tokio::spawn(async move {
+        let _write = write;
+        match receive(&mut lines).await? {
+            Reply::Token(login) => Ok(login),
+            _ => Err(Error::Network("unexpected reply of the broker".to_string())),
+        }
+    });
+    let login = hooks::wait_for_poller(conv, config, &device, poller)
+        .await
+        .and_then(|login| hooks::confirm_user_code(conv, config, &device).map(|_| login));
+    if let Some(signals) = &signals {
+        signals
+            .ended(&device, login.as_ref().err().map(Error::class))
+            .await;
+    }
+    login
+}
+
+async fn send<T: Serialize>(write: &mut (impl AsyncWrite + Unpin), message: &T) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    write
+        .write_all(&line)
+        .await
+        .map_err(|err| Error::Network(err.to_string()))
+}
+
+/// The next reply of the broker; an error reply is returned as the error.
+async fn receive(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Result<Reply> {
+    let line = lines
+        .next_line()
+        .await
+        .map_err(|err| Error::Network(format!("broker: {}", err)))?
+        .ok_or_else(|| Error::Network("the broker closed the connection".to_string()))?;
+    match serde_json::from_str(&line)? {
+        Reply::Error {
+            class,
+            message,
+            error: oauth_error,
+            description,
+        } => Err(error(&class, message, oauth_error, description)),
+        reply => Ok(reply),
+    }
+}
+
+/// Serves the logins of the modules connecting to `listener` with the provider of `config`,
+/// until the listener fails. Only connections of root and of the user the broker runs as are
+/// served, whatever the permissions of the socket.
+pub async fn run_broker(listener: UnixListener, config: Config) -> std::io::Result<()> {
+    let config = Arc::new(config);
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    let euid = unsafe { libc::geteuid() };
+    loop {
+        let (stream, _) = listener.accept().await?;
+        match stream.peer_cred() {
+            Ok(cred) if cred.uid() == 0 || cred.uid() == euid => {}
+            Ok(cred) => {
+                warn!("Refusing the broker connection of uid {}", cred.uid());
+                continue;
+            }
+            Err(err) => {
+                warn!("Refusing a broker connection without credentials: {}", err);
+                continue;
+            }
+        }
+        let config = config.clone();
+        tokio::spawn(async move {
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            match serve(&config, &mut lines, &mut write).await {
+                Ok(()) => {}
+                Err(Error::Cancelled) => info!("The module abandoned the login"),
+                Err(err) => {
+                    warn!("Broker login failed: {}", err);
+                    let _ = send(&mut write, &Reply::from(&err)).await;
+                }
+            }
+        });
+    }
+}
+
+/// Runs the device flow that the module on the connection asks for.
+async fn serve(
+    config: &Config,
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    write: &mut (impl AsyncWrite + Unpin),
+) -> Result<()> {
+    let line = lines
+        .next_line()
+        .await
+        .map_err(|err| Error::Network(err.to_string()))?
+        .ok_or(Error::Cancelled)?;
+    let request: Request = serde_json::from_str(&line)?;
+    let config = match &request.user {
+        Some(user) => config.for_user(user, &groups::of_user(user))?,
+        None => config.clone(),
+    };
+    let config = hooks::with_context(config, &request.context, request.user.as_deref());
+    let (config, client) = DeviceFlowClient::from_providers(&config).await?;
+    let device = client.authorize_device().await?;
+    info!(
+        "Device authorization started for {}: user_code={}",
+        request.user.as_deref().unwrap_or("an unknown user"),
+        device.user_code
+    );
+    let shown = DeviceAuthResponse {
+        device_code: String::new(),
+        ..device.clone()
+    };
+    send(write, &Reply::Device(shown)).await?;
+    let token = tokio::select! {
+        token = client.poll_token(&device) => token?,
+        // The module sends nothing more, so this only returns when it goes away.
+        _ = lines.next_line() => return Err(Error::Cancelled),
+    };
+    let claims = client.claims(&token).await?;
+    let token = hooks::exchange_token(&config, &client, token).await?;
+    let login = Login {
+        token,
+        claims,
+        provider: config.provider,
+    };
+    send(write, &Reply::Token(login)).await
+}
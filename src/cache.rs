@@ -0,0 +1,99 @@
+//! On-disk cache of the documents a provider publishes, shared by the logins on the host. The
+//! directory must only be writable by root, as it decides which keys sign valid id_tokens.
+
+use log::{debug, warn};
+use ring::digest::{digest, SHA256};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+/// Documents kept as `<dir>/<sha256 of the URL>.json`, fresh for `ttl` after they were fetched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DiskCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+/// A cached document and whether it is still within the TTL.
+pub(crate) struct Cached<T> {
+    pub(crate) value: T,
+    pub(crate) fresh: bool,
+}
+
+impl DiskCache {
+    pub(crate) fn new<P: Into<PathBuf>>(dir: P, ttl: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            ttl,
+        }
+    }
+
+    /// The document fetched from `url`, if any; unreadable entries count as absent.
+    pub(crate) fn load<T: DeserializeOwned>(&self, url: &str) -> Option<Cached<T>> {
+        let path = self.path(url);
+        let read = || -> io::Result<Cached<T>> {
+            let age = fs::metadata(&path)?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default();
+            let value = serde_json::from_slice(&fs::read(&path)?)?;
+            Ok(Cached {
+                value,
+                fresh: age < self.ttl,
+            })
+        };
+        match read() {
+            Ok(cached) => Some(cached),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => {
+                warn!("Ignoring cache entry {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Keeps `value` as the document of `url`. Failures are only logged, as the document can
+    /// still be fetched next time.
+    pub(crate) fn store<T: Serialize>(&self, url: &str, value: &T) {
+        let path = self.path(url);
+        match write(&self.dir, &path, value) {
+            Ok(()) => debug!("Cached {} as {}", url, path.display()),
+            Err(err) => warn!("Failed to cache {} as {}: {}", url, path.display(), err),
+        }
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        let hash: String = digest(&SHA256, url.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.dir.join(format!("{}.json", hash))
+    }
+}
+
+/// Writes `value` to a temporary file next to `path` and renames it into place, which also
+/// renews its modification time.
+fn write<T: Serialize>(dir: &Path, path: &Path, value: &T) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o755).create(dir)?;
+    let data = serde_json::to_vec(value)?;
+    let tmp = path.with_extension(format!("tmp.{}", process::id()));
+    let result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o644)
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(&data))
+        .and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
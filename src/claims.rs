@@ -0,0 +1,214 @@
+use crate::{
+    accounts,
+    config::{self, Config, MatchBy, Normalization},
+    device_flow::TokenResponse,
+    error::{Error, Result},
+    hooks,
+};
+use log::error;
+use serde_json::Value;
+use unicode_normalization::UnicodeNormalization;
+
+/// Whether the token with `claims` is for the local account `user`, compared as `match_by` says.
+pub(crate) fn matches_user(
+    config: &Config,
+    token: &TokenResponse,
+    claims: &Value,
+    user: &str,
+) -> bool {
+    match config.match_by {
+        MatchBy::Username => config
+            .username(token, claims)
+            .is_some_and(|username| canonical(config, &username) == canonical(config, user)),
+        MatchBy::Email => {
+            let Some(email) = verified_email(config, claims) else {
+                return false;
+            };
+            match accounts::email_of(user, config.email_map.as_deref()) {
+                Ok(local) => local.is_some_and(|local| local.eq_ignore_ascii_case(email)),
+                Err(err) => {
+                    error!("Failed to read the email address of {}: {}", user, err);
+                    false
+                }
+            }
+        }
+    }
+}
+
+/// `name` as compared under `normalize_username` and `ignore_case`.
+fn canonical(config: &Config, name: &str) -> String {
+    let name: String = match config.normalize_username {
+        Some(Normalization::Nfc) => name.nfc().collect(),
+        Some(Normalization::Nfkc) => name.nfkc().collect(),
+        None => name.to_string(),
+    };
+    if config.ignore_case {
+        name.to_lowercase()
+    } else {
+        name
+    }
+}
+
+/// Name of the claim `match_by` compares and the value it is compared as, for log messages.
+pub(crate) fn match_claim<'a>(
+    config: &'a Config,
+    token: &TokenResponse,
+    claims: &Value,
+) -> (&'a str, String) {
+    match config.match_by {
+        MatchBy::Username => (
+            config.username_field(token, claims),
+            config.username(token, claims).unwrap_or_default(),
+        ),
+        MatchBy::Email => (
+            &config.email_claim,
+            verified_email(config, claims)
+                .unwrap_or_default()
+                .to_string(),
+        ),
+    }
+}
+
+/// Local account of the token, for logins that leave the PAM user to the token.
+pub(crate) fn local_user(config: &Config, token: &TokenResponse, claims: &Value) -> Result<String> {
+    match config.match_by {
+        MatchBy::Username => config.username(token, claims).ok_or_else(|| {
+            Error::Token(format!(
+                "token has no {} claim",
+                config.username_field(token, claims)
+            ))
+        }),
+        MatchBy::Email => {
+            let email = verified_email(config, claims).ok_or_else(|| {
+                Error::Token(format!(
+                    "token has no verified {} claim",
+                    config.email_claim
+                ))
+            })?;
+            let email_map = config.email_map.as_deref().ok_or_else(|| {
+                Error::Config("email_map is required to find the user of a token".to_string())
+            })?;
+            accounts::user_of(email, email_map)
+                .map_err(|err| Error::Config(format!("failed to read {}: {}", email_map, err)))?
+                .ok_or_else(|| {
+                    Error::Authorization(format!("no account has the email address {}", email))
+                })
+        }
+    }
+}
+
+/// The email claim, unless `email_verified` says the provider has not verified it.
+fn verified_email<'a>(config: &Config, claims: &'a Value) -> Option<&'a str> {
+    if claims.get("email_verified") == Some(&Value::Bool(false)) {
+        return None;
+    }
+    claims.get(&config.email_claim).and_then(Value::as_str)
+}
+
+/// Checks the `required_claims`, `required_groups`, `required_roles`, `required_acr` and
+/// `required_amr` policies against verified id_token claims.
+pub(crate) fn authorize(config: &Config, claims: &Value) -> Result<()> {
+    for required in &config.required_claims {
+        let (name, expected) = match required.split_once('=') {
+            Some((name, expected)) => (name, Some(expected)),
+            None => (required.as_str(), None),
+        };
+        let matched = match (claims.get(name), expected) {
+            (None | Some(Value::Null) | Some(Value::Bool(false)), None) => false,
+            (Some(_), None) => true,
+            (Some(claim), Some(expected)) => contains(claim, expected),
+            (None, Some(_)) => false,
+        };
+        if !matched {
+            return Err(Error::Authorization(format!(
+                "required claim not satisfied: {}",
+                required
+            )));
+        }
+    }
+
+    if !config.required_groups.is_empty() {
+        let groups = claims.get(&config.groups_claim).unwrap_or(&Value::Null);
+        if !config
+            .required_groups
+            .iter()
+            .any(|group| contains(groups, group))
+        {
+            return Err(Error::Authorization(format!(
+                "user is not a member of any required group: {}",
+                config.required_groups.join(",")
+            )));
+        }
+    }
+
+    if !config.required_roles.is_empty() {
+        let has_role = |role: &String| {
+            config
+                .roles_claims
+                .iter()
+                .filter_map(|claim| config::claim(claims, claim))
+                .any(|roles| contains(roles, role))
+        };
+        if !config.required_roles.iter().any(has_role) {
+            return Err(Error::Authorization(format!(
+                "user has none of the required roles: {}",
+                config.required_roles.join(",")
+            )));
+        }
+    }
+
+    // Both prove how the user authenticated, e.g. with a second factor.
+    let acr = claims.get("acr").unwrap_or(&Value::Null);
+    if !config.required_acr.is_empty()
+        && !config.required_acr.iter().any(|class| contains(acr, class))
+    {
+        return Err(Error::Authorization(format!(
+            "authentication context {} is not one of {}",
+            acr,
+            config.required_acr.join(",")
+        )));
+    }
+    let amr = claims.get("amr").unwrap_or(&Value::Null);
+    if !config.required_amr.is_empty()
+        && !config
+            .required_amr
+            .iter()
+            .any(|method| contains(amr, method))
+    {
+        return Err(Error::Authorization(format!(
+            "authentication methods {} include none of {}",
+            amr,
+            config.required_amr.join(",")
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that the user authenticated to the provider within `max_age`, allowing for
+/// `clock_skew`.
+pub(crate) fn check_auth_time(config: &Config, claims: &Value) -> Result<()> {
+    let Some(max_age) = config.max_age else {
+        return Ok(());
+    };
+    let auth_time = claims
+        .get("auth_time")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| Error::Token("token has no auth_time claim".to_string()))?;
+    if auth_time + max_age + config.clock_skew < hooks::unix_time() {
+        return Err(Error::Token(format!(
+            "authentication at {} is older than max_age",
+            auth_time
+        )));
+    }
+    Ok(())
+}
+
+/// Returns whether `claim` equals `expected`, or contains it when the claim is an array.
+fn contains(claim: &Value, expected: &str) -> bool {
+    match claim {
+        Value::Array(values) => values.iter().any(|value| contains(value, expected)),
+        Value::String(value) => value == expected,
+        Value::Null => false,
+        value => serde_json::from_str::<Value>(expected).is_ok_and(|expected| *value == expected),
+    }
+}
@@ -0,0 +1,113 @@
+//! `dbus_signal`: the pending device authorization as signals on the system bus, so that a
+//! graphical greeter such as GDM or SDDM can show the link or a QR code of its own instead of
+//! the text of the conversation.
+//!
+//! The signals come from the object `/io/github/chalharu/PamOauth2` with the interface
+//! `io.github.chalharu.PamOauth2`:
+//!
+//! - `DeviceAuthorization(service, user, tty, verification_uri, user_code,
+//!   verification_uri_complete, expires_in)` when the user is asked to log in, with an empty
+//!   `verification_uri_complete` when the provider returned none;
+//! - `DeviceAuthorizationEnded(service, user, tty, user_code, result)` once the login is over,
+//!   `result` being `success` or the error class.
+//!
+//! All are strings but `expires_in`, a `uint32`. The greeter tells its own login apart by the
+//! service and the tty. The default policy of the system bus lets root send signals, so no bus
+//! configuration is needed.
+
+use crate::{config::Config, device_flow::DeviceAuthResponse, hooks::PamContext};
+use log::{debug, warn};
+use std::{process::Stdio, time::Duration};
+use tokio::{process::Command, time};
+
+const PATH: &str = "/io/github/chalharu/PamOauth2";
+const INTERFACE: &str = "io.github.chalharu.PamOauth2";
+/// How long `dbus-send` may take, as the login waits for it.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The signals of the device authorizations of one transaction.
+#[derive(Debug, Clone)]
+pub(crate) struct Signals {
+    dbus_send: String,
+    service: String,
+    user: String,
+    tty: String,
+}
+
+impl Signals {
+    /// The signals of the transaction in `context` of `user`, or `None` when `config` sends none.
+    pub(crate) fn from_config(
+        config: &Config,
+        context: &PamContext,
+        user: Option<&str>,
+    ) -> Option<Self> {
+        config.dbus_signal.then(|| Self {
+            dbus_send: config.dbus_send.clone(),
+            service: context.service.clone(),
+            user: user.unwrap_or_default().to_string(),
+            tty: context.tty.clone(),
+        })
+    }
+
+    /// Announces the device authorization `result` that the user is asked to complete.
+    pub(crate) async fn started(&self, result: &DeviceAuthResponse) {
+        self.send(
+            "DeviceAuthorization",
+            &[
+                format!("string:{}", self.service),
+                format!("string:{}", self.user),
+                format!("string:{}", self.tty),
+                format!("string:{}", result.verification_uri),
+                format!("string:{}", result.user_code),
+                format!(
+                    "string:{}",
+                    result.verification_uri_complete.as_deref().unwrap_or("")
+                ),
+                format!("uint32:{}", result.expires_in),
+            ],
+        )
+        .await
+    }
+
+    /// Announces that the device authorization `result` ended with the error class `error`, if
+    /// any.
+    pub(crate) async fn ended(&self, result: &DeviceAuthResponse, error: Option<&str>) {
+        self.send(
+            "DeviceAuthorizationEnded",
+            &[
+                format!("string:{}", self.service),
+                format!("string:{}", self.user),
+                format!("string:{}", self.tty),
+                format!("string:{}", result.user_code),
+                format!("string:{}", error.unwrap_or("success")),
+            ],
+        )
+        .await
+    }
+
+    /// Sends the signal `member`. Failures are only logged, as the conversation shows the
+    /// login all the same.
+    async fn send(&self, member: &str, args: &[String]) {
+        let output = Command::new(&self.dbus_send)
+            .args(["--system", "--type=signal", PATH])
+            .arg(format!("{}.{}", INTERFACE, member))
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output();
+        match time::timeout(SEND_TIMEOUT, output).await {
+            Ok(Ok(output)) if output.status.success() => debug!("Sent the {} signal", member),
+            Ok(Ok(output)) => warn!(
+                "{} failed to send the {} signal: {}: {}",
+                self.dbus_send,
+                member,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Ok(Err(err)) => warn!("Failed to run {}: {}", self.dbus_send, err),
+            Err(_) => warn!("{} timed out after {:?}", self.dbus_send, SEND_TIMEOUT),
+        }
+    }
+}
@@ -0,0 +1,1333 @@
+//! OAuth 2.0 Device Authorization Grant (RFC 8628) client, with the authorization code grant for
+//! logins on a machine with a browser and OpenID CIBA for approval on the user's phone.
+
+use crate::{
+    auth_code::AuthorizationRequest,
+    cache::DiskCache,
+    client_assertion::ClientAssertion,
+    config::{Config, ENTRA_ID},
+    dpop::DpopKey,
+    error::{Error, Result},
+    http::HttpConfig,
+    i18n::Messages,
+    jwks,
+    qr::{self, QrOptions},
+    template,
+};
+use http::{HeaderMap, StatusCode};
+use jsonwebtoken::jwk::JwkSet;
+use log::{debug, warn};
+use serde::{
+    de::{self, Unexpected},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::Value;
+use std::{collections::BTreeMap, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+use tokio::time::{self, Instant};
+
+/// Amount added to the polling interval on `slow_down` (RFC 8628, section 3.5).
+const SLOW_DOWN_INCREMENT: Duration = Duration::from_secs(5);
+
+const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Upper bound in seconds of the time a user has to approve a device code or CIBA request.
+const MAX_EXPIRES_IN: usize = 1800;
+
+/// Most pages of groups read from the groups API, so that a misbehaving one cannot keep the
+/// login following `@odata.nextLink`s.
+const MAX_GROUP_PAGES: usize = 20;
+
+/// Floor of the polling interval, so that a provider cannot make the client busy-poll.
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `client_assertion_type` of `private_key_jwt` (RFC 7523, section 2.2).
+const JWT_BEARER_ASSERTION: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// How the client authenticates to the token endpoint when it has a secret or key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMethod {
+    #[default]
+    ClientSecretPost,
+    ClientSecretBasic,
+    /// A JWT signed with the `client_key`, which may live in the TPM.
+    PrivateKeyJwt,
+}
+
+impl FromStr for ClientAuthMethod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "client_secret_post" => Ok(Self::ClientSecretPost),
+            "client_secret_basic" => Ok(Self::ClientSecretBasic),
+            "private_key_jwt" => Ok(Self::PrivateKeyJwt),
+            _ => Err(Error::Config(format!("unknown client_auth_method: {}", s))),
+        }
+    }
+}
+
+/// Response of the device authorization endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceAuthResponse {
+    pub device_code: String,
+    pub user_code: String,
+    /// `verification_url` in the answers of the Azure AD v1 endpoints and of Google.
+    #[serde(alias = "verification_url")]
+    pub verification_uri: String,
+    /// `verification_uri` with the user code included; optional in RFC 8628.
+    pub verification_uri_complete: Option<String>,
+    /// Lifetime of the device code in seconds; 300 when the provider does not say, and at most
+    /// 1800.
+    #[serde(default = "default_expires_in", deserialize_with = "expires_in")]
+    pub expires_in: usize,
+    /// Polling interval in seconds; 5 when the provider does not say.
+    #[serde(default = "default_interval", deserialize_with = "number")]
+    pub interval: usize,
+}
+
+impl DeviceAuthResponse {
+    /// The URI to open: `verification_uri_complete` when the provider returned one, else
+    /// `verification_uri`, where the user code has to be entered.
+    pub fn login_uri(&self) -> &str {
+        self.verification_uri_complete
+            .as_deref()
+            .unwrap_or(&self.verification_uri)
+    }
+
+    /// The user code split into groups of four, e.g. `WDJB-MJHT`, keeping the grouping of codes
+    /// that already contain separators.
+    pub fn formatted_user_code(&self) -> String {
+        let groups: Vec<&str> = self
+            .user_code
+            .split(['-', ' '])
+            .filter(|group| !group.is_empty())
+            .collect();
+        if groups.len() > 1 {
+            return groups.join("-");
+        }
+        let chars: Vec<char> = self.user_code.chars().collect();
+        chars
+            .chunks(4)
+            .map(|chunk| chunk.iter().collect::<String>())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// Renders [`login_uri`](Self::login_uri) as a QR code for terminals, or `None` when the URI
+    /// is too long to encode or the crate is built without the `qr` feature.
+    pub fn qr_code(&self) -> Option<String> {
+        self.qr_code_with(&QrOptions::default())
+    }
+
+    /// Like [`qr_code`](Self::qr_code), rendered with `options`.
+    pub fn qr_code_with(&self, options: &QrOptions) -> Option<String> {
+        qr::render(self.login_uri(), options)
+    }
+
+    /// Fills the `{url}`, `{verification_uri}`, `{user_code}`, `{expires_min}` and `{qr}`
+    /// placeholders of `template`; `{qr}` is the QR code rendered with `qr`, or empty when that is
+    /// `None` or the URI is too long to encode.
+    pub fn render(&self, template: &str, qr: Option<&QrOptions>) -> String {
+        let qr_code = match qr {
+            Some(options) if template.contains("{qr}") => {
+                self.qr_code_with(options).unwrap_or_default()
+            }
+            _ => String::new(),
+        };
+        template::render(
+            template,
+            &[
+                ("url", self.login_uri()),
+                ("verification_uri", &self.verification_uri),
+                ("user_code", &self.formatted_user_code()),
+                ("expires_min", &self.expires_in.div_ceil(60).to_string()),
+                ("qr", &qr_code),
+            ],
+        )
+    }
+
+    /// Instructions for the user from `messages`, with the QR code rendered with `qr` unless it
+    /// is `None` or the crate is built without the `qr` feature. The user code is shown on its own
+    /// when the URI does not include it. Returns `None` when the QR code cannot be encoded.
+    pub fn instructions(&self, messages: &Messages, qr: Option<&QrOptions>) -> Option<String> {
+        let qr = qr.filter(|_| cfg!(feature = "qr"));
+        let template = match (&self.verification_uri_complete, qr) {
+            (_, None) => &messages.login_code,
+            (Some(_), Some(_)) => &messages.login_qr,
+            (None, Some(_)) => &messages.login_code_qr,
+        };
+        if let Some(options) = qr {
+            self.qr_code_with(options)?;
+        }
+        Some(self.render(template, qr))
+    }
+}
+
+/// Response of the CIBA backchannel authentication endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackchannelAuthResponse {
+    pub auth_req_id: String,
+    /// Lifetime of the request in seconds, like [`DeviceAuthResponse::expires_in`].
+    #[serde(default = "default_expires_in", deserialize_with = "expires_in")]
+    pub expires_in: usize,
+    /// Polling interval in seconds; 5 when the provider does not say.
+    #[serde(default = "default_interval", deserialize_with = "number")]
+    pub interval: usize,
+}
+
+fn default_interval() -> usize {
+    5
+}
+
+fn default_expires_in() -> usize {
+    300
+}
+
+/// `expires_in` of a device code or CIBA request, 0 counting as missing. Longer lifetimes are
+/// cut to `MAX_EXPIRES_IN`, so that a wrong value does not keep the login waiting for hours.
+fn expires_in<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    Ok(match number(deserializer)? {
+        0 => default_expires_in(),
+        expires_in => expires_in.min(MAX_EXPIRES_IN),
+    })
+}
+
+/// Successful response of the token endpoint. Only `access_token` and `token_type` are required
+/// by RFC 6749; providers differ in which of the other fields they return.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub token_type: String,
+    pub id_token: Option<String>,
+    pub scope: Option<String>,
+    pub session_state: Option<String>,
+    #[serde(default, deserialize_with = "optional_number")]
+    pub expires_in: Option<u64>,
+    /// The other fields of the response, such as `refresh_expires_in` or `not-before-policy`
+    /// of Keycloak, kept for the claim mapping and exports.
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// A number that some providers send as a string, e.g. `"expires_in": "3600"`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Lenient<T> {
+    Number(T),
+    String(String),
+}
+
+impl<T: FromStr> Lenient<T> {
+    fn into_number<E: de::Error>(self) -> Result<T, E> {
+        match self {
+            Self::Number(number) => Ok(number),
+            Self::String(text) => text
+                .trim()
+                .parse()
+                .map_err(|_| E::invalid_value(Unexpected::Str(&text), &"a number")),
+        }
+    }
+}
+
+fn number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr,
+{
+    Lenient::deserialize(deserializer)?.into_number()
+}
+
+fn optional_number<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr,
+{
+    Option::<Lenient<T>>::deserialize(deserializer)?
+        .map(Lenient::into_number)
+        .transpose()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct OpenIdConfiguration {
+    issuer: String,
+    authorization_endpoint: Option<String>,
+    device_authorization_endpoint: Option<String>,
+    backchannel_authentication_endpoint: Option<String>,
+    token_endpoint: String,
+    jwks_uri: String,
+    userinfo_endpoint: Option<String>,
+    revocation_endpoint: Option<String>,
+    end_session_endpoint: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonResult<T: Sized> {
+    Ok(T),
+    Err {
+        error: String,
+        error_description: Option<String>,
+    },
+}
+
+impl<T> JsonResult<T> {
+    fn into_result(self) -> Result<T> {
+        match self {
+            Self::Ok(value) => Ok(value),
+            Self::Err {
+                error,
+                error_description,
+            } => Err(Error::idp(error, error_description)),
+        }
+    }
+}
+
+/// Identity provider endpoints used by the flow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoints {
+    /// Issuer the id_token must come from; not checked when `None`.
+    pub issuer: Option<String>,
+    /// Authorization endpoint for the authorization code grant.
+    pub authorization_url: Option<String>,
+    pub device_authorization_url: Option<String>,
+    /// CIBA backchannel authentication endpoint.
+    pub backchannel_authentication_url: Option<String>,
+    pub token_url: String,
+    pub jwks_url: String,
+    /// Used for the claims when the token response has no id_token.
+    pub userinfo_url: Option<String>,
+    /// RFC 7662 endpoint validating opaque access tokens; takes precedence over userinfo.
+    pub introspection_url: Option<String>,
+    /// RFC 7009 endpoint revoking the tokens at logout.
+    pub revocation_url: Option<String>,
+    /// OpenID Connect RP-initiated logout endpoint.
+    pub end_session_url: Option<String>,
+}
+
+impl Endpoints {
+    /// Discovers the endpoints from the OpenID configuration of `issuer`.
+    pub async fn discover(issuer: &str) -> Result<Self> {
+        Self::discover_with(issuer, &HttpConfig::default()).await
+    }
+
+    /// Like [`discover`](Self::discover), fetching the configuration with `http`.
+    pub async fn discover_with(issuer: &str, http: &HttpConfig) -> Result<Self> {
+        Self::resolve(
+            &Config {
+                issuer: Some(issuer.to_string()),
+                ..Config::default()
+            },
+            http,
+        )
+        .await
+    }
+
+    /// Takes the endpoints set in `config` and discovers the missing ones from its issuer.
+    pub(crate) async fn resolve(config: &Config, http: &HttpConfig) -> Result<Self> {
+        let cache = config
+            .discovery_cache_ttl
+            .map(|ttl| DiskCache::new(&config.cache_dir, Duration::from_secs(ttl)));
+        let discovery = match config.issuer() {
+            Some(issuer) => {
+                let discovery = discover(&issuer, http, cache.as_ref()).await?;
+                check_issuer(config, &issuer, &discovery.issuer)?;
+                Some(discovery)
+            }
+            None => None,
+        };
+        Ok(Self {
+            // The discovered value is what the provider puts into `iss`.
+            issuer: discovery.as_ref().map(|d| d.issuer.clone()),
+            authorization_url: config.endpoint(&config.authorize_url).or_else(|| {
+                discovery
+                    .as_ref()
+                    .and_then(|d| d.authorization_endpoint.clone())
+            }),
+            device_authorization_url: config.endpoint(&config.device_authorize_url).or_else(|| {
+                discovery
+                    .as_ref()
+                    .and_then(|d| d.device_authorization_endpoint.clone())
+            }),
+            backchannel_authentication_url: config
+                .endpoint(&config.backchannel_authentication_url)
+                .or_else(|| {
+                    discovery
+                        .as_ref()
+                        .and_then(|d| d.backchannel_authentication_endpoint.clone())
+                }),
+            token_url: config
+                .endpoint(&config.token_url)
+                .or_else(|| discovery.as_ref().map(|d| d.token_endpoint.clone()))
+                .ok_or_else(|| Error::Config("no token endpoint is available".to_string()))?,
+            jwks_url: config
+                .endpoint(&config.jwks_url)
+                .or_else(|| discovery.as_ref().map(|d| d.jwks_uri.clone()))
+                .ok_or_else(|| Error::Config("no JWKS endpoint is available".to_string()))?,
+            userinfo_url: config
+                .endpoint(&config.userinfo_url)
+                .or_else(|| discovery.as_ref().and_then(|d| d.userinfo_endpoint.clone())),
+            introspection_url: config.endpoint(&config.introspection_url),
+            revocation_url: config.endpoint(&config.revocation_url).or_else(|| {
+                discovery
+                    .as_ref()
+                    .and_then(|d| d.revocation_endpoint.clone())
+            }),
+            end_session_url: config
+                .endpoint(&config.end_session_url)
+                .or_else(|| discovery.and_then(|d| d.end_session_endpoint)),
+        })
+    }
+}
+
+/// Client for the device authorization grant of a single OAuth client registration.
+#[derive(Debug, Clone)]
+pub struct DeviceFlowClient {
+    endpoints: Endpoints,
+    http: HttpConfig,
+    client_id: String,
+    audience: Option<String>,
+    clock_skew: Duration,
+    max_auth_time: Option<Duration>,
+    min_interval: Duration,
+    client_secret: Option<String>,
+    client_auth_method: ClientAuthMethod,
+    scope: Vec<String>,
+    auth_params: Vec<(String, String)>,
+    resource: Option<String>,
+    jwks_cache: Option<DiskCache>,
+    dpop: Option<Arc<DpopKey>>,
+    client_assertion: Option<Arc<ClientAssertion>>,
+    groups_lookup: Option<GroupsLookup>,
+}
+
+/// API listing the groups of the user, for tokens that leave them out.
+#[derive(Debug, Clone)]
+struct GroupsLookup {
+    url: String,
+    /// Claim the groups are put in.
+    claim: String,
+    /// Field naming a group when the API lists objects.
+    field: String,
+}
+
+impl DeviceFlowClient {
+    /// Creates a public client requesting the `openid` scope.
+    pub fn new<S: Into<String>>(endpoints: Endpoints, client_id: S) -> Self {
+        Self {
+            endpoints,
+            http: HttpConfig::default(),
+            client_id: client_id.into(),
+            audience: None,
+            clock_skew: DEFAULT_CLOCK_SKEW,
+            max_auth_time: None,
+            min_interval: DEFAULT_MIN_INTERVAL,
+            client_secret: None,
+            client_auth_method: ClientAuthMethod::default(),
+            scope: vec!["openid".to_string()],
+            auth_params: Vec::new(),
+            resource: None,
+            jwks_cache: None,
+            dpop: None,
+            client_assertion: None,
+            groups_lookup: None,
+        }
+    }
+
+    /// Builds the client from the configured endpoints, discovering the missing ones from the
+    /// issuer.
+    pub async fn from_config(config: &Config) -> Result<Self> {
+        let http = Self::http(config)?;
+        let endpoints = Endpoints::resolve(config, &http).await?;
+        Self::with_endpoints(config, endpoints, http)
+    }
+
+    /// The HTTP client of `config`, with its `http_headers`.
+    fn http(config: &Config) -> Result<HttpConfig> {
+        HttpConfig::from_config(config)?.with_headers(
+            config
+                .http_headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        )
+    }
+
+    /// The client of `config` for the resolved `endpoints`.
+    fn with_endpoints(config: &Config, endpoints: Endpoints, http: HttpConfig) -> Result<Self> {
+        let mut auth_params = config.auth_params.clone();
+        if let Some(max_age) = config.max_age {
+            auth_params
+                .entry("max_age".to_string())
+                .or_insert_with(|| max_age.to_string());
+        }
+        let client = Self::new(endpoints, config.client_id.as_str())
+            .with_http(http)
+            .with_scope(config.scope.iter().cloned())
+            .with_auth_params(auth_params)
+            .with_clock_skew(Duration::from_secs(config.clock_skew));
+        let client = match config.max_auth_time {
+            Some(max_auth_time) => client.with_max_auth_time(Duration::from_secs(max_auth_time)),
+            None => client,
+        }
+        .with_min_interval(Duration::from_secs(config.min_poll_interval));
+        let client = match config.jwks_cache_ttl {
+            Some(ttl) => client.with_jwks_cache(&config.cache_dir, Duration::from_secs(ttl)),
+            None => client,
+        };
+        let client = match &config.audience {
+            Some(audience) => client.with_audience(audience.as_str()),
+            None => client,
+        };
+        let client = match &config.resource {
+            Some(resource) => client.with_resource(resource.as_str()),
+            None => client,
+        };
+        let client = match config.dpop {
+            true => client.with_dpop(DpopKey::load_or_generate(&config.dpop_key)?),
+            false => client,
+        };
+        let client = match &config.groups_url {
+            Some(url) => client.with_groups_url(
+                url.as_str(),
+                config.groups_claim.as_str(),
+                config.groups_field.as_str(),
+            ),
+            None => client,
+        };
+        if config.client_auth_method == ClientAuthMethod::PrivateKeyJwt {
+            let assertion = match config.client_key.as_deref() {
+                Some(key) => match key.strip_prefix("tpm:") {
+                    Some(handle) => ClientAssertion::tpm(
+                        handle,
+                        &config.tpm2_sign,
+                        config.client_key_id.as_deref(),
+                    ),
+                    None => ClientAssertion::from_file(key, config.client_key_id.as_deref())?,
+                },
+                None => {
+                    return Err(Error::Config(
+                        "client_key is required with private_key_jwt".to_string(),
+                    ))
+                }
+            };
+            return Ok(client.with_client_assertion(assertion));
+        }
+        Ok(match &config.client_secret {
+            Some(secret) => client.with_client_secret(secret.as_str(), config.client_auth_method),
+            None => client,
+        })
+    }
+
+    /// Builds the client for the first of the [`Config::provider_configs`] that can be reached,
+    /// returning the configuration of that provider with it. Providers whose discovery fails,
+    /// as it cannot be reached or has no valid document at its issuer, are skipped. When none is
+    /// left, the error is that of an unreachable provider if there is one, so that
+    /// `offline_login` applies, else that of the last provider.
+    pub async fn from_providers(config: &Config) -> Result<(Config, Self)> {
+        let mut errors = Vec::new();
+        for config in config.provider_configs()? {
+            let http = Self::http(&config)?;
+            match Endpoints::resolve(&config, &http).await {
+                Ok(endpoints) => {
+                    let client = Self::with_endpoints(&config, endpoints, http)?;
+                    return Ok((config, client));
+                }
+                Err(err @ (Error::Network(_) | Error::Response(_) | Error::Config(_))) => {
+                    warn!(
+                        "Provider {} is unavailable: {}",
+                        config.provider.as_deref().unwrap_or_default(),
+                        err
+                    );
+                    errors.push(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        // provider_configs returns at least one configuration.
+        let network = errors
+            .iter()
+            .position(|err| matches!(err, Error::Network(_)))
+            .unwrap_or(errors.len() - 1);
+        Err(errors.swap_remove(network))
+    }
+
+    /// Authenticates the client with `secret` using `method`.
+    pub fn with_client_secret<S: Into<String>>(
+        mut self,
+        secret: S,
+        method: ClientAuthMethod,
+    ) -> Self {
+        self.client_secret = Some(secret.into());
+        self.client_auth_method = method;
+        self
+    }
+
+    /// Authenticates the client with `private_key_jwt` assertions signed by `assertion`.
+    pub fn with_client_assertion(mut self, assertion: ClientAssertion) -> Self {
+        self.client_assertion = Some(Arc::new(assertion));
+        self.client_auth_method = ClientAuthMethod::PrivateKeyJwt;
+        self
+    }
+
+    /// Sends the requests with the transport settings of `http`.
+    pub fn with_http(mut self, http: HttpConfig) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Accepts id_tokens issued for `audience` instead of the client id.
+    pub fn with_audience<S: Into<String>>(mut self, audience: S) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Keeps the JWKS in `dir` for `ttl`, fetching it again early for a `kid` it does not have
+    /// and falling back to the stale copy while the provider cannot be reached.
+    pub fn with_jwks_cache<P: Into<PathBuf>>(mut self, dir: P, ttl: Duration) -> Self {
+        self.jwks_cache = Some(DiskCache::new(dir, ttl));
+        self
+    }
+
+    /// Sends DPoP proofs of `key` with the token requests, so that the provider binds the
+    /// tokens to it, and presents DPoP-bound access tokens to the userinfo endpoint with a proof.
+    pub fn with_dpop(mut self, key: DpopKey) -> Self {
+        self.dpop = Some(Arc::new(key));
+        self
+    }
+
+    /// Tolerance for the `exp`, `nbf` and `iat` claims of the id_token, 60 seconds by default.
+    pub fn with_clock_skew(mut self, clock_skew: Duration) -> Self {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    /// Gives up polling after `max_auth_time` even when the device code is still valid.
+    pub fn with_max_auth_time(mut self, max_auth_time: Duration) -> Self {
+        self.max_auth_time = Some(max_auth_time);
+        self
+    }
+
+    /// Polls no more often than every `min_interval`, 1 second by default, whatever interval
+    /// the provider asks for. An interval of 0 counts as missing and is 5 seconds.
+    pub fn with_min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    pub fn with_scope<I: IntoIterator<Item = S>, S: Into<String>>(mut self, scope: I) -> Self {
+        self.scope = scope.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Provider-specific parameters, such as `resource` or `prompt`, added to the device,
+    /// browser and CIBA authorization requests.
+    pub fn with_auth_params<I, K, V>(mut self, params: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.auth_params = params
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.into()))
+            .collect();
+        self
+    }
+
+    /// The `resource` of the Azure AD v1 endpoints, sent with the device authorization and the
+    /// token polls.
+    pub fn with_resource<S: Into<String>>(mut self, resource: S) -> Self {
+        self.resource = Some(resource.into());
+        self
+    }
+
+    /// Fills in the `claim` of claims that lack it with the groups listed by the API at `url`,
+    /// read with the access token. It answers with a JSON array or a Microsoft Graph collection
+    /// such as `https://graph.microsoft.com/v1.0/me/transitiveMemberOf`, whose pages are
+    /// followed; objects are named by their `field`. This covers the groups overage of Azure AD,
+    /// which leaves the groups out of the tokens of users in too many of them.
+    pub fn with_groups_url<S: Into<String>>(mut self, url: S, claim: S, field: S) -> Self {
+        self.groups_lookup = Some(GroupsLookup {
+            url: url.into(),
+            claim: claim.into(),
+            field: field.into(),
+        });
+        self
+    }
+
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    /// Starts the flow at the device authorization endpoint.
+    pub async fn authorize_device(&self) -> Result<DeviceAuthResponse> {
+        let url = self
+            .endpoints
+            .device_authorization_url
+            .as_deref()
+            .ok_or_else(|| {
+                Error::Config("no device authorization endpoint is available".to_string())
+            })?;
+        let scope = self.scope.join(" ");
+        let mut params = vec![("scope", scope.as_str())];
+        params.extend(self.auth_params());
+        if let Some(resource) = &self.resource {
+            params.push(("resource", resource));
+        }
+        let (_, _, text) = self.client_post(url, &params, None).await?;
+        serde_json::from_str::<JsonResult<DeviceAuthResponse>>(&text)?.into_result()
+    }
+
+    /// Polls the token endpoint until the user has approved the request, the device code expires
+    /// or the time set with [`with_max_auth_time`](Self::with_max_auth_time) has passed. Dropping
+    /// the future stops polling, at the latest once the request in flight is answered.
+    pub async fn poll_token(&self, device_auth: &DeviceAuthResponse) -> Result<TokenResponse> {
+        let mut params = vec![("device_code", device_auth.device_code.as_str())];
+        // The v1 endpoints have a grant type of their own and take the device code as `code`.
+        match &self.resource {
+            Some(resource) => params.extend([
+                ("grant_type", "device_code"),
+                ("resource", resource.as_str()),
+                ("code", &device_auth.device_code),
+            ]),
+            None => params.push(("grant_type", "urn:ietf:params:oauth:grant-type:device_code")),
+        }
+        self.poll(&params, device_auth.expires_in, device_auth.interval)
+            .await
+    }
+
+    /// Sends a CIBA authentication request (OpenID CIBA Core 1.0, section 7) for the user
+    /// identified by `login_hint`, who approves it on their authentication device;
+    /// `binding_message` is shown there as well. CIBA requires a confidential client.
+    pub async fn authorize_backchannel(
+        &self,
+        login_hint: &str,
+        binding_message: Option<&str>,
+    ) -> Result<BackchannelAuthResponse> {
+        let url = self
+            .endpoints
+            .backchannel_authentication_url
+            .as_deref()
+            .ok_or_else(|| {
+                Error::Config("no backchannel authentication endpoint is available".to_string())
+            })?;
+        let scope = self.scope.join(" ");
+        let mut params = vec![("scope", scope.as_str()), ("login_hint", login_hint)];
+        if let Some(binding_message) = binding_message {
+            params.push(("binding_message", binding_message));
+        }
+        params.extend(self.auth_params());
+        let (_, _, text) = self.client_post(url, &params, None).await?;
+        serde_json::from_str::<JsonResult<BackchannelAuthResponse>>(&text)?.into_result()
+    }
+
+    /// Polls the token endpoint for the CIBA request `auth`, like
+    /// [`poll_token`](Self::poll_token).
+    pub async fn poll_backchannel_token(
+        &self,
+        auth: &BackchannelAuthResponse,
+    ) -> Result<TokenResponse> {
+        let params = [
+            ("auth_req_id", auth.auth_req_id.as_str()),
+            ("grant_type", "urn:openid:params:grant-type:ciba"),
+        ];
+        self.poll(&params, auth.expires_in, auth.interval).await
+    }
+
+    /// Polls the token endpoint with `params` every `interval` seconds until a token is issued,
+    /// an error other than `authorization_pending` and `slow_down` is returned or `expires_in`
+    /// seconds or the `max_auth_time` have passed.
+    async fn poll(
+        &self,
+        params: &[(&str, &str)],
+        expires_in: usize,
+        interval: usize,
+    ) -> Result<TokenResponse> {
+        let started = Instant::now();
+        let expires_in = Duration::from_secs(expires_in.try_into().unwrap());
+        let deadline = started
+            + self
+                .max_auth_time
+                .map_or(expires_in, |max| max.min(expires_in));
+        let interval = match interval {
+            0 => default_interval(),
+            interval => interval,
+        };
+        let mut interval = Duration::from_secs(interval.try_into().unwrap()).max(self.min_interval);
+        while Instant::now() < deadline {
+            match self.token_request(params).await {
+                Ok(JsonResult::Ok(token)) => return Ok(token),
+                Ok(JsonResult::Err {
+                    error,
+                    error_description,
+                }) => {
+                    let message = error_message(&error, error_description.as_deref());
+                    match error.as_str() {
+                        "authorization_pending" => debug!("{}", message),
+                        "slow_down" => {
+                            debug!("{}", message);
+                            interval += SLOW_DOWN_INCREMENT;
+                        }
+                        // access_denied, expired_token and any other error end the flow.
+                        _ => return Err(Error::idp(error, error_description)),
+                    }
+                }
+                Err(e) => {
+                    warn!("Token request error: {}", e);
+                }
+            }
+            time::sleep_until((Instant::now() + interval).min(deadline)).await;
+        }
+        let description = if started.elapsed() < expires_in {
+            "max_auth_time elapsed before the authorization completed"
+        } else {
+            "authorization request expired before it was approved"
+        };
+        Err(Error::idp(
+            "expired_token".to_string(),
+            Some(description.to_string()),
+        ))
+    }
+
+    /// Starts the authorization code grant (RFC 6749, section 4.1) with a redirect to `port` on
+    /// the loopback interface, any free port for 0. The user has to open
+    /// [`AuthorizationRequest::authorize_url`] in a browser on the same machine.
+    pub fn authorize_browser(&self, port: u16) -> Result<AuthorizationRequest> {
+        let url =
+            self.endpoints.authorization_url.as_deref().ok_or_else(|| {
+                Error::Config("no authorization endpoint is available".to_string())
+            })?;
+        let scope = self.scope.join(" ");
+        let mut params = vec![("client_id", self.client_id.as_str()), ("scope", &scope)];
+        params.extend(self.auth_params());
+        AuthorizationRequest::new(url, port, &params)
+    }
+
+    /// Waits up to `timeout` for the browser to come back with the authorization code of
+    /// `request` and exchanges the code for a token.
+    pub async fn complete_authorization(
+        &self,
+        request: &AuthorizationRequest,
+        timeout: Duration,
+    ) -> Result<TokenResponse> {
+        let code = request.wait_for_code(timeout).await?;
+        let params = [
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &request.redirect_uri),
+            ("code_verifier", &request.code_verifier),
+        ];
+        self.token_request(&params).await?.into_result()
+    }
+
+    /// Exchanges `refresh_token` for a new token. When the provider does not rotate refresh
+    /// tokens, the returned token carries `refresh_token` over.
+    pub async fn refresh_token(&self, refresh_token: &str) -> Result<TokenResponse> {
+        let params = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ];
+        let mut token = self.token_request(&params).await?.into_result()?;
+        // RFC 6749, section 6: issuing a new refresh token is optional.
+        token
+            .refresh_token
+            .get_or_insert_with(|| refresh_token.to_string());
+        Ok(token)
+    }
+
+    /// Exchanges the access token of `token` for one for `audience` and `scope` at the token
+    /// endpoint (RFC 8693). The result replaces the access token of `token`; its id_token and
+    /// refresh token are kept, so the login can still be verified and refreshed.
+    pub async fn exchange_token(
+        &self,
+        token: &TokenResponse,
+        audience: Option<&str>,
+        scope: &[String],
+    ) -> Result<TokenResponse> {
+        let scope = scope.join(" ");
+        let mut params = vec![
+            (
+                "grant_type",
+                "urn:ietf:params:oauth:grant-type:token-exchange",
+            ),
+            ("subject_token", &token.access_token),
+            (
+                "subject_token_type",
+                "urn:ietf:params:oauth:token-type:access_token",
+            ),
+        ];
+        if let Some(audience) = audience {
+            params.push(("audience", audience));
+        }
+        if !scope.is_empty() {
+            params.push(("scope", &scope));
+        }
+        let exchanged = self.token_request(&params).await?.into_result()?;
+        Ok(TokenResponse {
+            access_token: exchanged.access_token,
+            token_type: exchanged.token_type,
+            scope: exchanged.scope,
+            expires_in: exchanged.expires_in,
+            extra: exchanged.extra,
+            ..token.clone()
+        })
+    }
+
+    /// Verifies the id_token of `token` against the provider JWKS and returns its claims. The
+    /// token must be issued by [`Endpoints::issuer`] for the client id, or for the audience set
+    /// with [`with_audience`](Self::with_audience).
+    pub async fn verify_id_token(&self, token: &TokenResponse) -> Result<Value> {
+        let id_token = token
+            .id_token
+            .as_deref()
+            .ok_or_else(|| Error::Token("token response has no id_token".to_string()))?;
+        let jwks = self.jwks(jwks::kid(id_token)?.as_deref()).await?;
+        jwks::verify(
+            id_token,
+            &jwks,
+            &jwks::Expected {
+                audience: self.audience.as_deref().unwrap_or(&self.client_id),
+                issuer: self.endpoints.issuer.as_deref(),
+                clock_skew: self.clock_skew.as_secs(),
+            },
+        )
+    }
+
+    /// The provider JWKS, from the cache while it is fresh and has the key for `kid`.
+    async fn jwks(&self, kid: Option<&str>) -> Result<JwkSet> {
+        let url = &self.endpoints.jwks_url;
+        let Some(cache) = &self.jwks_cache else {
+            return self.http.get_json(url, None).await;
+        };
+        let cached = cache.load::<JwkSet>(url);
+        if let Some(cached) = &cached {
+            if cached.fresh && jwks::has_key(&cached.value, kid) {
+                return Ok(cached.value.clone());
+            }
+        }
+        match self.http.get_json::<JwkSet>(url, None).await {
+            Ok(jwks) => {
+                cache.store(url, &jwks);
+                Ok(jwks)
+            }
+            Err(err @ Error::Network(_)) => match cached {
+                Some(cached) => {
+                    warn!("Using the cached JWKS of {}: {}", url, err);
+                    Ok(cached.value)
+                }
+                None => Err(err),
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Fetches the claims of the user from the userinfo endpoint with the access token of
+    /// `token`.
+    pub async fn userinfo(&self, token: &TokenResponse) -> Result<Value> {
+        let url = self
+            .endpoints
+            .userinfo_url
+            .as_deref()
+            .ok_or_else(|| Error::Config("no userinfo endpoint is available".to_string()))?;
+        let (status, text) = self.get_with_token(url, token).await?;
+        if !status.is_success() {
+            return Err(Error::idp(
+                "invalid_token".to_string(),
+                Some(format!("userinfo request failed with {}", status)),
+            ));
+        }
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// GETs `url` with the access token of `token`, DPoP-bound when it is.
+    async fn get_with_token(
+        &self,
+        url: &str,
+        token: &TokenResponse,
+    ) -> Result<(StatusCode, String)> {
+        match &self.dpop {
+            Some(dpop) if token.token_type.eq_ignore_ascii_case("DPoP") => {
+                let proof = dpop.proof("GET", url, Some(&token.access_token))?;
+                let authorization = format!("DPoP {}", token.access_token);
+                self.http
+                    .get_with_headers(url, &[("Authorization", &authorization), ("DPoP", &proof)])
+                    .await
+            }
+            _ => self.http.get(url, Some(&token.access_token)).await,
+        }
+    }
+
+    /// The groups the [`with_groups_url`](Self::with_groups_url) API lists for `token`.
+    async fn groups(&self, lookup: &GroupsLookup, token: &TokenResponse) -> Result<Vec<Value>> {
+        let mut groups = Vec::new();
+        let mut url = Some(lookup.url.clone());
+        for _ in 0..MAX_GROUP_PAGES {
+            let Some(page_url) = url.take() else {
+                break;
+            };
+            let (status, text) = self.get_with_token(&page_url, token).await?;
+            if !status.is_success() {
+                return Err(Error::Network(format!(
+                    "groups request to {} failed with {}",
+                    page_url, status
+                )));
+            }
+            let mut page: Value = serde_json::from_str(&text)?;
+            let entries = match &mut page {
+                Value::Array(entries) => entries,
+                page => {
+                    url = page
+                        .get("@odata.nextLink")
+                        .and_then(Value::as_str)
+                        .map(str::to_string);
+                    match page.get_mut("value") {
+                        Some(Value::Array(entries)) => entries,
+                        _ => return Err(Error::Token("unexpected groups response".to_string())),
+                    }
+                }
+            };
+            groups.extend(entries.drain(..).filter_map(|entry| match entry {
+                Value::Object(mut object) => object.remove(&lookup.field).filter(Value::is_string),
+                Value::String(_) => Some(entry),
+                _ => None,
+            }));
+        }
+        if url.is_some() {
+            warn!("Stopped reading groups after {} pages", MAX_GROUP_PAGES);
+        }
+        Ok(groups)
+    }
+
+    /// Validates the access token of `token` at the introspection endpoint and returns the
+    /// introspection response, which carries `username`, `scope` and similar claims.
+    pub async fn introspect(&self, token: &TokenResponse) -> Result<Value> {
+        let url =
+            self.endpoints.introspection_url.as_deref().ok_or_else(|| {
+                Error::Config("no introspection endpoint is available".to_string())
+            })?;
+        let params = [
+            ("token", token.access_token.as_str()),
+            ("token_type_hint", "access_token"),
+        ];
+        let (_, _, text) = self.client_post(url, &params, None).await?;
+        let claims: Value = serde_json::from_str(&text)?;
+        if claims.get("active").and_then(Value::as_bool) != Some(true) {
+            return Err(Error::Token("access token is not active".to_string()));
+        }
+        Ok(claims)
+    }
+
+    /// Revokes `token` at the revocation endpoint (RFC 7009), with `token_type_hint`
+    /// `refresh_token` or `access_token`. Tokens the provider does not know count as revoked.
+    pub async fn revoke(&self, token: &str, token_type_hint: &str) -> Result<()> {
+        let url = self
+            .endpoints
+            .revocation_url
+            .as_deref()
+            .ok_or_else(|| Error::Config("no revocation endpoint is available".to_string()))?;
+        let params = [("token", token), ("token_type_hint", token_type_hint)];
+        let (status, _, text) = self.client_post(url, &params, None).await?;
+        if status.is_success() {
+            return Ok(());
+        }
+        let response: Value = serde_json::from_str(&text).unwrap_or_default();
+        match response.get("error").and_then(Value::as_str) {
+            Some(error) => Err(Error::idp(
+                error.to_string(),
+                response
+                    .get("error_description")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+            )),
+            None => Err(Error::Network(format!(
+                "revocation request failed with {}",
+                status
+            ))),
+        }
+    }
+
+    /// Ends the session of the user at the provider (OpenID Connect RP-Initiated Logout 1.0),
+    /// identified by the `id_token` it issued.
+    pub async fn end_session(&self, id_token: &str) -> Result<()> {
+        let url = self
+            .endpoints
+            .end_session_url
+            .as_deref()
+            .ok_or_else(|| Error::Config("no end session endpoint is available".to_string()))?;
+        let params = [("id_token_hint", id_token), ("client_id", &self.client_id)];
+        let (status, _, _) = self
+            .http
+            .post_form(url, encode(&params)?, None, &[])
+            .await?;
+        // Providers answer with their logout page or a redirect to it.
+        if status.is_client_error() || status.is_server_error() {
+            return Err(Error::Network(format!(
+                "end session request failed with {}",
+                status
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the verified id_token claims. For providers that return no id_token, the claims
+    /// come from introspection when it is configured and from the userinfo endpoint otherwise;
+    /// userinfo claims are only as trustworthy as the TLS connection to the provider, since they
+    /// carry no signature, audience or issuer.
+    pub async fn claims(&self, token: &TokenResponse) -> Result<Value> {
+        let mut claims = match (&token.id_token, &self.endpoints.introspection_url) {
+            (Some(_), _) => self.verify_id_token(token).await?,
+            (None, Some(_)) => self.introspect(token).await?,
+            (None, None) => self.userinfo(token).await?,
+        };
+        if let Some(lookup) = &self.groups_lookup {
+            if claims.get(&lookup.claim).is_none() && claims.is_object() {
+                debug!("Reading the groups from {}", lookup.url);
+                claims[&lookup.claim] = Value::Array(self.groups(lookup, token).await?);
+            }
+        }
+        Ok(claims)
+    }
+
+    /// POSTs `params` to the token endpoint with the [`client_params`](Self::client_params) and
+    /// a DPoP proof when a key is set. A `use_dpop_nonce` error is retried once with the nonce
+    /// the provider sent along.
+    async fn token_request(&self, params: &[(&str, &str)]) -> Result<JsonResult<TokenResponse>> {
+        let url = &self.endpoints.token_url;
+        let Some(dpop) = &self.dpop else {
+            let (_, _, text) = self.client_post(url, params, None).await?;
+            return Ok(serde_json::from_str(&text)?);
+        };
+        let mut retried = false;
+        loop {
+            let (_, headers, text) = self.client_post(url, params, Some(dpop)).await?;
+            let new_nonce = headers
+                .get("dpop-nonce")
+                .and_then(|nonce| nonce.to_str().ok())
+                .is_some_and(|nonce| dpop.set_nonce(nonce));
+            let result: JsonResult<TokenResponse> = serde_json::from_str(&text)?;
+            match &result {
+                JsonResult::Err { error, .. }
+                    if error == "use_dpop_nonce" && new_nonce && !retried =>
+                {
+                    debug!("Retrying the token request with the DPoP nonce");
+                    retried = true;
+                }
+                _ => return Ok(result),
+            }
+        }
+    }
+
+    /// POSTs `params` to `url` with the client authentication and, with `dpop`, a DPoP proof.
+    /// Both are made anew for every retry, since the provider may reject a used one.
+    async fn client_post(
+        &self,
+        url: &str,
+        params: &[(&str, &str)],
+        dpop: Option<&DpopKey>,
+    ) -> Result<(StatusCode, HeaderMap, String)> {
+        self.http
+            .post_form_with(url, self.basic_auth(), move || async move {
+                let client = self.client_params().await?;
+                let mut headers = Vec::new();
+                if let Some(dpop) = dpop {
+                    headers.push(("DPoP", dpop.proof("POST", url, None)?));
+                }
+                Ok((encode(&[&pairs(&client), params].concat())?, headers))
+            })
+            .await
+    }
+
+    /// `client_id` and, for `client_secret_post`, `client_secret` body parameters, or a new
+    /// `client_assertion` for `private_key_jwt`.
+    async fn client_params(&self) -> Result<Vec<(&'static str, String)>> {
+        let mut params = vec![("client_id", self.client_id.clone())];
+        if let Some(assertion) = &self.client_assertion {
+            params.extend([
+                ("client_assertion_type", JWT_BEARER_ASSERTION.to_string()),
+                (
+                    "client_assertion",
+                    assertion
+                        .sign(&self.client_id, &self.endpoints.token_url)
+                        .await?,
+                ),
+            ]);
+        } else if let (Some(secret), ClientAuthMethod::ClientSecretPost) =
+            (&self.client_secret, self.client_auth_method)
+        {
+            params.push(("client_secret", secret.clone()));
+        }
+        Ok(params)
+    }
+
+    /// `auth_params` as body parameters.
+    fn auth_params(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.auth_params
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    fn basic_auth(&self) -> Option<(&str, &str)> {
+        match (self.client_secret.as_deref(), self.client_auth_method) {
+            (Some(secret), ClientAuthMethod::ClientSecretBasic) => {
+                Some((self.client_id.as_str(), secret))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `params` borrowed for [`encode`].
+fn pairs<'a>(params: &'a [(&'static str, String)]) -> Vec<(&'a str, &'a str)> {
+    params
+        .iter()
+        .map(|(key, value)| (*key, value.as_str()))
+        .collect()
+}
+
+/// The `application/x-www-form-urlencoded` body of `params`.
+fn encode(params: &[(&str, &str)]) -> Result<String> {
+    serde_urlencoded::to_string(params)
+        .map_err(|err| Error::Config(format!("failed to encode the request: {}", err)))
+}
+
+fn error_message(error: &str, error_description: Option<&str>) -> String {
+    error_description.map_or_else(|| error.to_string(), |d| format!("{}: {}", error, d))
+}
+
+/// Checks that the discovery document of `issuer` is for that issuer (OpenID Connect Discovery
+/// 1.0, section 4.3). Entra ID names its issuers by the tenant id, so the issuer of a `tenant`
+/// given as a domain only has to be one of a tenant.
+fn check_issuer(config: &Config, issuer: &str, discovered: &str) -> Result<()> {
+    if discovered.contains("{tenantid}") {
+        return Err(Error::Config(format!(
+            "{} is a multi-tenant authority; set tenant to the id or domain of a tenant",
+            issuer
+        )));
+    }
+    let matches = match (&config.issuer, &config.tenant) {
+        (None, Some(_)) => discovered
+            .strip_prefix(ENTRA_ID)
+            .is_some_and(|path| path.starts_with('/') && path.ends_with("/v2.0")),
+        _ => discovered.trim_end_matches('/') == issuer.trim_end_matches('/'),
+    };
+    if !matches {
+        return Err(Error::Config(format!(
+            "discovery document of {} is for issuer {}",
+            issuer, discovered
+        )));
+    }
+    Ok(())
+}
+
+/// Fetches the OpenID configuration of `issuer` at `url`. A document that is not there points at
+/// a wrong `issuer` rather than at an outage, so it is a configuration error.
+async fn fetch_discovery(
+    issuer: &str,
+    url: &str,
+    http: &HttpConfig,
+) -> Result<OpenIdConfiguration> {
+    let (status, text) = http.get(url, None).await?;
+    // A throttled request is an outage of the provider as much as an unanswered one.
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(Error::Network(format!("{} answered {}", url, status)));
+    }
+    if !status.is_success() {
+        return Err(Error::Config(format!(
+            "{} answered {}; check that issuer {} is the URL of the provider",
+            url, status, issuer
+        )));
+    }
+    serde_json::from_str(&text).map_err(|err| {
+        Error::Config(format!(
+            "{} is not an OpenID configuration ({}); check that issuer {} is the URL of the \
+             provider",
+            url, err, issuer
+        ))
+    })
+}
+
+/// Fetches the OpenID configuration of `issuer`, from `cache` while it is fresh and whenever the
+/// provider cannot be reached.
+async fn discover(
+    issuer: &str,
+    http: &HttpConfig,
+    cache: Option<&DiskCache>,
+) -> Result<OpenIdConfiguration> {
+    let url = discovery_url(issuer);
+    let Some(cache) = cache else {
+        return fetch_discovery(issuer, &url, http).await;
+    };
+    let cached = cache.load::<OpenIdConfiguration>(&url);
+    if let Some(cached) = &cached {
+        if cached.fresh {
+            return Ok(cached.value.clone());
+        }
+    }
+    match fetch_discovery(issuer, &url, http).await {
+        Ok(discovery) => {
+            cache.store(&url, &discovery);
+            Ok(discovery)
+        }
+        Err(err @ Error::Network(_)) => match cached {
+            Some(cached) => {
+                warn!(
+                    "Using the cached OpenID configuration of {}: {}",
+                    issuer, err
+                );
+                Ok(cached.value)
+            }
+            None => Err(err),
+        },
+        Err(err) => Err(err),
+    }
+}
+
+fn discovery_url(issuer: &str) -> String {
+    format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    )
+}
+
+/// Verifies the id_token of `token` like [`DeviceFlowClient::verify_id_token`] with the JWKS
+/// and OpenID configuration of `config` kept in `cache_dir`, however old, for the logins while
+/// the provider cannot be reached.
+#[cfg(feature = "pam")]
+pub(crate) fn verify_cached_id_token(config: &Config, token: &TokenResponse) -> Result<Value> {
+    let id_token = token
+        .id_token
+        .as_deref()
+        .ok_or_else(|| Error::Token("cached token has no id_token".to_string()))?;
+    let cache = DiskCache::new(&config.cache_dir, Duration::ZERO);
+    let discovery = match config.issuer() {
+        Some(issuer) => match cache.load::<OpenIdConfiguration>(&discovery_url(&issuer)) {
+            Some(cached) => {
+                check_issuer(config, &issuer, &cached.value.issuer)?;
+                Some(cached.value)
+            }
+            None => None,
+        },
+        None => None,
+    };
+    let jwks_url = config
+        .endpoint(&config.jwks_url)
+        .or_else(|| discovery.as_ref().map(|d| d.jwks_uri.clone()))
+        .ok_or_else(|| {
+            Error::Token("no cached OpenID configuration to find the JWKS".to_string())
+        })?;
+    let jwks = cache
+        .load::<JwkSet>(&jwks_url)
+        .ok_or_else(|| Error::Token(format!("no cached JWKS of {}", jwks_url)))?;
+    // Without the configuration, the tokens are taken to name the issuer as configured.
+    let issuer = discovery.map(|d| d.issuer).or_else(|| config.issuer());
+    jwks::verify(
+        id_token,
+        &jwks.value,
+        &jwks::Expected {
+            audience: config.audience.as_deref().unwrap_or(&config.client_id),
+            issuer: issuer.as_deref(),
+            clock_skew: config.clock_skew,
+        },
+    )
+}
@@ -0,0 +1,169 @@
+//! OAuth 2.0 Demonstrating Proof of Possession (RFC 9449): the requests carry a JWT signed with
+//! a host key, so that the provider can bind the tokens to that key.
+
+use crate::error::{Error, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde_json::{json, Value};
+use std::{
+    fmt,
+    fs::{self, DirBuilder, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::Path,
+    process,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// ES256 key signing the DPoP proofs, with the last nonce the provider asked for.
+pub struct DpopKey {
+    key_pair: EcdsaKeyPair,
+    rng: SystemRandom,
+    nonce: Mutex<Option<String>>,
+}
+
+impl fmt::Debug for DpopKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DpopKey")
+            .field("jwk", &self.jwk())
+            .finish_non_exhaustive()
+    }
+}
+
+impl DpopKey {
+    /// A new key that only lives as long as the process.
+    pub fn generate() -> Result<Self> {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .map_err(|_| Error::Config("failed to generate the DPoP key".to_string()))?;
+        Self::from_pkcs8(pkcs8.as_ref())
+    }
+
+    /// The PKCS#8 key in `path`, generated with mode 0600 on first use so that every login of
+    /// the host proves the same key.
+    pub fn load_or_generate<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let invalid = |err: &dyn fmt::Display| {
+            Error::Config(format!("invalid DPoP key {}: {}", path.display(), err))
+        };
+        match fs::read(path) {
+            Ok(pkcs8) => return Self::from_pkcs8(&pkcs8).map_err(|err| invalid(&err)),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(invalid(&err)),
+        }
+        if let Some(parent) = path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)
+                .map_err(|err| invalid(&err))?;
+        }
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .map_err(|_| Error::Config("failed to generate the DPoP key".to_string()))?;
+        // The key is written to a temporary file and linked into place, so that a login never
+        // reads it half written; the name is random as logins of the same process may race too.
+        let mut suffix = [0; 8];
+        rng.fill(&mut suffix)
+            .map_err(|_| Error::Config("failed to generate the DPoP key".to_string()))?;
+        let suffix: String = suffix.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let tmp = path.with_extension(format!("tmp.{}.{}", process::id(), suffix));
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut file| {
+                file.write_all(pkcs8.as_ref())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::hard_link(&tmp, path));
+        let _ = fs::remove_file(&tmp);
+        match created {
+            Ok(()) => Self::from_pkcs8(pkcs8.as_ref()),
+            // Another login created the key concurrently.
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => Self::load_or_generate(path),
+            Err(err) => Err(invalid(&err)),
+        }
+    }
+
+    fn from_pkcs8(pkcs8: &[u8]) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng)
+            .map_err(|err| Error::Config(format!("invalid DPoP key: {}", err)))?;
+        Ok(Self {
+            key_pair,
+            rng,
+            nonce: Mutex::new(None),
+        })
+    }
+
+    /// Public key as a JWK, taken from its uncompressed point `04 || x || y`.
+    pub fn jwk(&self) -> Value {
+        let point = self.key_pair.public_key().as_ref();
+        json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        })
+    }
+
+    /// Proof for a `method` request to `url`, bound to `access_token` when it is presented as
+    /// well (RFC 9449, section 4.2).
+    pub fn proof(&self, method: &str, url: &str, access_token: Option<&str>) -> Result<String> {
+        let mut jti = [0; 16];
+        self.rng
+            .fill(&mut jti)
+            .map_err(|_| Error::Config("no random numbers available".to_string()))?;
+        let header = json!({"typ": "dpop+jwt", "alg": "ES256", "jwk": self.jwk()});
+        let mut claims = json!({
+            "jti": URL_SAFE_NO_PAD.encode(jti),
+            "htm": method,
+            // The URI without query and fragment.
+            "htu": url.split(['?', '#']).next().unwrap_or(url),
+            "iat": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        });
+        if let Some(access_token) = access_token {
+            claims["ath"] = URL_SAFE_NO_PAD
+                .encode(digest(&SHA256, access_token.as_bytes()))
+                .into();
+        }
+        if let Some(nonce) = self.nonce.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+            claims["nonce"] = nonce.into();
+        }
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = self
+            .key_pair
+            .sign(&self.rng, signing_input.as_bytes())
+            .map_err(|_| Error::Config("failed to sign the DPoP proof".to_string()))?;
+        Ok(format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature)
+        ))
+    }
+
+    /// Keeps the `DPoP-Nonce` the provider sent for the next proofs, returning whether it
+    /// changed.
+    pub(crate) fn set_nonce(&self, nonce: &str) -> bool {
+        let mut current = self.nonce.lock().unwrap_or_else(|e| e.into_inner());
+        if current.as_deref() == Some(nonce) {
+            return false;
+        }
+        *current = Some(nonce.to_string());
+        true
+    }
+}
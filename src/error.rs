@@ -0,0 +1,129 @@
+#[cfg(feature = "pam")]
+use log::{error, warn};
+#[cfg(feature = "pam")]
+use pam::constants::PamResultCode;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Failures of the device flow and of the PAM module. With the `pam` feature each one maps to the
+/// PAM code reported to the application.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The module arguments or configuration file are invalid.
+    #[error("configuration error: {0}")]
+    Config(String),
+    /// The identity provider could not be reached.
+    #[error("network error: {0}")]
+    Network(String),
+    /// The identity provider answered with something that is not the expected JSON.
+    #[error("invalid response: {0}")]
+    Response(#[from] serde_json::Error),
+    /// The identity provider returned an OAuth error response.
+    #[error("identity provider error: {}", match .description {
+        Some(description) => format!("{}: {}", .error, description),
+        None => .error.clone(),
+    })]
+    IdP {
+        error: String,
+        description: Option<String>,
+    },
+    /// The id_token is missing, malformed or fails verification.
+    #[error("token error: {0}")]
+    Token(String),
+    /// The token is valid but the user is not allowed in.
+    #[error("authorization error: {0}")]
+    Authorization(String),
+    /// The token cache could not be read or written.
+    #[error("token cache error: {0}")]
+    Cache(String),
+    /// An out-of-band notification could not be delivered.
+    #[error("notification error: {0}")]
+    Notification(String),
+    /// The SSH CA refused or failed to sign a certificate.
+    #[error("SSH CA error: {0}")]
+    SshCa(String),
+    /// The local account of the user could not be created.
+    #[error("provisioning error: {0}")]
+    Provision(String),
+    /// The user cancelled the flow, or polling was cancelled because the conversation failed.
+    #[error("token polling cancelled")]
+    Cancelled,
+    /// A call into PAM, such as the conversation, failed.
+    #[cfg(feature = "pam")]
+    #[error("PAM error: {0:?}")]
+    Pam(PamResultCode),
+}
+
+impl Error {
+    pub(crate) fn idp(error: String, description: Option<String>) -> Self {
+        Self::IdP { error, description }
+    }
+
+    /// Short name of the kind of error, e.g. `network`, for audit records and metrics.
+    pub fn class(&self) -> &'static str {
+        match self {
+            Self::Config(_) => "config",
+            Self::Network(_) => "network",
+            Self::Response(_) => "response",
+            Self::IdP { .. } => "idp",
+            Self::Token(_) => "token",
+            Self::Authorization(_) => "authorization",
+            Self::Cache(_) => "cache",
+            Self::Notification(_) => "notification",
+            Self::SshCa(_) => "ssh_ca",
+            Self::Provision(_) => "provision",
+            Self::Cancelled => "cancelled",
+            #[cfg(feature = "pam")]
+            Self::Pam(_) => "pam",
+        }
+    }
+}
+
+#[cfg(feature = "pam")]
+impl Error {
+    pub(crate) fn pam_code(self) -> PamResultCode {
+        match self {
+            Self::Config(_) => PamResultCode::PAM_SERVICE_ERR,
+            Self::Network(_) | Self::Response(_) => PamResultCode::PAM_AUTHINFO_UNAVAIL,
+            // Errors caused by the client registration rather than by the user.
+            Self::IdP { error, .. } => match error.as_str() {
+                "invalid_client"
+                | "unauthorized_client"
+                | "invalid_scope"
+                | "unsupported_grant_type" => PamResultCode::PAM_SERVICE_ERR,
+                _ => PamResultCode::PAM_AUTH_ERR,
+            },
+            Self::Token(_) => PamResultCode::PAM_AUTH_ERR,
+            Self::Authorization(_) => PamResultCode::PAM_PERM_DENIED,
+            Self::Cache(_) | Self::Notification(_) | Self::SshCa(_) | Self::Provision(_) => {
+                PamResultCode::PAM_SYSTEM_ERR
+            }
+            Self::Cancelled => PamResultCode::PAM_ABORT,
+            Self::Pam(code) => code,
+        }
+    }
+
+    /// Logs the error and returns its PAM code, for use as `pam_try!(result.map_err(Error::report))`.
+    pub(crate) fn report(self) -> PamResultCode {
+        match self {
+            Self::IdP { .. } | Self::Token(_) | Self::Authorization(_) | Self::Cancelled => {
+                warn!("{}", self)
+            }
+            _ => error!("{}", self),
+        }
+        self.pam_code()
+    }
+}
+
+#[cfg(feature = "pam")]
+impl From<PamResultCode> for Error {
+    fn from(code: PamResultCode) -> Self {
+        Self::Pam(crate::pam_abi::native(code))
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for Error {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        Self::Token(err.to_string())
+    }
+}
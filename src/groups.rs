@@ -0,0 +1,48 @@
+//! Local group membership, for choosing the identity provider of a user.
+
+use std::ffi::{CStr, CString};
+
+/// Group IDs of `getgrouplist`, which macOS declares as `int`.
+#[cfg(target_os = "macos")]
+type Gid = libc::c_int;
+#[cfg(not(target_os = "macos"))]
+type Gid = libc::gid_t;
+
+/// Names of the local groups of `user`, primary group included; empty for unknown users.
+pub(crate) fn of_user(user: &str) -> Vec<String> {
+    let Ok(name) = CString::new(user) else {
+        return Vec::new();
+    };
+    // SAFETY: `name` is a valid NUL-terminated string and the returned entry is only read
+    // before any other passwd lookup can overwrite it.
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Vec::new();
+    }
+    // SAFETY: checked for NULL above.
+    let gid = unsafe { (*passwd).pw_gid } as Gid;
+    let mut gids: Vec<Gid> = vec![0; 64];
+    loop {
+        let mut count = gids.len() as libc::c_int;
+        // SAFETY: `gids` has room for `count` entries; on -1 `count` is the number needed.
+        let result =
+            unsafe { libc::getgrouplist(name.as_ptr(), gid, gids.as_mut_ptr(), &mut count) };
+        if result >= 0 {
+            gids.truncate(count as usize);
+            break;
+        }
+        gids.resize((count as usize).max(gids.len() * 2), 0);
+    }
+    gids.into_iter()
+        .filter_map(|gid| {
+            // SAFETY: as for getpwnam, the entry is read before the next lookup.
+            let group = unsafe { libc::getgrgid(gid as _) };
+            if group.is_null() {
+                return None;
+            }
+            // SAFETY: checked for NULL above; gr_name is NUL-terminated.
+            let name = unsafe { CStr::from_ptr((*group).gr_name) };
+            Some(name.to_string_lossy().into_owned())
+        })
+        .collect()
+}
@@ -0,0 +1,1170 @@
+use crate::{
+    audit::Attempt,
+    broker, claims,
+    config::{Config, Flow, OnNetworkError},
+    dbus::Signals,
+    device_flow::{verify_cached_id_token, DeviceAuthResponse, DeviceFlowClient, TokenResponse},
+    error::{Error, Result},
+    groups,
+    http::HttpConfig,
+    i18n::Reason,
+    logger,
+    notify::{self, Notification},
+    pam_abi, provision,
+    rate_limit::RateLimit,
+    session,
+    ssh_cert::SshCa,
+    template,
+    token_store::TokenStore,
+};
+use log::{debug, error, info, warn};
+use pam::{
+    constants::{
+        PamFlag, PamResultCode, PAM_ERROR_MSG, PAM_PROMPT_ECHO_OFF, PAM_PROMPT_ECHO_ON,
+        PAM_REFRESH_CRED, PAM_REINITIALIZE_CRED, PAM_SILENT, PAM_TEXT_INFO,
+    },
+    conv::Conv,
+    items::{RHost, Service, Tty, User},
+    module::{PamHandle, PamHooks},
+    pam_try,
+};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    future::Future,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    runtime,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+/// `pam_set_data` key under which the authenticated token is shared between hooks.
+const TOKEN_DATA: &str = "pam_oauth2_df_token";
+
+/// Time the user has to complete `flow=authorization_code` without `max_auth_time`.
+const AUTHORIZATION_CODE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Time past the deadline the token polling gets to report the expiry itself, before the
+/// device flow gives up on it.
+const POLL_DEADLINE_GRACE: Duration = Duration::from_secs(1);
+
+/// `PAM_PRELIM_CHECK` of `<security/_pam_types.h>`: the first pass of `pam_chauthtok`.
+const PAM_PRELIM_CHECK: PamFlag = 0x4000;
+
+pub(crate) struct PamOauth2;
+#[cfg(not(any(target_os = "freebsd", target_os = "macos")))]
+pam::pam_hooks!(PamOauth2);
+
+/// Result of a successful authentication, handed to the other hooks through `pam_set_data` and
+/// kept in the token cache.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CachedToken {
+    pub(crate) token: TokenResponse,
+    /// Verified id_token or userinfo claims.
+    pub(crate) claims: Value,
+    /// Unix time at which the access token expires.
+    pub(crate) expires_at: Option<u64>,
+    /// Name of the provider that issued the token, when `providers` are configured.
+    #[serde(default)]
+    pub(crate) provider: Option<String>,
+    /// Unix time at which the claims were last verified with the provider.
+    #[serde(default)]
+    pub(crate) verified_at: u64,
+}
+
+impl CachedToken {
+    pub(crate) fn new(token: TokenResponse, claims: Value, provider: Option<String>) -> Self {
+        Self {
+            expires_at: token
+                .expires_in
+                .map(|expires_in| unix_time() + expires_in)
+                .or_else(|| claims.get("exp").and_then(Value::as_u64)),
+            token,
+            claims,
+            provider,
+            verified_at: unix_time(),
+        }
+    }
+}
+
+/// Login kept for `grace_period`, per user and origin of the login.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct GraceLogin {
+    cached: CachedToken,
+    /// Unix time of the login.
+    authenticated_at: u64,
+}
+
+impl PamHooks for PamOauth2 {
+    fn sm_authenticate(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+        logger::init(pamh);
+        let config = pam_try!(load_config(pamh, args).map_err(Error::report));
+        let messages = config.messages();
+
+        let mut attempt = Attempt::start(&config);
+        let on_network_error = config.on_network_error;
+        // Local logins have no remote host to hold back.
+        let rate_limit = pamh
+            .get_item::<RHost>()
+            .ok()
+            .flatten()
+            .and_then(|rhost| RateLimit::from_config(&config, &rhost.0.to_string_lossy()));
+        pam_try!(block_on(async {
+            let result = authenticate(pamh, config, &mut attempt, rate_limit.as_ref()).await;
+            let result = match result {
+                PamResultCode::PAM_AUTHINFO_UNAVAIL if attempt.error() == Some("network") => {
+                    match on_network_error {
+                        OnNetworkError::Deny => PamResultCode::PAM_AUTH_ERR,
+                        OnNetworkError::Ignore => PamResultCode::PAM_IGNORE,
+                        OnNetworkError::Unavail => PamResultCode::PAM_AUTHINFO_UNAVAIL,
+                    }
+                }
+                result => result,
+            };
+            if let Some(rate_limit) = &rate_limit {
+                rate_limit.record(matches!(result, PamResultCode::PAM_SUCCESS));
+            }
+            if !matches!(result, PamResultCode::PAM_SUCCESS) && flags & PAM_SILENT == 0 {
+                if let (Some(reason), Ok(messages)) = (attempt.reason(), &messages) {
+                    tell_failure(pamh, messages.reason(reason));
+                }
+            }
+            attempt.finish(pamh, &result).await;
+            result
+        }))
+    }
+
+    fn acct_mgmt(pamh: &mut PamHandle, args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        logger::init(pamh);
+        let config = pam_try!(load_config(pamh, args).map_err(Error::report));
+
+        let user = match pam_try!(pamh.get_item::<User>().map_err(pam_abi::native)) {
+            Some(user) => pam_try!(user.to_str(), PamResultCode::PAM_USER_UNKNOWN).to_string(),
+            None => return PamResultCode::PAM_USER_UNKNOWN,
+        };
+
+        let cached = match cached_token(pamh, &config, &user) {
+            Some(cached) => cached,
+            None => {
+                warn!("No OAuth2 token available for {}", user);
+                return PamResultCode::PAM_PERM_DENIED;
+            }
+        };
+
+        let now = unix_time();
+        // Logins let in by offline_login carry the token that expired while the provider was
+        // unreachable.
+        if cached
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now)
+            && config
+                .offline_login
+                .is_none_or(|offline_login| cached.verified_at + offline_login <= now)
+        {
+            warn!("OAuth2 token for {} has expired", user);
+            return PamResultCode::PAM_ACCT_EXPIRED;
+        }
+        if !claims::matches_user(&config, &cached.token, &cached.claims, &user) {
+            warn!("OAuth2 token does not belong to {}", user);
+            return PamResultCode::PAM_PERM_DENIED;
+        }
+        pam_try!(claims::authorize(&config, &cached.claims).map_err(Error::report));
+
+        PamResultCode::PAM_SUCCESS
+    }
+
+    fn sm_open_session(pamh: &mut PamHandle, args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        logger::init(pamh);
+        let config = pam_try!(load_config(pamh, args).map_err(Error::report));
+        if !config.export_tokens && !config.token_file && config.claim_env.is_empty() {
+            return PamResultCode::PAM_SUCCESS;
+        }
+
+        let user = match pam_try!(pamh.get_item::<User>().map_err(pam_abi::native)) {
+            Some(user) => pam_try!(user.to_str(), PamResultCode::PAM_SESSION_ERR).to_string(),
+            None => return PamResultCode::PAM_SESSION_ERR,
+        };
+        let cached = match cached_token(pamh, &config, &user) {
+            Some(cached) => cached,
+            None => return PamResultCode::PAM_IGNORE,
+        };
+        if config.export_tokens {
+            pam_try!(session::export(pamh, &cached));
+        }
+        pam_try!(session::export_claims(
+            pamh,
+            &config.claim_env,
+            &cached.claims
+        ));
+        if config.token_file {
+            // A missing runtime directory must not keep the user out.
+            if let Err(err) = session::write_token_file(pamh, &user, &cached) {
+                warn!("Failed to write the token file of {}: {}", user, err);
+            }
+        }
+
+        PamResultCode::PAM_SUCCESS
+    }
+
+    fn sm_close_session(pamh: &mut PamHandle, args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        logger::init(pamh);
+        let config = pam_try!(load_config(pamh, args).map_err(Error::report));
+        if config.export_tokens {
+            pam_try!(session::clear(pamh));
+        }
+        pam_try!(session::clear_claims(pamh, &config.claim_env));
+        if !config.token_file && !config.revoke_on_logout && !config.end_session_on_logout {
+            return PamResultCode::PAM_SUCCESS;
+        }
+        let user = match pam_try!(pamh.get_item::<User>().map_err(pam_abi::native)) {
+            Some(user) => pam_try!(user.to_str(), PamResultCode::PAM_SESSION_ERR).to_string(),
+            None => return PamResultCode::PAM_SESSION_ERR,
+        };
+        if config.token_file {
+            if let Err(err) = session::remove_token_file(pamh, &user) {
+                warn!("Failed to remove the token file of {}: {}", user, err);
+            }
+        }
+        if config.revoke_on_logout || config.end_session_on_logout {
+            if let Some(cached) = cached_token(pamh, &config, &user) {
+                // The session is over whether or not the provider could be told.
+                let _ = block_on(log_out(&config, &user, cached));
+            }
+        }
+
+        PamResultCode::PAM_SUCCESS
+    }
+
+    fn sm_setcred(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+        if flags & (PAM_REFRESH_CRED | PAM_REINITIALIZE_CRED) == 0 {
+            return PamResultCode::PAM_SUCCESS;
+        }
+        logger::init(pamh);
+        let config = pam_try!(load_config(pamh, args).map_err(Error::report));
+
+        let user = match pam_try!(pamh.get_item::<User>().map_err(pam_abi::native)) {
+            Some(user) => pam_try!(user.to_str(), PamResultCode::PAM_USER_UNKNOWN).to_string(),
+            None => return PamResultCode::PAM_USER_UNKNOWN,
+        };
+        let cached = match cached_token(pamh, &config, &user) {
+            Some(cached) => cached,
+            None => return PamResultCode::PAM_CRED_UNAVAIL,
+        };
+
+        pam_try!(block_on(refresh_credentials(pamh, config, &user, cached)))
+    }
+
+    /// The password lives at the provider, so `passwd` is pointed there and fails in the
+    /// preliminary check, before any new password is asked for.
+    fn sm_chauthtok(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+        logger::init(pamh);
+        let config = pam_try!(load_config(pamh, args).map_err(Error::report));
+        if flags & PAM_PRELIM_CHECK == 0 || flags & PAM_SILENT != 0 {
+            return PamResultCode::PAM_AUTHTOK_ERR;
+        }
+
+        let user = match pam_try!(pamh.get_item::<User>().map_err(pam_abi::native)) {
+            Some(user) => pam_try!(user.to_str(), PamResultCode::PAM_USER_UNKNOWN).to_string(),
+            None => return PamResultCode::PAM_USER_UNKNOWN,
+        };
+        let config = pam_try!(config
+            .for_user(&user, &groups::of_user(&user))
+            .map_err(Error::report));
+        let url = config
+            .password_url
+            .clone()
+            .or_else(|| config.issuer())
+            .map(|url| template::render(&url, &[("user", &user)]))
+            .unwrap_or_default();
+        let messages = pam_try!(config.messages().map_err(Error::report));
+        let message =
+            template::render(&messages.password_change, &[("user", &user), ("url", &url)]);
+        info!("Refusing password change of {}", user);
+        let conv = pam_try!(pamh.get_item::<Conv>().map_err(pam_abi::native)).unwrap();
+        pam_try!(conv.send(PAM_ERROR_MSG, &message).map_err(pam_abi::native));
+
+        PamResultCode::PAM_AUTHTOK_ERR
+    }
+}
+
+/// The refresh of `sm_setcred`: renews the `cached` login of `user` with its refresh token.
+async fn refresh_credentials(
+    pamh: &mut PamHandle,
+    config: Config,
+    user: &str,
+    cached: CachedToken,
+) -> PamResultCode {
+    // The token can only be refreshed at the provider that issued it.
+    let config = Config {
+        provider: cached.provider.clone(),
+        ..config
+    };
+    let (_, client) = pam_try!(DeviceFlowClient::from_providers(&config)
+        .await
+        .map_err(Error::report));
+    let refresh_token = match &cached.token.refresh_token {
+        Some(refresh_token) => refresh_token,
+        None => {
+            info!("No refresh token available for {}", user);
+            return PamResultCode::PAM_CRED_UNAVAIL;
+        }
+    };
+    // setcred has its own set of result codes, so the refresh errors are mapped here.
+    let token = match client.refresh_token(refresh_token).await {
+        Ok(token) => token,
+        Err(err @ Error::IdP { .. }) => {
+            warn!("Token refresh error: {}", err);
+            return PamResultCode::PAM_CRED_EXPIRED;
+        }
+        Err(err) => {
+            error!("Token refresh error: {}", err);
+            return PamResultCode::PAM_CRED_UNAVAIL;
+        }
+    };
+    let claims = match client.claims(&token).await {
+        Ok(claims) => claims,
+        Err(err) => {
+            error!("{}", err);
+            return PamResultCode::PAM_CRED_ERR;
+        }
+    };
+    if !claims::matches_user(&config, &token, &claims, user) {
+        warn!("Refreshed OAuth2 token does not belong to {}", user);
+        return PamResultCode::PAM_CRED_ERR;
+    }
+    let token = match exchange_token(&config, &client, token).await {
+        Ok(token) => token,
+        Err(err) => {
+            error!("Token exchange error: {}", err);
+            return PamResultCode::PAM_CRED_ERR;
+        }
+    };
+
+    let cached = CachedToken::new(token, claims, config.provider.clone());
+    if config.token_cache {
+        if let Err(err) =
+            TokenStore::new(&config.token_cache_dir, &config.token_cache_key).store(user, &cached)
+        {
+            warn!("{}", err);
+        }
+    }
+    if config.export_tokens {
+        pam_try!(session::export(pamh, &cached));
+    }
+    pam_try!(session::export_claims(
+        pamh,
+        &config.claim_env,
+        &cached.claims
+    ));
+    if config.token_file {
+        if let Err(err) = session::write_token_file(pamh, user, &cached) {
+            warn!("Failed to write the token file of {}: {}", user, err);
+        }
+    }
+    pam_try!(pamh.set_data(TOKEN_DATA, Box::new(cached)));
+
+    PamResultCode::PAM_SUCCESS
+}
+
+/// The login of `sm_authenticate`, recording in `attempt` why it failed.
+async fn authenticate(
+    pamh: &mut PamHandle,
+    config: Config,
+    attempt: &mut Attempt,
+    rate_limit: Option<&RateLimit>,
+) -> PamResultCode {
+    let pam_user = match pam_try!(pamh.get_item::<User>().map_err(pam_abi::native)) {
+        Some(user) => Some(pam_try!(user.to_str(), PamResultCode::PAM_AUTH_ERR).to_string()),
+        None => None,
+    };
+    let config = match &pam_user {
+        Some(user) => pam_try!(config
+            .for_user(user, &groups::of_user(user))
+            .map_err(|err| attempt.fail(err))),
+        None => config,
+    };
+    let config = with_pam_context(pamh, config, pam_user.as_deref());
+    if config.broker.is_some() {
+        return broker_login(pamh, config, attempt, rate_limit, pam_user).await;
+    }
+    // A login within the grace period needs no provider, nor its discovery document.
+    if let (Some(grace_period), Some(user)) = (config.grace_period, &pam_user) {
+        let providers = pam_try!(config.provider_configs().map_err(|err| attempt.fail(err)));
+        for config in providers {
+            if let Some(cached) = grace_login(pamh, &config, user, grace_period) {
+                attempt.set_config(&config);
+                attempt.set_claims(&cached.claims);
+                pam_try!(
+                    claims::authorize(&config, &cached.claims).map_err(|err| attempt.fail(err))
+                );
+                info!("Login of {} within the grace period", user);
+                pam_try!(pamh.set_data(TOKEN_DATA, Box::new(cached)));
+                return PamResultCode::PAM_SUCCESS;
+            }
+        }
+    }
+    let (config, client) = match DeviceFlowClient::from_providers(&config).await {
+        Ok(found) => found,
+        Err(err) => {
+            attempt.note(&err);
+            return offline_login(pamh, &config, pam_user.as_deref(), err);
+        }
+    };
+    attempt.set_config(&config);
+    let token_store = config
+        .token_cache
+        .then(|| TokenStore::new(&config.token_cache_dir, &config.token_cache_key));
+
+    let cached_token = match (&token_store, &pam_user) {
+        (Some(token_store), Some(user)) => {
+            refresh_cached_token(token_store, user, &config, &client).await
+        }
+        _ => None,
+    };
+    let token = match cached_token {
+        Some(token) => token,
+        None => {
+            if rate_limited(rate_limit, attempt) {
+                return PamResultCode::PAM_MAXTRIES;
+            }
+            let conv = pam_try!(pamh.get_item::<Conv>().map_err(pam_abi::native)).unwrap();
+            let token = match config.flow {
+                Flow::Device => {
+                    let context = PamContext::of(pamh);
+                    device_flow(&conv, &config, &context, &client, pam_user.as_deref()).await
+                }
+                Flow::AuthorizationCode => authorization_code_flow(&conv, &config, &client).await,
+                Flow::Ciba => ciba_flow(&conv, &config, &client, pam_user.as_deref()).await,
+            };
+            match token {
+                Ok(token) => token,
+                Err(err) => {
+                    attempt.note(&err);
+                    return offline_login(pamh, &config, pam_user.as_deref(), err);
+                }
+            }
+        }
+    };
+
+    let claims = pam_try!(client.claims(&token).await.map_err(|err| attempt.fail(err)));
+    finish_login(
+        pamh,
+        &config,
+        attempt,
+        pam_user,
+        Some(&client),
+        token,
+        claims,
+    )
+    .await
+}
+
+/// The login of `authenticate` through the `broker`, which runs the device flow with the
+/// provider and hands back the verified token.
+async fn broker_login(
+    pamh: &mut PamHandle,
+    config: Config,
+    attempt: &mut Attempt,
+    rate_limit: Option<&RateLimit>,
+    pam_user: Option<String>,
+) -> PamResultCode {
+    if rate_limited(rate_limit, attempt) {
+        return PamResultCode::PAM_MAXTRIES;
+    }
+    let conv = pam_try!(pamh.get_item::<Conv>().map_err(pam_abi::native)).unwrap();
+    let context = PamContext::of(pamh);
+    let login = broker::device_flow(&conv, &config, &context, pam_user.as_deref()).await;
+    let login = match login {
+        Ok(login) => login,
+        Err(err) => {
+            attempt.note(&err);
+            return offline_login(pamh, &config, pam_user.as_deref(), err);
+        }
+    };
+    let config = Config {
+        provider: login.provider,
+        ..config
+    };
+    attempt.set_config(&config);
+    finish_login(
+        pamh,
+        &config,
+        attempt,
+        pam_user,
+        None,
+        login.token,
+        login.claims,
+    )
+    .await
+}
+
+/// Whether the remote host has used up its failed logins, which `attempt` then records.
+fn rate_limited(rate_limit: Option<&RateLimit>, attempt: &mut Attempt) -> bool {
+    let Some(rate_limit) = rate_limit.filter(|rate_limit| rate_limit.exceeded()) else {
+        return false;
+    };
+    warn!("Too many failed logins from {}", rate_limit.rhost());
+    attempt.fail_with("rate_limit", Reason::TooManyFailures);
+    true
+}
+
+/// The rest of a login with the verified `token` and its `claims`: the checks of the user, and
+/// the records for the other hooks and the next logins. The token is exchanged with `client`,
+/// which the broker has already done for its logins.
+async fn finish_login(
+    pamh: &mut PamHandle,
+    config: &Config,
+    attempt: &mut Attempt,
+    pam_user: Option<String>,
+    client: Option<&DeviceFlowClient>,
+    token: TokenResponse,
+    claims: Value,
+) -> PamResultCode {
+    attempt.set_claims(&claims);
+    pam_try!(claims::check_auth_time(config, &claims).map_err(|err| attempt.fail(err)));
+
+    let username = match &pam_user {
+        Some(user) => {
+            if !claims::matches_user(config, &token, &claims, user) {
+                let (claim, value) = claims::match_claim(config, &token, &claims);
+                warn!("username unmatch: [{}]{}, [pam_user]{}", claim, value, user);
+                attempt.fail_with("username", Reason::WrongUser);
+                return PamResultCode::PAM_AUTH_ERR;
+            }
+            user.clone()
+        }
+        None => {
+            let username = pam_try!(
+                claims::local_user(config, &token, &claims).map_err(|err| attempt.fail(err))
+            );
+            let username_c = pam_try!(CString::new(username.as_str()), PamResultCode::PAM_AUTH_ERR);
+            pam_try!(pamh
+                .set_item_str(User(username_c.as_c_str()))
+                .map_err(pam_abi::native));
+            logger::set_user(&username);
+            username
+        }
+    };
+
+    pam_try!(claims::authorize(config, &claims).map_err(|err| attempt.fail(err)));
+    pam_try!(provision::ensure_account(config, &username, &claims)
+        .await
+        .map_err(|err| attempt.fail(err)));
+
+    if let Err(err) = issue_ssh_cert(config, &username, &token).await {
+        warn!("{}", err);
+    }
+    let token = match client {
+        Some(client) => pam_try!(exchange_token(config, client, token)
+            .await
+            .map_err(|err| attempt.fail(err))),
+        None => token,
+    };
+    let cached = CachedToken::new(token, claims, config.provider.clone());
+    if config.token_cache {
+        let token_store = TokenStore::new(&config.token_cache_dir, &config.token_cache_key);
+        if let Err(err) = token_store.store(&username, &cached) {
+            warn!("{}", err);
+        }
+    }
+    if config.grace_period.is_some() {
+        let grace_login = GraceLogin {
+            cached: cached.clone(),
+            authenticated_at: unix_time(),
+        };
+        if let Err(err) = grace_store(pamh, config).store(&username, &grace_login) {
+            warn!("{}", err);
+        }
+    }
+    pam_try!(pamh.set_data(TOKEN_DATA, Box::new(cached)));
+
+    info!("OAuth2 Device flow successed");
+    PamResultCode::PAM_SUCCESS
+}
+
+/// Ends the `cached` login of `user` at the provider that issued it, as `revoke_on_logout` and
+/// `end_session_on_logout` say. Failures are only logged.
+async fn log_out(config: &Config, user: &str, cached: CachedToken) {
+    let config = Config {
+        provider: cached.provider.clone(),
+        ..config.clone()
+    };
+    if config.revoke_on_logout && config.token_cache {
+        let token_store = TokenStore::new(&config.token_cache_dir, &config.token_cache_key);
+        if let Err(err) = token_store.remove(user) {
+            warn!("{}", err);
+        }
+    }
+    let client = match DeviceFlowClient::from_providers(&config).await {
+        Ok((_, client)) => client,
+        Err(err) => {
+            warn!("Not logging {} out at the provider: {}", user, err);
+            return;
+        }
+    };
+    if config.end_session_on_logout {
+        match &cached.token.id_token {
+            Some(id_token) => match client.end_session(id_token).await {
+                Ok(()) => debug!("Ended the provider session of {}", user),
+                Err(err) => warn!("Failed to end the provider session of {}: {}", user, err),
+            },
+            None => info!("No id_token to end the provider session of {} with", user),
+        }
+    }
+    if !config.revoke_on_logout {
+        return;
+    }
+    let tokens = [
+        (cached.token.refresh_token.as_deref(), "refresh_token"),
+        (Some(cached.token.access_token.as_str()), "access_token"),
+    ];
+    for (token, hint) in tokens {
+        let Some(token) = token else {
+            continue;
+        };
+        match client.revoke(token, hint).await {
+            Ok(()) => debug!("Revoked the {} of {}", hint, user),
+            Err(err) => warn!("Failed to revoke the {} of {}: {}", hint, user, err),
+        }
+    }
+}
+
+/// Shows the user why the login failed; the details are only in the log.
+fn tell_failure(pamh: &PamHandle, reason: &str) {
+    let conv = match pamh.get_item::<Conv>() {
+        Ok(Some(conv)) => conv,
+        _ => return,
+    };
+    if let Err(err) = conv.send(PAM_ERROR_MSG, reason) {
+        warn!("Failed to show the failure reason: {:?}", err);
+    }
+}
+
+/// Runs `future` to completion on a runtime of the hook. Its worker thread keeps spawned tasks,
+/// such as the token polling, going while the hook waits in the PAM conversation.
+fn block_on<F: Future>(future: F) -> std::result::Result<F::Output, PamResultCode> {
+    match runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => Ok(runtime.block_on(future)),
+        Err(err) => {
+            error!("Failed to start the async runtime: {}", err);
+            Err(PamResultCode::PAM_SYSTEM_ERR)
+        }
+    }
+}
+
+fn load_config(pamh: &PamHandle, args: Vec<&CStr>) -> Result<Config> {
+    let args: Vec<_> = args.iter().map(|s| s.to_string_lossy()).collect();
+    let mut args: HashMap<&str, &str> = args
+        .iter()
+        .map(|s| {
+            let mut parts = s.splitn(2, '=');
+            (parts.next().unwrap(), parts.next().unwrap_or(""))
+        })
+        .collect();
+    // The `[service.<name>]` section of the configuration file, unless `service` picks another.
+    let service = pamh.get_item::<Service>().ok().flatten();
+    let service = service.as_ref().map(|service| service.0.to_string_lossy());
+    if let Some(service) = &service {
+        args.entry("service").or_insert(service);
+    }
+
+    let config = Config::from_args(&args)?;
+    logger::set_level(config.log_level());
+    Ok(config)
+}
+
+/// Fills in the `{service}`, `{rhost}`, `{tty}`, `{host}` and `{user}` of the transaction in the
+/// `auth_params` and `binding_message`, so that the approval at the provider can show which
+/// machine is being logged into.
+fn with_pam_context(pamh: &PamHandle, config: Config, user: Option<&str>) -> Config {
+    let context = PamContext::of(pamh);
+    with_context(config, &context, user)
+}
+
+/// The items of a transaction that the requests to the provider can mention.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct PamContext {
+    pub(crate) service: String,
+    pub(crate) rhost: String,
+    pub(crate) tty: String,
+}
+
+impl PamContext {
+    pub(crate) fn of(pamh: &PamHandle) -> Self {
+        let item = |value: Option<&CStr>| {
+            value
+                .map(|value| value.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        Self {
+            service: item(pamh.get_item::<Service>().ok().flatten().map(|s| s.0)),
+            rhost: item(pamh.get_item::<RHost>().ok().flatten().map(|r| r.0)),
+            tty: item(pamh.get_item::<Tty>().ok().flatten().map(|t| t.0)),
+        }
+    }
+}
+
+/// [`with_pam_context`] for the items of `context`.
+pub(crate) fn with_context(mut config: Config, context: &PamContext, user: Option<&str>) -> Config {
+    let host = hostname();
+    let mut context = vec![
+        ("service", context.service.as_str()),
+        ("rhost", context.rhost.as_str()),
+        ("tty", context.tty.as_str()),
+        ("host", host.as_str()),
+    ];
+    // Without a PAM user, `{user}` is left for the flow to fill in.
+    if let Some(user) = user {
+        context.push(("user", user));
+    }
+    for value in config.auth_params.values_mut() {
+        *value = template::render(value, &context);
+    }
+    config.binding_message = config
+        .binding_message
+        .map(|message| template::render(&message, &context));
+    config
+}
+
+/// Name of this host, for the metrics and the requests to the provider.
+pub(crate) fn hostname() -> String {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer is writable for its whole length, and its last byte stays NUL.
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len() - 1) } != 0 {
+        return "localhost".to_string();
+    }
+    CStr::from_bytes_until_nul(&name)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Returns the token obtained by `sm_authenticate` on this handle, falling back to the token
+/// cache when it is enabled.
+fn cached_token(pamh: &PamHandle, config: &Config, user: &str) -> Option<CachedToken> {
+    // SAFETY: TOKEN_DATA is only ever set to a CachedToken by sm_authenticate.
+    if let Ok(cached) = unsafe { pamh.get_data::<CachedToken>(TOKEN_DATA) } {
+        return Some(cached.clone());
+    }
+    if !config.token_cache {
+        return None;
+    }
+    match TokenStore::new(&config.token_cache_dir, &config.token_cache_key).load(user) {
+        Ok(cached) => cached,
+        Err(err) => {
+            warn!("{}", err);
+            None
+        }
+    }
+}
+
+/// Lets `user` in on the token cache while the provider cannot be reached, if `offline_login`
+/// allows; otherwise reports `err`.
+fn offline_login(
+    pamh: &mut PamHandle,
+    config: &Config,
+    user: Option<&str>,
+    err: Error,
+) -> PamResultCode {
+    let (Error::Network(_), Some(offline_login), Some(user)) = (&err, config.offline_login, user)
+    else {
+        return err.report();
+    };
+    let now = unix_time();
+    let cached = TokenStore::new(&config.token_cache_dir, &config.token_cache_key)
+        .load::<CachedToken>(user)
+        .unwrap_or_else(|err| {
+            warn!("{}", err);
+            None
+        })
+        // The refresh token shows the login can be renewed once the provider is back.
+        .filter(|cached| {
+            cached.verified_at + offline_login > now
+                && cached.token.refresh_token.is_some()
+                && claims::matches_user(config, &cached.token, &cached.claims, user)
+        });
+    let Some(cached) = cached else {
+        return err.report();
+    };
+    if let Err(verify_err) = verify_offline(config, &cached) {
+        warn!("Not letting {} in offline: {}", user, verify_err);
+        return err.report();
+    }
+    warn!(
+        "{}; letting {} in on the login verified {} seconds ago",
+        err,
+        user,
+        now - cached.verified_at
+    );
+    pam_try!(claims::authorize(config, &cached.claims).map_err(Error::report));
+    pam_try!(pamh.set_data(TOKEN_DATA, Box::new(cached)));
+    PamResultCode::PAM_SUCCESS
+}
+
+/// Verifies the id_token of `cached` with the cached JWKS of the provider that issued it.
+fn verify_offline(config: &Config, cached: &CachedToken) -> Result<()> {
+    let config = Config {
+        provider: cached.provider.clone(),
+        ..config.clone()
+    };
+    let config = config
+        .provider_configs()?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::Config("no provider is configured".to_string()))?;
+    verify_cached_id_token(&config, &cached.token).map(drop)
+}
+
+/// Store of the grace period logins from the tty and remote host of this transaction.
+fn grace_store(pamh: &PamHandle, config: &Config) -> TokenStore {
+    let tty = pamh.get_item::<Tty>().ok().flatten();
+    let rhost = pamh.get_item::<RHost>().ok().flatten();
+    let origin = [tty.map(|tty| tty.0), rhost.map(|rhost| rhost.0)]
+        .map(|item| item.map_or(&b""[..], CStr::to_bytes))
+        .join(&0);
+    let hash: String = digest(&SHA256, &origin).as_ref()[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    TokenStore::new(&config.token_cache_dir, &config.token_cache_key)
+        .with_file(&format!("grace-{}", hash))
+}
+
+/// The login of `user` from the same origin within the last `grace_period` seconds.
+fn grace_login(
+    pamh: &PamHandle,
+    config: &Config,
+    user: &str,
+    grace_period: u64,
+) -> Option<CachedToken> {
+    let grace_login = match grace_store(pamh, config).load::<GraceLogin>(user) {
+        Ok(grace_login) => grace_login?,
+        Err(err) => {
+            warn!("{}", err);
+            return None;
+        }
+    };
+    let cached = grace_login.cached;
+    (grace_login.authenticated_at + grace_period > unix_time()
+        && cached.provider == config.provider
+        && claims::matches_user(config, &cached.token, &cached.claims, user))
+    .then_some(cached)
+}
+
+async fn device_flow(
+    conv: &Conv<'_>,
+    config: &Config,
+    context: &PamContext,
+    client: &DeviceFlowClient,
+    user: Option<&str>,
+) -> Result<TokenResponse> {
+    let result = client.authorize_device().await?;
+
+    info!(
+        "Device authorization started: user_code={}",
+        result.user_code
+    );
+
+    show_device_auth(conv, config, &result)?;
+    let notifiers = match user {
+        Some(_) => notify::from_config(config, &HttpConfig::from_config(config)?),
+        None => Vec::new(),
+    };
+    // Poll while the user is still at the prompt, and while the notifications are sent, so that
+    // the device code lifetime is not wasted.
+    let poller = tokio::spawn({
+        let client = client.clone();
+        let result = result.clone();
+        async move { client.poll_token(&result).await }
+    });
+    let signals = Signals::from_config(config, context, user);
+    if let Some(signals) = &signals {
+        signals.started(&result).await;
+    }
+    if let Some(user) = user {
+        let notification = Notification::new(user, &result);
+        for notifier in notifiers {
+            if let Err(err) = notifier.notify(&notification).await {
+                warn!("{}", err);
+            }
+        }
+    }
+    let token = wait_for_poller(conv, config, &result, poller)
+        .await
+        .and_then(|token| confirm_user_code(conv, config, &result).map(|_| token));
+    if let Some(signals) = &signals {
+        signals
+            .ended(&result, token.as_ref().err().map(Error::class))
+            .await;
+    }
+    token
+}
+
+/// Shows the user where to log in for the device authorization `result`.
+pub(crate) fn show_device_auth(
+    conv: &Conv<'_>,
+    config: &Config,
+    result: &DeviceAuthResponse,
+) -> Result<()> {
+    let messages = config.messages()?;
+    let qr_options = config.qr_options();
+    let qr_options = (!config.no_qr).then_some(&qr_options);
+    let message = match &config.message_template {
+        Some(template) => result.render(template, qr_options),
+        None => format!(
+            "\n\n{}",
+            result
+                .instructions(&messages, qr_options)
+                .ok_or(Error::Pam(PamResultCode::PAM_BUF_ERR))?
+        ),
+    };
+    conv.send(PAM_TEXT_INFO, &message)?;
+    Ok(())
+}
+
+/// With `confirm_user_code`, asks for the user code of the approved authorization `result` and
+/// fails unless it is the one issued for this session.
+pub(crate) fn confirm_user_code(
+    conv: &Conv<'_>,
+    config: &Config,
+    result: &DeviceAuthResponse,
+) -> Result<()> {
+    if !config.confirm_user_code {
+        return Ok(());
+    }
+    let messages = config.messages()?;
+    let answer = conv.send(PAM_PROMPT_ECHO_ON, &messages.confirm_code)?;
+    // Codes are usually shown grouped, such as `ABCD-EFGH`, and typed in any case.
+    let normalize = |code: &[u8]| -> Vec<u8> {
+        code.iter()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(u8::to_ascii_uppercase)
+            .collect()
+    };
+    match answer {
+        Some(answer) if normalize(answer.to_bytes()) == normalize(result.user_code.as_bytes()) => {
+            Ok(())
+        }
+        Some(_) => Err(Error::Token(format!(
+            "the code entered is not the user code {} of the login",
+            result.user_code
+        ))),
+        None => Err(Error::Cancelled),
+    }
+}
+
+/// Waits for the `poller` of the device authorization `result` behind the prompt and the
+/// progress messages, until the deadline of the authorization.
+pub(crate) async fn wait_for_poller<T>(
+    conv: &Conv<'_>,
+    config: &Config,
+    result: &DeviceAuthResponse,
+    mut poller: JoinHandle<Result<T>>,
+) -> Result<T> {
+    let messages = config.messages()?;
+    let prompt = result.render(config.prompt.as_deref().unwrap_or(&messages.prompt), None);
+    let progress = result.render(&messages.progress, None);
+    let expires_in = Duration::from_secs(result.expires_in.try_into().unwrap());
+    let deadline = Instant::now()
+        + config
+            .max_auth_time
+            .map_or(expires_in, |max| expires_in.min(Duration::from_secs(max)));
+
+    // The conversation cannot be interrupted, so the token is picked up once the prompt
+    // returns. Progress is reported only while no prompt is outstanding.
+    if !config.no_prompt {
+        match conv.send(PAM_PROMPT_ECHO_OFF, &prompt) {
+            // A missing response is how conversations report end of input.
+            Ok(Some(answer))
+                if !answer
+                    .to_bytes()
+                    .trim_ascii()
+                    .eq_ignore_ascii_case(b"cancel") => {}
+            Ok(_) => {
+                info!("Device authorization cancelled by the user");
+                poller.abort();
+                return Err(Error::Cancelled);
+            }
+            Err(err) => {
+                poller.abort();
+                return Err(Error::from(err));
+            }
+        }
+    }
+    let progress_interval = Duration::from_secs(config.progress_interval.max(1));
+    let mut progress_ticks =
+        time::interval_at(Instant::now() + progress_interval, progress_interval);
+    loop {
+        tokio::select! {
+            biased;
+            polled = &mut poller => {
+                return polled.unwrap_or_else(|err| {
+                    error!("Token polling failed: {}", err);
+                    Err(Error::Pam(PamResultCode::PAM_SYSTEM_ERR))
+                });
+            }
+            // A request to the provider that hangs must not hold the login past the deadline.
+            _ = time::sleep_until(deadline + POLL_DEADLINE_GRACE) => {
+                poller.abort();
+                return Err(Error::idp(
+                    "expired_token".to_string(),
+                    Some("the authorization did not complete in time".to_string()),
+                ));
+            }
+            _ = progress_ticks.tick(), if config.progress_interval > 0 => {
+                let remaining = deadline.saturating_duration_since(Instant::now()).as_secs();
+                let message = template::render(
+                    &progress,
+                    &[(
+                        "remaining",
+                        &format!("{}:{:02}", remaining / 60, remaining % 60),
+                    )],
+                );
+                if let Err(err) = conv.send(PAM_TEXT_INFO, &message) {
+                    poller.abort();
+                    return Err(Error::from(err));
+                }
+            }
+        }
+    }
+}
+
+async fn authorization_code_flow(
+    conv: &Conv<'_>,
+    config: &Config,
+    client: &DeviceFlowClient,
+) -> Result<TokenResponse> {
+    let request = client.authorize_browser(config.redirect_port)?;
+    info!("Authorization code flow started");
+    let messages = config.messages()?;
+    conv.send(
+        PAM_TEXT_INFO,
+        &template::render(
+            &messages.login_browser,
+            &[("url", request.authorize_url.as_str())],
+        ),
+    )?;
+    let timeout = config
+        .max_auth_time
+        .map_or(AUTHORIZATION_CODE_TIMEOUT, Duration::from_secs);
+    client.complete_authorization(&request, timeout).await
+}
+
+async fn ciba_flow(
+    conv: &Conv<'_>,
+    config: &Config,
+    client: &DeviceFlowClient,
+    user: Option<&str>,
+) -> Result<TokenResponse> {
+    // The provider has to know whom to ask, so the user must be known up front.
+    let user = user.ok_or(Error::Pam(PamResultCode::PAM_USER_UNKNOWN))?;
+    let login_hint = template::render(&config.login_hint, &[("user", user)]);
+    let binding_message = config
+        .binding_message
+        .as_deref()
+        .map(|message| template::render(message, &[("user", user)]));
+    let auth = client
+        .authorize_backchannel(&login_hint, binding_message.as_deref())
+        .await?;
+    info!(
+        "Backchannel authentication started: login_hint={}",
+        login_hint
+    );
+    let messages = config.messages()?;
+    conv.send(
+        PAM_TEXT_INFO,
+        &template::render(
+            &messages.login_ciba,
+            &[(
+                "binding_message",
+                binding_message.as_deref().unwrap_or_default(),
+            )],
+        ),
+    )?;
+    client.poll_backchannel_token(&auth).await
+}
+
+/// Has the `ssh_ca` sign the public key of `user` for the login `token` and installs the
+/// certificate next to the key.
+async fn issue_ssh_cert(config: &Config, user: &str, token: &TokenResponse) -> Result<()> {
+    let Some(ca) = SshCa::from_config(config)? else {
+        return Ok(());
+    };
+    let id_token = token
+        .id_token
+        .as_deref()
+        .ok_or_else(|| Error::Token("token response has no id_token".to_string()))?;
+    let (path, public_key) = session::read_public_key(user, &config.ssh_public_key)
+        .map_err(|err| Error::SshCa(format!("failed to read the public key: {}", err)))?;
+    let cert = ca.sign(id_token, &public_key, user).await?;
+    session::install_ssh_cert(user, &path, &cert)
+        .map_err(|err| Error::SshCa(format!("failed to install {}: {}", path.display(), err)))?;
+    info!("Installed SSH certificate {}", path.display());
+    Ok(())
+}
+
+/// Applies `exchange_audience` and `exchange_scope` to the verified `token`.
+pub(crate) async fn exchange_token(
+    config: &Config,
+    client: &DeviceFlowClient,
+    token: TokenResponse,
+) -> Result<TokenResponse> {
+    if config.exchange_audience.is_none() && config.exchange_scope.is_empty() {
+        return Ok(token);
+    }
+    client
+        .exchange_token(
+            &token,
+            config.exchange_audience.as_deref(),
+            &config.exchange_scope,
+        )
+        .await
+}
+
+async fn refresh_cached_token(
+    token_store: &TokenStore,
+    user: &str,
+    config: &Config,
+    client: &DeviceFlowClient,
+) -> Option<TokenResponse> {
+    let refresh_token = match token_store.load::<CachedToken>(user) {
+        Ok(cached) => cached
+            .filter(|cached| cached.provider == config.provider)
+            .filter(|cached| claims::check_auth_time(config, &cached.claims).is_ok())
+            .and_then(|cached| cached.token.refresh_token)?,
+        Err(err) => {
+            warn!("{}", err);
+            return None;
+        }
+    };
+    match client.refresh_token(&refresh_token).await {
+        Ok(token) => Some(token),
+        Err(err @ Error::IdP { .. }) => {
+            warn!("Token refresh error: {}", err);
+            if matches!(&err, Error::IdP { error, .. } if error == "invalid_grant") {
+                if let Err(err) = token_store.remove(user) {
+                    warn!("{}", err);
+                }
+            }
+            None
+        }
+        Err(err) => {
+            error!("Token refresh error: {}", err);
+            None
+        }
+    }
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
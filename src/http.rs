@@ -0,0 +1,447 @@
+//! HTTP transport for the requests to the identity provider, over reqwest or, with the `ureq`
+//! feature, the smaller blocking ureq client.
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    redact,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{
+    header::{HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    HeaderMap, Method, StatusCode,
+};
+use log::{debug, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    fmt, fs,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    sync::OnceLock,
+    time::Duration,
+};
+
+#[cfg(not(any(feature = "reqwest", feature = "ureq")))]
+compile_error!("either the `reqwest` or the `ureq` feature is required");
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("either the `native-tls` or the `rustls` feature is required");
+
+#[cfg_attr(not(feature = "ureq"), path = "http/reqwest.rs")]
+#[cfg_attr(feature = "ureq", path = "http/ureq.rs")]
+mod backend;
+
+use backend::{Certificate, Client, Identity, Proxy};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+
+const DEFAULT_RETRIES: u32 = 2;
+
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Delay before the first retry; it doubles with every further attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// Settings of the HTTP client used for every request to the identity provider, discovery
+/// included. The client is built on the first request and shared by the clones made after it,
+/// so the requests of a login reuse its connections; changing a setting drops it.
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    ca_certs: Vec<Certificate>,
+    identity: Option<Identity>,
+    proxy: Option<Proxy>,
+    timeout: Duration,
+    connect_timeout: Option<Duration>,
+    retries: u32,
+    user_agent: HeaderValue,
+    headers: HeaderMap,
+    client: OnceLock<Client>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            ca_certs: Vec::new(),
+            identity: None,
+            proxy: None,
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: None,
+            retries: DEFAULT_RETRIES,
+            user_agent: HeaderValue::from_static(DEFAULT_USER_AGENT),
+            headers: HeaderMap::new(),
+            client: OnceLock::new(),
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Builds the settings from the TLS, proxy, timeout and User-Agent options. The extra
+    /// `http_headers` are only for the identity provider and left to the caller.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut http = Self::default()
+            .with_timeout(Duration::from_secs(config.http_timeout))
+            .with_retries(config.http_retries);
+        if let Some(connect_timeout) = config.connect_timeout {
+            http = http.with_connect_timeout(Duration::from_secs(connect_timeout));
+        }
+        if let Some(path) = &config.ca_cert {
+            http = http.with_ca_certs(&read(path)?)?;
+        }
+        if let (Some(cert), Some(key)) = (&config.tls_client_cert, &config.tls_client_key) {
+            http = http.with_client_identity(&read(cert)?, &read(key)?)?;
+        }
+        if let Some(proxy) = &config.proxy {
+            http = http.with_proxy(proxy)?;
+        }
+        if let Some(user_agent) = &config.user_agent {
+            http = http.with_user_agent(user_agent)?;
+        }
+        Ok(http)
+    }
+
+    /// Trusts the certificates of the PEM bundle `pem` in addition to the system trust store.
+    pub fn with_ca_certs(mut self, pem: &[u8]) -> Result<Self> {
+        self.client.take();
+        let certs = backend::ca_certs(pem)
+            .map_err(|err| Error::Config(format!("invalid CA certificate: {}", err)))?;
+        if certs.is_empty() {
+            return Err(Error::Config(
+                "CA certificate bundle has no certificates".to_string(),
+            ));
+        }
+        self.ca_certs.extend(certs);
+        Ok(self)
+    }
+
+    /// Presents the PEM certificate chain `cert` with the PKCS#8 PEM private key `key` to servers
+    /// that request a client certificate, for mutual TLS (RFC 8705).
+    pub fn with_client_identity(mut self, cert: &[u8], key: &[u8]) -> Result<Self> {
+        self.client.take();
+        self.identity =
+            Some(backend::identity(cert, key).map_err(|err| {
+                Error::Config(format!("invalid TLS client certificate: {}", err))
+            })?);
+        Ok(self)
+    }
+
+    /// Sends every request through the `http://`, `https://`, `socks5://` or `socks5h://` proxy
+    /// at `url`, which may carry percent-encoded `user:password@` credentials. Without it the
+    /// `HTTPS_PROXY` and related environment variables apply.
+    pub fn with_proxy(mut self, url: &str) -> Result<Self> {
+        self.client.take();
+        self.proxy = Some(
+            backend::proxy(url).map_err(|err| Error::Config(format!("invalid proxy: {}", err)))?,
+        );
+        Ok(self)
+    }
+
+    /// Limit for each request from connecting until the response body is read, 15 seconds by
+    /// default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client.take();
+        self.timeout = timeout;
+        self
+    }
+
+    /// Limit for establishing the connection, bounded only by the request timeout by default.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.client.take();
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Number of times a request is repeated after a connection error, a timeout or a 5xx
+    /// response, 2 by default. The delay between attempts grows exponentially with random jitter.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// `User-Agent` of the requests, `pam-oauth2-df-rs/<version>` by default.
+    pub fn with_user_agent(mut self, user_agent: &str) -> Result<Self> {
+        self.client.take();
+        self.user_agent = HeaderValue::from_str(user_agent)
+            .map_err(|err| Error::Config(format!("invalid user_agent: {}", err)))?;
+        Ok(self)
+    }
+
+    /// Adds the `headers` to every request, e.g. the API key of a gateway in front of the
+    /// identity provider. Headers that a request sets itself, such as `Authorization`, win.
+    pub fn with_headers<'a, I>(mut self, headers: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        self.client.take();
+        for (name, value) in headers {
+            self.headers.append(
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|err| invalid_header(&name, &err))?,
+                HeaderValue::from_str(value).map_err(|err| invalid_header(&name, &err))?,
+            );
+        }
+        Ok(self)
+    }
+
+    /// The client of these settings, built on first use.
+    fn client(&self) -> Result<&Client> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let client = backend::build(self)?;
+        Ok(self.client.get_or_init(|| client))
+    }
+
+    /// POSTs the form `body` with the extra `headers` and returns the response status, headers
+    /// and body.
+    pub(crate) async fn post_form<S: Into<String>>(
+        &self,
+        url: &str,
+        body: S,
+        basic_auth: Option<(&str, &str)>,
+        headers: &[(&str, &str)],
+    ) -> Result<(StatusCode, HeaderMap, String)> {
+        let request = form_request(url, body.into(), basic_auth, headers)?;
+        self.send_with_headers(request).await
+    }
+
+    /// [`post_form`](Self::post_form) with the body and extra headers that `form` builds, anew
+    /// for every retry: client assertions and DPoP proofs may each be used only once.
+    pub(crate) async fn post_form_with<F, Fut>(
+        &self,
+        url: &str,
+        basic_auth: Option<(&str, &str)>,
+        mut form: F,
+    ) -> Result<(StatusCode, HeaderMap, String)>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(String, Vec<(&'static str, String)>)>>,
+    {
+        let client = self.client()?;
+        let mut attempt = 0;
+        loop {
+            let (body, headers) = form().await?;
+            let headers: Vec<_> = headers
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect();
+            let request = form_request(url, body, basic_auth, &headers)?;
+            let result = backend::send(client, &request).await;
+            if !self.retry(&mut attempt, url, &result).await {
+                return response(url, result);
+            }
+        }
+    }
+
+    /// POSTs `body` as JSON with the extra `headers` and returns the response status and body.
+    pub(crate) async fn post_json<T: Serialize>(
+        &self,
+        url: &str,
+        body: &T,
+        headers: &[(&str, &str)],
+    ) -> Result<(StatusCode, String)> {
+        let body = serde_json::to_string(body)?;
+        debug!("POST {}", url);
+        self.send(Request {
+            method: Method::POST,
+            url,
+            headers: header_map(
+                &[
+                    (CONTENT_TYPE, "application/json"),
+                    (ACCEPT, "application/json"),
+                ],
+                headers,
+            )?,
+            body: Some(body),
+        })
+        .await
+    }
+
+    /// PUTs `body` as `content_type` and returns the response status and body.
+    #[cfg(feature = "pam")]
+    pub(crate) async fn put(
+        &self,
+        url: &str,
+        content_type: &str,
+        body: String,
+    ) -> Result<(StatusCode, String)> {
+        debug!("PUT {}", url);
+        self.send(Request {
+            method: Method::PUT,
+            url,
+            headers: header_map(&[], &[("Content-Type", content_type)])?,
+            body: Some(body),
+        })
+        .await
+    }
+
+    /// GETs `url` and parses the JSON response, whatever its status.
+    pub(crate) async fn get_json<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        bearer: Option<&str>,
+    ) -> Result<T> {
+        let (_, text) = self.get(url, bearer).await?;
+        Ok(serde_json::from_str(text.as_str())?)
+    }
+
+    pub(crate) async fn get(
+        &self,
+        url: &str,
+        bearer: Option<&str>,
+    ) -> Result<(StatusCode, String)> {
+        let authorization = bearer.map(|bearer| format!("Bearer {}", bearer));
+        match &authorization {
+            Some(authorization) => {
+                self.get_with_headers(url, &[("Authorization", authorization)])
+                    .await
+            }
+            None => self.get_with_headers(url, &[]).await,
+        }
+    }
+
+    /// GETs `url` with the extra `headers` and returns the response status and body.
+    pub(crate) async fn get_with_headers(
+        &self,
+        url: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(StatusCode, String)> {
+        debug!("GET {}", url);
+        self.send(Request {
+            method: Method::GET,
+            url,
+            headers: header_map(&[(ACCEPT, "application/json")], headers)?,
+            body: None,
+        })
+        .await
+    }
+
+    /// Sends `request`, retrying transient failures.
+    async fn send(&self, request: Request<'_>) -> Result<(StatusCode, String)> {
+        let (status, _, text) = self.send_with_headers(request).await?;
+        Ok((status, text))
+    }
+
+    /// [`send`](Self::send), also returning the response headers.
+    async fn send_with_headers(
+        &self,
+        request: Request<'_>,
+    ) -> Result<(StatusCode, HeaderMap, String)> {
+        let client = self.client()?;
+        let mut attempt = 0;
+        loop {
+            let result = backend::send(client, &request).await;
+            if !self.retry(&mut attempt, request.url, &result).await {
+                return response(request.url, result);
+            }
+        }
+    }
+
+    /// Whether to send the request to `url` again after `result`, once the backoff for the
+    /// `attempt` has passed.
+    async fn retry(&self, attempt: &mut u32, url: &str, result: &SendResult) -> bool {
+        let retry = match result {
+            Ok((status, _, _)) => status.is_server_error(),
+            Err(err) => err.transient,
+        };
+        if !retry || *attempt >= self.retries {
+            return false;
+        }
+        match result {
+            Ok((status, _, _)) => warn!("{} {}, retrying", status, url),
+            Err(err) => warn!("{}, retrying", err.message),
+        }
+        tokio::time::sleep(backoff(*attempt)).await;
+        *attempt += 1;
+        true
+    }
+}
+
+/// The form POST of `body` to `url`.
+fn form_request<'a>(
+    url: &'a str,
+    body: String,
+    basic_auth: Option<(&str, &str)>,
+    headers: &[(&str, &str)],
+) -> Result<Request<'a>> {
+    debug!("POST {} {}", url, redact::redact_form(&body));
+    let mut headers = header_map(
+        &[
+            (CONTENT_TYPE, "application/x-www-form-urlencoded"),
+            (ACCEPT, "application/json"),
+        ],
+        headers,
+    )?;
+    if let Some((username, password)) = basic_auth {
+        let credentials = STANDARD.encode(format!("{}:{}", username, password));
+        let mut value = HeaderValue::try_from(format!("Basic {}", credentials))
+            .map_err(|err| Error::Config(format!("invalid client credentials: {}", err)))?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    Ok(Request {
+        method: Method::POST,
+        url,
+        headers,
+        body: Some(body),
+    })
+}
+
+/// The final `result` of a request to `url`.
+fn response(url: &str, result: SendResult) -> Result<(StatusCode, HeaderMap, String)> {
+    let (status, headers, text) = result.map_err(|err| Error::Network(err.message))?;
+    debug!("{} {} {}", status, url, redact::redact_json(&text));
+    Ok((status, headers, text))
+}
+
+/// A request as the backends send it.
+struct Request<'a> {
+    method: Method,
+    url: &'a str,
+    /// Set on top of the headers of [`HttpConfig::with_headers`].
+    headers: HeaderMap,
+    body: Option<String>,
+}
+
+/// A request that got no response, and whether trying again may help: the connection failed
+/// or timed out.
+struct SendError {
+    message: String,
+    transient: bool,
+}
+
+type SendResult = std::result::Result<(StatusCode, HeaderMap, String), SendError>;
+
+/// The `defaults` of a request followed by its extra `headers`.
+fn header_map(defaults: &[(HeaderName, &str)], headers: &[(&str, &str)]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in defaults {
+        map.insert(
+            name,
+            HeaderValue::from_str(value).map_err(|err| invalid_header(name, &err))?,
+        );
+    }
+    for (name, value) in headers {
+        map.append(
+            HeaderName::from_bytes(name.as_bytes()).map_err(|err| invalid_header(name, &err))?,
+            HeaderValue::from_str(value).map_err(|err| invalid_header(name, &err))?,
+        );
+    }
+    Ok(map)
+}
+
+fn invalid_header(name: &dyn fmt::Display, err: &dyn fmt::Display) -> Error {
+    Error::Config(format!("invalid header {}: {}", name, err))
+}
+
+/// Exponential backoff with full jitter: a random delay up to `RETRY_BASE_DELAY * 2^attempt`.
+fn backoff(attempt: u32) -> Duration {
+    let ceiling = RETRY_BASE_DELAY * 2u32.saturating_pow(attempt.min(8));
+    // Every `RandomState` is seeded randomly, which is enough for jitter.
+    let random = RandomState::new().build_hasher().finish();
+    ceiling.mul_f64((random >> 11) as f64 / (1u64 << 53) as f64)
+}
+
+fn read(path: &str) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|err| Error::Config(format!("failed to read {}: {}", path, err)))
+}
@@ -0,0 +1,74 @@
+//! reqwest backend, the default.
+
+use super::{HttpConfig, Request, SendError};
+use crate::error::{Error, Result};
+use http::{HeaderMap, StatusCode};
+
+pub(super) use reqwest::{Certificate, Client, Identity, Proxy};
+
+pub(super) fn ca_certs(pem: &[u8]) -> reqwest::Result<Vec<Certificate>> {
+    Certificate::from_pem_bundle(pem)
+}
+
+#[cfg(not(feature = "rustls"))]
+pub(super) fn identity(cert: &[u8], key: &[u8]) -> reqwest::Result<Identity> {
+    Identity::from_pkcs8_pem(cert, key)
+}
+
+/// rustls takes the key and the chain in one PEM.
+#[cfg(feature = "rustls")]
+pub(super) fn identity(cert: &[u8], key: &[u8]) -> reqwest::Result<Identity> {
+    Identity::from_pem(&[key, b"\n", cert].concat())
+}
+
+pub(super) fn proxy(url: &str) -> reqwest::Result<Proxy> {
+    Proxy::all(url)
+}
+
+pub(super) fn build(http: &HttpConfig) -> Result<Client> {
+    let mut builder = Client::builder()
+        .timeout(http.timeout)
+        .user_agent(http.user_agent.clone())
+        .default_headers(http.headers.clone());
+    if let Some(connect_timeout) = http.connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+    #[cfg(feature = "rustls")]
+    {
+        builder = builder.use_rustls_tls();
+    }
+    for cert in &http.ca_certs {
+        builder = builder.add_root_certificate(cert.clone());
+    }
+    if let Some(identity) = &http.identity {
+        builder = builder.identity(identity.clone());
+    }
+    if let Some(proxy) = &http.proxy {
+        builder = builder.proxy(proxy.clone());
+    }
+    builder
+        .build()
+        .map_err(|err| Error::Network(err.to_string()))
+}
+
+pub(super) async fn send(
+    client: &Client,
+    request: &Request<'_>,
+) -> std::result::Result<(StatusCode, HeaderMap, String), SendError> {
+    let mut builder = client
+        .request(request.method.clone(), request.url)
+        .headers(request.headers.clone());
+    if let Some(body) = &request.body {
+        builder = builder.body(body.clone());
+    }
+    let send = async {
+        let response = builder.send().await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        Ok((status, headers, response.text().await?))
+    };
+    send.await.map_err(|err: reqwest::Error| SendError {
+        transient: err.is_connect() || err.is_timeout(),
+        message: err.to_string(),
+    })
+}
@@ -0,0 +1,231 @@
+//! ureq backend, from the `ureq` feature. ureq is blocking, so every request runs on a thread
+//! of the blocking pool, which also keeps it going until it completes or times out when the
+//! caller gives up on it.
+
+use super::{HttpConfig, Request, SendError};
+use crate::error::{Error, Result};
+use http::{header::HeaderName, HeaderMap, HeaderValue, StatusCode};
+use std::{fmt, io, sync::Arc};
+#[cfg(feature = "rustls")]
+use ureq::rustls::{
+    self,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+};
+use ureq::{Agent, AgentBuilder, ErrorKind};
+
+pub(super) use ureq::Proxy;
+
+#[cfg(not(feature = "rustls"))]
+#[derive(Clone)]
+pub(super) struct Certificate(native_tls::Certificate);
+
+#[cfg(feature = "rustls")]
+#[derive(Clone)]
+pub(super) struct Certificate(CertificateDer<'static>);
+
+impl fmt::Debug for Certificate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Certificate")
+    }
+}
+
+#[cfg(not(feature = "rustls"))]
+#[derive(Clone)]
+pub(super) struct Identity(native_tls::Identity);
+
+/// The certificate chain with its key, which rustls only takes as a whole.
+#[cfg(feature = "rustls")]
+pub(super) struct Identity(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+#[cfg(feature = "rustls")]
+impl Clone for Identity {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), self.1.clone_key())
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Identity")
+    }
+}
+
+/// The agent, which keeps the connections, with the headers that ureq has no default for.
+#[derive(Debug, Clone)]
+pub(super) struct Client {
+    agent: Agent,
+    headers: HeaderMap,
+}
+
+#[cfg(not(feature = "rustls"))]
+pub(super) fn ca_certs(pem: &[u8]) -> std::result::Result<Vec<Certificate>, String> {
+    let pem = std::str::from_utf8(pem).map_err(|err| err.to_string())?;
+    const END: &str = "-----END CERTIFICATE-----";
+    pem.split_inclusive(END)
+        .filter(|block| block.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|block| {
+            native_tls::Certificate::from_pem(block.trim().as_bytes())
+                .map(Certificate)
+                .map_err(|err| err.to_string())
+        })
+        .collect()
+}
+
+#[cfg(feature = "rustls")]
+pub(super) fn ca_certs(pem: &[u8]) -> std::result::Result<Vec<Certificate>, String> {
+    CertificateDer::pem_slice_iter(pem)
+        .map(|cert| cert.map(Certificate).map_err(|err| err.to_string()))
+        .collect()
+}
+
+#[cfg(not(feature = "rustls"))]
+pub(super) fn identity(cert: &[u8], key: &[u8]) -> native_tls::Result<Identity> {
+    native_tls::Identity::from_pkcs8(cert, key).map(Identity)
+}
+
+#[cfg(feature = "rustls")]
+pub(super) fn identity(cert: &[u8], key: &[u8]) -> std::result::Result<Identity, String> {
+    let chain = CertificateDer::pem_slice_iter(cert)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|err| err.to_string())?;
+    if chain.is_empty() {
+        return Err("no certificate".to_string());
+    }
+    let key = PrivateKeyDer::from_pem_slice(key).map_err(|err| err.to_string())?;
+    Ok(Identity(chain, key))
+}
+
+pub(super) fn proxy(url: &str) -> std::result::Result<Proxy, String> {
+    // ureq always leaves the name resolution to a SOCKS5 proxy.
+    match url.strip_prefix("socks5h://") {
+        Some(rest) => Proxy::new(format!("socks5://{}", rest)),
+        None => Proxy::new(url),
+    }
+    .map_err(|err| err.to_string())
+}
+
+pub(super) fn build(http: &HttpConfig) -> Result<Client> {
+    let user_agent = http
+        .user_agent
+        .to_str()
+        .map_err(|err| Error::Config(format!("invalid user_agent: {}", err)))?;
+    let mut builder = AgentBuilder::new()
+        .timeout(http.timeout)
+        .user_agent(user_agent)
+        .try_proxy_from_env(true);
+    builder = tls(builder, http)?;
+    if let Some(connect_timeout) = http.connect_timeout {
+        builder = builder.timeout_connect(connect_timeout);
+    }
+    if let Some(proxy) = &http.proxy {
+        builder = builder.proxy(proxy.clone());
+    }
+    Ok(Client {
+        agent: builder.build(),
+        headers: http.headers.clone(),
+    })
+}
+
+#[cfg(not(feature = "rustls"))]
+fn tls(builder: AgentBuilder, http: &HttpConfig) -> Result<AgentBuilder> {
+    let mut tls = native_tls::TlsConnector::builder();
+    for cert in &http.ca_certs {
+        tls.add_root_certificate(cert.0.clone());
+    }
+    if let Some(identity) = &http.identity {
+        tls.identity(identity.0.clone());
+    }
+    let tls = tls.build().map_err(|err| Error::Network(err.to_string()))?;
+    Ok(builder.tls_connector(Arc::new(tls)))
+}
+
+/// rustls with the system trust store, as native-tls has it, and the `ca_cert` certificates.
+#[cfg(feature = "rustls")]
+fn tls(builder: AgentBuilder, http: &HttpConfig) -> Result<AgentBuilder> {
+    let failed = |err: &dyn fmt::Display| Error::Network(err.to_string());
+    let mut roots = rustls::RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs();
+    for err in native.errors {
+        log::warn!("Failed to load the system certificates: {}", err);
+    }
+    // Certificates that rustls cannot parse are skipped, like OpenSSL does.
+    roots.add_parsable_certificates(native.certs);
+    for cert in &http.ca_certs {
+        roots.add(cert.0.clone()).map_err(|err| failed(&err))?;
+    }
+    let tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|err| failed(&err))?
+    .with_root_certificates(roots);
+    let tls = match &http.identity {
+        Some(Identity(chain, key)) => tls
+            .with_client_auth_cert(chain.clone(), key.clone_key())
+            .map_err(|err| failed(&err))?,
+        None => tls.with_no_client_auth(),
+    };
+    Ok(builder.tls_config(Arc::new(tls)))
+}
+
+pub(super) async fn send(
+    client: &Client,
+    request: &Request<'_>,
+) -> std::result::Result<(StatusCode, HeaderMap, String), SendError> {
+    let mut call = client.agent.request(request.method.as_str(), request.url);
+    for (name, value) in client.headers.iter().chain(&request.headers) {
+        let value = value.to_str().map_err(|err| SendError {
+            message: format!("invalid header {}: {}", name, err),
+            transient: false,
+        })?;
+        call = call.set(name.as_str(), value);
+    }
+    let body = request.body.clone();
+    let response = tokio::task::spawn_blocking(move || {
+        let response = match body {
+            Some(body) => call.send_string(&body),
+            None => call.call(),
+        };
+        match response {
+            // Statuses are for the caller to handle, like with reqwest.
+            Ok(response) | Err(ureq::Error::Status(_, response)) => read(response),
+            Err(ureq::Error::Transport(err)) => Err(SendError {
+                transient: matches!(
+                    err.kind(),
+                    ErrorKind::Dns | ErrorKind::ConnectionFailed | ErrorKind::Io
+                ),
+                message: err.to_string(),
+            }),
+        }
+    });
+    response.await.map_err(|err| SendError {
+        message: err.to_string(),
+        transient: false,
+    })?
+}
+
+fn read(
+    response: ureq::Response,
+) -> std::result::Result<(StatusCode, HeaderMap, String), SendError> {
+    let failed = |err: &dyn fmt::Display| SendError {
+        message: format!("invalid response from {}: {}", response.get_url(), err),
+        transient: false,
+    };
+    let status = StatusCode::from_u16(response.status()).map_err(|err| failed(&err))?;
+    let mut headers = HeaderMap::new();
+    for name in response.headers_names() {
+        let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+            continue;
+        };
+        for value in response.all(name.as_str()) {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.append(&name, value);
+            }
+        }
+    }
+    let text = response.into_string().map_err(|err: io::Error| SendError {
+        transient: err.kind() == io::ErrorKind::TimedOut,
+        message: err.to_string(),
+    })?;
+    Ok((status, headers, text))
+}
@@ -0,0 +1,172 @@
+//! Conversation text per locale.
+
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use std::{env, fs, io, path::Path};
+
+/// Catalogs compiled into the module; files in the locale directory take precedence.
+const BUILTIN: &[(&str, &str)] = &[("ja", include_str!("../locale/ja.toml"))];
+
+/// Templates of the text shown to the user, with the placeholders of
+/// [`DeviceAuthResponse::render`](crate::DeviceAuthResponse::render). A catalog file only needs
+/// the entries it translates; the others stay English.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Messages {
+    /// Instructions when the URI carries the user code.
+    pub login_qr: String,
+    /// Instructions without QR code, where the user code has to be entered.
+    pub login_code: String,
+    /// Instructions with QR code when the user code still has to be entered at the URI.
+    pub login_code_qr: String,
+    pub prompt: String,
+    /// Prompt of `confirm_user_code` once the login was approved.
+    pub confirm_code: String,
+    /// Instructions of `flow=authorization_code`.
+    pub login_browser: String,
+    /// Shown by `flow=ciba` while the user approves the request on their device, with the
+    /// `binding_message` as `{binding_message}`.
+    pub login_ciba: String,
+    /// Sent every `progress_interval` seconds while polling, with the time left as
+    /// `{remaining}`.
+    pub progress: String,
+    /// Answer of `passwd`, with the `password_url` as `{url}` and the PAM user as `{user}`.
+    pub password_change: String,
+    /// The failure reasons shown to the user, which never carry the details of the provider's
+    /// answer; those are only logged. The provider answered `access_denied`.
+    pub denied: String,
+    /// The user code expired before the login was approved.
+    pub expired: String,
+    /// The token is valid, but the user is not in the groups or roles the host requires.
+    pub not_allowed: String,
+    /// The token belongs to another user than the one logging in.
+    pub wrong_user: String,
+    /// `max_failures` was reached for the remote host.
+    pub too_many_failures: String,
+    /// The identity provider could not be reached or gave an invalid answer.
+    pub unavailable: String,
+    /// Any other failure, such as an invalid configuration.
+    pub failed: String,
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Self {
+            login_qr: "Please login at {url} or scan the QRCode below:\n\n{qr}".to_string(),
+            login_code: "Please login at {url} and enter the code\n\n    {user_code}\n".to_string(),
+            login_code_qr: "Please login at {url} and enter the code\n\n    {user_code}\n\n\
+                            or scan the QRCode below:\n\n{qr}"
+                .to_string(),
+            prompt: "Press Enter to continue, or type cancel to abort:".to_string(),
+            confirm_code: "Enter the code shown by your identity provider:".to_string(),
+            login_browser: "Please open {url} in a browser on this machine to login.".to_string(),
+            login_ciba: "Please approve the login request on your device. {binding_message}"
+                .to_string(),
+            progress: "Waiting for approval, {remaining} remaining...".to_string(),
+            password_change: "The password of {user} is managed by the identity provider. \
+                              Please change it at {url}."
+                .to_string(),
+            denied: "Access was denied by your identity provider.".to_string(),
+            expired: "The login code expired before it was approved.".to_string(),
+            not_allowed: "Your account is not allowed to log in to this host.".to_string(),
+            wrong_user: "You logged in with another account than the one of this user.".to_string(),
+            too_many_failures: "Too many failed logins. Please try again later.".to_string(),
+            unavailable: "The identity provider is unavailable. Please try again later."
+                .to_string(),
+            failed: "Login failed.".to_string(),
+        }
+    }
+}
+
+/// Why a login failed, as told to the user.
+#[cfg(feature = "pam")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reason {
+    Denied,
+    Expired,
+    NotAllowed,
+    WrongUser,
+    TooManyFailures,
+    Unavailable,
+    Failed,
+}
+
+#[cfg(feature = "pam")]
+impl Reason {
+    /// The reason of `err`, or `None` when the user needs no telling: they cancelled the login
+    /// or the conversation itself failed.
+    pub(crate) fn of(err: &Error) -> Option<Self> {
+        Some(match err {
+            Error::IdP { error, .. } if error == "access_denied" => Self::Denied,
+            Error::IdP { error, .. } if error == "expired_token" => Self::Expired,
+            Error::Authorization(_) => Self::NotAllowed,
+            Error::Network(_) | Error::Response(_) => Self::Unavailable,
+            Error::Cancelled | Error::Pam(_) => return None,
+            _ => Self::Failed,
+        })
+    }
+}
+
+impl Messages {
+    #[cfg(feature = "pam")]
+    pub(crate) fn reason(&self, reason: Reason) -> &str {
+        match reason {
+            Reason::Denied => &self.denied,
+            Reason::Expired => &self.expired,
+            Reason::NotAllowed => &self.not_allowed,
+            Reason::WrongUser => &self.wrong_user,
+            Reason::TooManyFailures => &self.too_many_failures,
+            Reason::Unavailable => &self.unavailable,
+            Reason::Failed => &self.failed,
+        }
+    }
+
+    /// Loads the catalog for `locale`, e.g. `ja_JP.UTF-8`, from `<dir>/ja_JP.toml` or
+    /// `<dir>/ja.toml`, falling back to the built-in catalogs and then to English.
+    pub fn load<P: AsRef<Path>>(dir: P, locale: &str) -> Result<Self> {
+        for lang in candidates(locale) {
+            let path = dir.as_ref().join(format!("{}.toml", lang));
+            match fs::read_to_string(&path) {
+                Ok(text) => return parse(&text, &path.display().to_string()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(Error::Config(format!(
+                        "failed to read {}: {}",
+                        path.display(),
+                        err
+                    )))
+                }
+            }
+            if let Some((_, text)) = BUILTIN.iter().find(|(name, _)| *name == lang) {
+                return parse(text, lang);
+            }
+        }
+        Ok(Self::default())
+    }
+}
+
+/// Locale of the process from `LC_ALL`, `LC_MESSAGES` or `LANG`.
+pub(crate) fn locale_from_env() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// `ja_JP.UTF-8@latin` → `ja_JP`, `ja`; nothing for the `C` and `POSIX` locales.
+fn candidates(locale: &str) -> Vec<&str> {
+    let locale = locale.split(['.', '@']).next().unwrap_or_default();
+    if locale.is_empty() || locale == "C" || locale == "POSIX" {
+        return Vec::new();
+    }
+    let mut candidates = vec![locale];
+    if let Some((lang, _)) = locale.split_once(['_', '-']) {
+        candidates.push(lang);
+    }
+    candidates
+}
+
+fn parse(text: &str, source: &str) -> Result<Messages> {
+    toml::from_str(text)
+        .map_err(|err| Error::Config(format!("failed to parse {}: {}", source, err)))
+}
@@ -0,0 +1,77 @@
+use crate::error::{Error, Result};
+use jsonwebtoken::{
+    decode, decode_header, get_current_timestamp, jwk::JwkSet, Algorithm, DecodingKey, Validation,
+};
+use serde_json::Value;
+
+/// Claim values an id_token must satisfy.
+pub(crate) struct Expected<'a> {
+    pub(crate) audience: &'a str,
+    /// Not checked when `None`.
+    pub(crate) issuer: Option<&'a str>,
+    /// Tolerance in seconds applied to `exp`, `nbf` and `iat`.
+    pub(crate) clock_skew: u64,
+}
+
+/// `kid` of the key that signed `id_token`.
+pub(crate) fn kid(id_token: &str) -> Result<Option<String>> {
+    Ok(decode_header(id_token)?.kid)
+}
+
+/// Whether `jwks` has the key [`verify`] would pick for `kid`.
+pub(crate) fn has_key(jwks: &JwkSet, kid: Option<&str>) -> bool {
+    match kid {
+        Some(kid) => jwks.find(kid).is_some(),
+        None => jwks.keys.len() == 1,
+    }
+}
+
+/// Verifies the RS256 signature of `id_token` against `jwks` and its `aud`, `iss`, `exp`, `nbf`
+/// and `iat` claims against `expected`, and returns its claims.
+pub(crate) fn verify(id_token: &str, jwks: &JwkSet, expected: &Expected) -> Result<Value> {
+    let header = decode_header(id_token)?;
+    if header.alg != Algorithm::RS256 {
+        return Err(Error::Token(format!(
+            "unsupported id_token algorithm: {:?}",
+            header.alg
+        )));
+    }
+    let jwk = match header.kid.as_deref() {
+        Some(kid) => jwks
+            .find(kid)
+            .ok_or_else(|| Error::Token(format!("no key found in JWKS for kid {}", kid)))?,
+        None => match jwks.keys.as_slice() {
+            [jwk] => jwk,
+            _ => {
+                return Err(Error::Token(
+                    "id_token has no kid and JWKS does not contain exactly one key".to_string(),
+                ))
+            }
+        },
+    };
+    let key = DecodingKey::from_jwk(jwk)?;
+
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.leeway = expected.clock_skew;
+    validation.validate_nbf = true;
+    // The audience and issuer are only checked when the claims are present, so they have to be
+    // required as well.
+    validation.set_audience(&[expected.audience]);
+    match expected.issuer {
+        Some(issuer) => {
+            validation.set_issuer(&[issuer]);
+            validation.set_required_spec_claims(&["exp", "aud", "iss"]);
+        }
+        None => validation.set_required_spec_claims(&["exp", "aud"]),
+    }
+    let claims = decode::<Value>(id_token, &key, &validation)?.claims;
+
+    if claims
+        .get("iat")
+        .and_then(Value::as_u64)
+        .is_some_and(|iat| iat > get_current_timestamp() + expected.clock_skew)
+    {
+        return Err(Error::Token("id_token is issued in the future".to_string()));
+    }
+    Ok(claims)
+}
@@ -0,0 +1,108 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use pam::{
+    items::{RHost, Service, Tty, User},
+    module::PamHandle,
+};
+use std::{
+    ffi::{CStr, CString},
+    sync::Mutex,
+};
+
+/// Name used as the prefix of every log line, in the style of `pam_syslog`.
+const MODULE_NAME: &str = "pam_oauth2_df";
+
+#[derive(Default)]
+struct Context {
+    service: String,
+    user: Option<String>,
+    rhost: Option<String>,
+    tty: Option<String>,
+}
+
+/// Logger writing to syslog with the `authpriv` facility. journald picks these messages up as
+/// well on systemd hosts.
+struct SyslogLogger {
+    context: Mutex<Context>,
+}
+
+static LOGGER: SyslogLogger = SyslogLogger {
+    context: Mutex::new(Context {
+        service: String::new(),
+        user: None,
+        rhost: None,
+        tty: None,
+    }),
+};
+
+/// Installs the syslog logger and records the PAM service, user, rhost and tty of `pamh` so that
+/// they are attached to every message.
+pub(crate) fn init(pamh: &PamHandle) {
+    // The logger can only be installed once per process; later calls just refresh the context.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(LevelFilter::Warn);
+
+    let item = |value: Option<&CStr>| value.map(|v| v.to_string_lossy().into_owned());
+    let context = Context {
+        service: item(pamh.get_item::<Service>().ok().flatten().map(|s| s.0)).unwrap_or_default(),
+        user: item(pamh.get_item::<User>().ok().flatten().map(|u| u.0)),
+        rhost: item(pamh.get_item::<RHost>().ok().flatten().map(|r| r.0)),
+        tty: item(pamh.get_item::<Tty>().ok().flatten().map(|t| t.0)),
+    };
+    *LOGGER.context.lock().unwrap_or_else(|e| e.into_inner()) = context;
+}
+
+/// Applies the configured verbosity.
+pub(crate) fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Updates the user attached to log messages, e.g. once it has been taken from the token.
+pub(crate) fn set_user(user: &str) {
+    LOGGER
+        .context
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .user = Some(user.to_string());
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = {
+            let context = self.context.lock().unwrap_or_else(|e| e.into_inner());
+            format!(
+                "{}({}): {}; user={} rhost={} tty={}",
+                MODULE_NAME,
+                context.service,
+                record.args(),
+                context.user.as_deref().unwrap_or(""),
+                context.rhost.as_deref().unwrap_or(""),
+                context.tty.as_deref().unwrap_or("")
+            )
+        };
+        let priority = match record.level() {
+            Level::Error => libc::LOG_ERR,
+            Level::Warn => libc::LOG_WARNING,
+            Level::Info => libc::LOG_INFO,
+            Level::Debug | Level::Trace => libc::LOG_DEBUG,
+        };
+        // Interior NUL bytes would truncate the message; replace them rather than dropping it.
+        let message = CString::new(message.replace('\0', "\\0")).unwrap_or_default();
+        // SAFETY: both strings are NUL-terminated and the format consumes exactly one argument.
+        unsafe {
+            libc::syslog(
+                libc::LOG_AUTHPRIV | priority,
+                c"%s".as_ptr(),
+                message.as_ptr(),
+            )
+        };
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,124 @@
+//! `success_hook` and `failure_hook`: site programs run after each login attempt, to provision
+//! the account or raise an alert without patching the module.
+
+use crate::{audit::Record, config::Config, session};
+use log::{debug, warn};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, process::Stdio, time::Duration};
+use tokio::{io::AsyncWriteExt, process::Command, time};
+
+/// How long a hook may run before it is killed, as the login waits for it.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a hook reads on stdin: the audit record of the attempt with the `hook_claims`.
+#[derive(Debug, Serialize)]
+struct Input<'a> {
+    #[serde(flatten)]
+    record: &'a Record<'a>,
+    claims: Map<String, Value>,
+}
+
+/// The hooks of `config` and what they are given.
+#[derive(Debug, Clone)]
+pub(crate) struct LoginHooks {
+    success: Option<String>,
+    failure: Option<String>,
+    claim_env: BTreeMap<String, String>,
+    hook_claims: Vec<String>,
+}
+
+impl LoginHooks {
+    /// The hooks of `config`, or `None` when there are none.
+    pub(crate) fn from_config(config: &Config) -> Option<Self> {
+        if config.success_hook.is_none() && config.failure_hook.is_none() {
+            return None;
+        }
+        Some(Self {
+            success: config.success_hook.clone(),
+            failure: config.failure_hook.clone(),
+            claim_env: config.claim_env.clone(),
+            hook_claims: config.hook_claims.clone(),
+        })
+    }
+
+    /// Runs the hook for the attempt of `record`, a `success` or not, with the verified
+    /// `claims`, if any. Failures are only logged, as the login itself has been decided.
+    pub(crate) async fn run(&self, success: bool, record: &Record<'_>, claims: Option<&Value>) {
+        let Some(program) = (if success {
+            &self.success
+        } else {
+            &self.failure
+        }) else {
+            return;
+        };
+        if let Err(err) = self.spawn(program, record, claims).await {
+            warn!("Login hook {} failed: {}", program, err);
+        }
+    }
+
+    async fn spawn(
+        &self,
+        program: &str,
+        record: &Record<'_>,
+        claims: Option<&Value>,
+    ) -> Result<(), String> {
+        let empty = Value::Null;
+        let claims = claims.unwrap_or(&empty);
+        let input = Input {
+            record,
+            claims: self
+                .hook_claims
+                .iter()
+                .filter_map(|claim| Some((claim.clone(), claims.get(claim)?.clone())))
+                .collect(),
+        };
+        let input = serde_json::to_vec(&input).map_err(|err| err.to_string())?;
+
+        let mut command = Command::new(program);
+        command
+            .args(record.user.as_deref())
+            .env("OAUTH2_RESULT", &record.result)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        for (name, value) in [
+            ("PAM_USER", &record.user),
+            ("PAM_RHOST", &record.rhost),
+            ("PAM_SERVICE", &record.service),
+        ] {
+            if let Some(value) = value {
+                command.env(name, value);
+            }
+        }
+        if let Some(error) = record.error {
+            command.env("OAUTH2_ERROR", error);
+        }
+        for (claim, name) in &self.claim_env {
+            if let Some(value) = session::claim_var(claims, claim) {
+                command.env(name, value);
+            }
+        }
+        let mut child = command.spawn().map_err(|err| err.to_string())?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A hook that does not read its input may well exit before it is written.
+            if let Err(err) = stdin.write_all(&input).await {
+                debug!("Login hook {} did not read its input: {}", program, err);
+            }
+        }
+        let output = time::timeout(HOOK_TIMEOUT, child.wait_with_output())
+            .await
+            .map_err(|_| format!("timed out after {:?}", HOOK_TIMEOUT))?
+            .map_err(|err| err.to_string())?;
+        if !output.status.success() {
+            return Err(format!(
+                "{}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        debug!("Ran login hook {}", program);
+        Ok(())
+    }
+}
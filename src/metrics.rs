@@ -0,0 +1,177 @@
+//! Prometheus metrics of the login attempts. As every login runs in its own process, the
+//! counters are kept in `cache_dir` and exported after each attempt, to a textfile for the
+//! node_exporter textfile collector or to a Pushgateway.
+
+use crate::{config::Config, hooks, http::HttpConfig, state_file, template};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+/// Upper bounds in seconds of the `pam_oauth2_flow_duration_seconds` buckets, from a cached
+/// token to a user who takes their time with the device flow.
+const BUCKETS: [f64; 8] = [0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Counters of all the attempts on the host so far.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Counters {
+    attempts: u64,
+    successes: u64,
+    /// Failures by error class.
+    failures: BTreeMap<String, u64>,
+    /// Duration of the attempts by flow.
+    durations: BTreeMap<String, Histogram>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Histogram {
+    /// Observations up to each bound of `BUCKETS`, not cumulated.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// Where the metrics go, from `metrics_textfile` and `metrics_pushgateway`.
+#[derive(Debug, Clone)]
+pub(crate) struct Metrics {
+    state: PathBuf,
+    textfile: Option<String>,
+    pushgateway: Option<(String, HttpConfig)>,
+}
+
+impl Metrics {
+    /// The sinks of `config`, or `None` when no metrics are exported.
+    pub(crate) fn from_config(config: &Config) -> Option<Self> {
+        if config.metrics_textfile.is_none() && config.metrics_pushgateway.is_none() {
+            return None;
+        }
+        let pushgateway = config.metrics_pushgateway.as_ref().and_then(|url| {
+            let url = template::render(url, &[("host", &hooks::hostname())]);
+            match HttpConfig::from_config(config) {
+                Ok(http) => Some((url, http)),
+                Err(err) => {
+                    warn!("Not pushing metrics: {}", err);
+                    None
+                }
+            }
+        });
+        Some(Self {
+            state: Path::new(&config.cache_dir).join("metrics.json"),
+            textfile: config.metrics_textfile.clone(),
+            pushgateway,
+        })
+    }
+
+    /// Counts an attempt that ran `flow` for `duration` and failed with the error class
+    /// `error`, if any. Failures are only logged, as the login itself has been decided.
+    pub(crate) async fn record(&self, flow: &str, error: Option<&str>, duration: Duration) {
+        let text = match self.update(flow, error, duration) {
+            Ok(text) => text,
+            Err(err) => {
+                warn!("Failed to update {}: {}", self.state.display(), err);
+                return;
+            }
+        };
+        if let Some(textfile) = &self.textfile {
+            if let Err(err) = write_textfile(Path::new(textfile), &text) {
+                warn!("Failed to write the metrics to {}: {}", textfile, err);
+            }
+        }
+        if let Some((url, http)) = &self.pushgateway {
+            match http.put(url, "text/plain; version=0.0.4", text).await {
+                Ok((status, _)) if status.is_success() => debug!("Pushed metrics to {}", url),
+                Ok((status, _)) => warn!("Pushgateway {} answered {}", url, status),
+                Err(err) => warn!("Failed to push metrics to {}: {}", url, err),
+            }
+        }
+    }
+
+    /// Adds the attempt to the counters and returns them in the Prometheus text format.
+    fn update(&self, flow: &str, error: Option<&str>, duration: Duration) -> io::Result<String> {
+        // A damaged state file starts the counters over, which Prometheus treats as a reset.
+        state_file::update(&self.state, |counters: &mut Counters| {
+            counters.add(flow, error, duration);
+            counters.render()
+        })
+    }
+}
+
+impl Counters {
+    fn add(&mut self, flow: &str, error: Option<&str>, duration: Duration) {
+        self.attempts += 1;
+        match error {
+            Some(error) => *self.failures.entry(error.to_string()).or_default() += 1,
+            None => self.successes += 1,
+        }
+        let histogram = self.durations.entry(flow.to_string()).or_default();
+        histogram.buckets.resize(BUCKETS.len(), 0);
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let _ = write!(
+            text,
+            "# HELP pam_oauth2_attempts_total Login attempts.\n\
+             # TYPE pam_oauth2_attempts_total counter\n\
+             pam_oauth2_attempts_total {}\n\
+             # HELP pam_oauth2_successes_total Successful logins.\n\
+             # TYPE pam_oauth2_successes_total counter\n\
+             pam_oauth2_successes_total {}\n\
+             # HELP pam_oauth2_failures_total Failed logins by error class.\n\
+             # TYPE pam_oauth2_failures_total counter\n",
+            self.attempts, self.successes
+        );
+        for (error, count) in &self.failures {
+            let _ = writeln!(
+                text,
+                "pam_oauth2_failures_total{{error=\"{}\"}} {}",
+                error, count
+            );
+        }
+        text.push_str(
+            "# HELP pam_oauth2_flow_duration_seconds Duration of the login attempts by flow.\n\
+             # TYPE pam_oauth2_flow_duration_seconds histogram\n",
+        );
+        for (flow, histogram) in &self.durations {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    text,
+                    "pam_oauth2_flow_duration_seconds_bucket{{flow=\"{}\",le=\"{}\"}} {}",
+                    flow, bound, cumulative
+                );
+            }
+            let _ = write!(
+                text,
+                "pam_oauth2_flow_duration_seconds_bucket{{flow=\"{0}\",le=\"+Inf\"}} {1}\n\
+                 pam_oauth2_flow_duration_seconds_sum{{flow=\"{0}\"}} {2}\n\
+                 pam_oauth2_flow_duration_seconds_count{{flow=\"{0}\"}} {1}\n",
+                flow, histogram.count, histogram.sum
+            );
+        }
+        text
+    }
+}
+
+/// Replaces the textfile at once, as the collector may read it at any time.
+fn write_textfile(path: &Path, text: &str) -> io::Result<()> {
+    let tmp = path.with_extension(format!("tmp.{}", process::id()));
+    let result = fs::write(&tmp, text).and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
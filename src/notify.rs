@@ -0,0 +1,243 @@
+//! Out-of-band delivery of the verification link, for clients that cannot show the
+//! instructions, such as scripts on a jump host.
+
+#[cfg(feature = "pam")]
+use crate::config::Config;
+use crate::{
+    device_flow::DeviceAuthResponse,
+    error::{Error, Result},
+    http::HttpConfig,
+    template,
+};
+use log::debug;
+use serde::Serialize;
+use std::{future::Future, io, pin::Pin, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    time,
+};
+
+/// Limit for each step of the SMTP dialogue.
+const SMTP_TIMEOUT: Duration = Duration::from_secs(15);
+
+const EMAIL_SUBJECT: &str = "Login verification";
+
+const EMAIL_BODY: &str = "To complete the login of {user}, open\r\n\r\n    \
+                          {url}\r\n\r\nand enter the code {user_code} if asked. The link \
+                          expires in {expires_min} minutes.\r\n";
+
+/// What a channel delivers to the user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Notification<'a> {
+    pub user: &'a str,
+    /// Verification URI, with the user code when the provider supports it.
+    pub url: &'a str,
+    pub user_code: &'a str,
+    pub expires_in: usize,
+}
+
+impl<'a> Notification<'a> {
+    pub fn new(user: &'a str, device_auth: &'a DeviceAuthResponse) -> Self {
+        Self {
+            user,
+            url: device_auth.login_uri(),
+            user_code: &device_auth.user_code,
+            expires_in: device_auth.expires_in,
+        }
+    }
+}
+
+/// Delivery of a notification by a [`Notifier`].
+pub type NotifyFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// A way of delivering the verification link besides the PAM conversation.
+pub trait Notifier {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> NotifyFuture<'a>;
+}
+
+/// POSTs the notification as JSON to a URL, with the user percent-encoded as `{user}` in the URL
+/// template.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+    http: HttpConfig,
+}
+
+impl Webhook {
+    pub fn new(url: &str, http: HttpConfig) -> Self {
+        Self {
+            url: url.to_string(),
+            http,
+        }
+    }
+}
+
+impl Notifier for Webhook {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> NotifyFuture<'a> {
+        Box::pin(async move {
+            // The PAM user comes from the client before any authentication, so it must stay within
+            // its part of the URL.
+            if matches!(notification.user, "." | "..") {
+                return Err(Error::Notification(format!(
+                    "refusing to notify {:?}, which is not a plain name",
+                    notification.user
+                )));
+            }
+            let user = percent_encode(notification.user);
+            let url = template::render(&self.url, &[("user", &user)]);
+            let (status, _) = self.http.post_json(&url, notification, &[]).await?;
+            if !status.is_success() {
+                return Err(Error::Notification(format!("webhook answered {}", status)));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// `value` with all but the unreserved characters of RFC 3986 percent-encoded.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(byte).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Sends a plain text email through an SMTP relay without authentication, such as the local
+/// MTA, to the address made from a template with the user as `{user}`.
+#[derive(Debug, Clone)]
+pub struct Email {
+    server: String,
+    from: String,
+    to: String,
+}
+
+impl Email {
+    pub fn new(server: &str, from: &str, to: &str) -> Self {
+        Self {
+            server: server.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    async fn send(&self, to: &str, body: &str) -> io::Result<()> {
+        let (reader, writer) = within(TcpStream::connect(&self.server)).await?.into_split();
+        let mut smtp = Smtp {
+            reader: BufReader::new(reader),
+            writer,
+        };
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\r\n{}",
+            self.from,
+            to,
+            EMAIL_SUBJECT,
+            // Dot-stuffing (RFC 5321, section 4.5.2).
+            body.replace("\r\n.", "\r\n..")
+        );
+        let dialogue = [
+            (None, 220),
+            (Some("EHLO localhost".to_string()), 250),
+            (Some(format!("MAIL FROM:<{}>", self.from)), 250),
+            (Some(format!("RCPT TO:<{}>", to)), 250),
+            (Some("DATA".to_string()), 354),
+            (Some(format!("{}\r\n.", message)), 250),
+            (Some("QUIT".to_string()), 221),
+        ];
+        for (command, expected) in dialogue {
+            if let Some(command) = command {
+                within(smtp.writer.write_all(format!("{}\r\n", command).as_bytes())).await?;
+            }
+            let reply = within(smtp.reply()).await?;
+            if !reply.starts_with(&expected.to_string()) {
+                return Err(io::Error::other(format!("unexpected reply {:?}", reply)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Notifier for Email {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> NotifyFuture<'a> {
+        Box::pin(async move {
+            let to = template::render(&self.to, &[("user", notification.user)]);
+            // The PAM user comes from the client before any authentication, and a line break or
+            // an angle bracket would end the header or the SMTP command it is put in.
+            if [notification.user, &to]
+                .iter()
+                .any(|value| value.contains(['\r', '\n', '<', '>']))
+            {
+                return Err(Error::Notification(format!(
+                    "refusing to email {:?}, which is not a plain address",
+                    to
+                )));
+            }
+            let body = template::render(
+                EMAIL_BODY,
+                &[
+                    ("user", notification.user),
+                    ("url", notification.url),
+                    ("user_code", notification.user_code),
+                    ("expires_min", &(notification.expires_in / 60).to_string()),
+                ],
+            );
+            debug!("Sending email to {} via {}", to, self.server);
+            self.send(&to, &body).await.map_err(|err| {
+                Error::Notification(format!("failed to send email via {}: {}", self.server, err))
+            })
+        })
+    }
+}
+
+/// Bounds a step of the SMTP dialogue by `SMTP_TIMEOUT`.
+async fn within<T>(step: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    time::timeout(SMTP_TIMEOUT, step)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+struct Smtp {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl Smtp {
+    /// Reads a reply, returning its last line; continuation lines have a `-` after the code.
+    async fn reply(&mut self) -> io::Result<String> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(line.trim_end().to_string());
+            }
+        }
+    }
+}
+
+/// Channels enabled by `notify_webhook` and `notify_email`.
+#[cfg(feature = "pam")]
+pub(crate) fn from_config(config: &Config, http: &HttpConfig) -> Vec<Box<dyn Notifier>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    if let Some(url) = &config.notify_webhook {
+        notifiers.push(Box::new(Webhook::new(url, http.clone())));
+    }
+    if let Some(to) = &config.notify_email {
+        notifiers.push(Box::new(Email::new(
+            &config.smtp_server,
+            &config.smtp_from,
+            to,
+        )));
+    }
+    notifiers
+}
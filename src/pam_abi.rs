@@ -0,0 +1,156 @@
+//! Numbering of the PAM results and flags. The `pam` crate uses those of Linux-PAM, while
+//! OpenPAM of FreeBSD and macOS numbers them otherwise, so there the entry points of the module
+//! translate them and the results of libpam calls are read back in the Linux-PAM numbering.
+
+use pam::constants::PamResultCode;
+
+/// A result that libpam returned, in the numbering of the `pam` crate.
+#[cfg(not(any(target_os = "freebsd", target_os = "macos")))]
+pub(crate) fn native(code: PamResultCode) -> PamResultCode {
+    code
+}
+
+/// A result that libpam returned, in the numbering of the `pam` crate. It came as an OpenPAM
+/// number in a Linux-PAM variant, so the variant is read as that number.
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+pub(crate) fn native(code: PamResultCode) -> PamResultCode {
+    openpam::from_openpam(code as std::ffi::c_int)
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+mod openpam {
+    use crate::hooks::PamOauth2;
+    use pam::{
+        constants::{PamFlag, PamResultCode},
+        module::{PamHandle, PamHooks},
+    };
+    use std::ffi::{c_char, c_int, CStr};
+
+    /// OpenPAM's `PAM_SILENT`, the same for every hook.
+    const PAM_SILENT: (PamFlag, PamFlag) = (0x8000_0000, 0x8000);
+    /// Flags of `pam_sm_authenticate` and `pam_sm_acct_mgmt`: `PAM_DISALLOW_NULL_AUTHTOK`.
+    const AUTH_FLAGS: &[(PamFlag, PamFlag)] = &[PAM_SILENT, (0x1, 0x1)];
+    /// Flags of `pam_sm_setcred`: `PAM_ESTABLISH_CRED`, `PAM_DELETE_CRED`,
+    /// `PAM_REINITIALIZE_CRED` and `PAM_REFRESH_CRED`.
+    const CRED_FLAGS: &[(PamFlag, PamFlag)] =
+        &[PAM_SILENT, (0x1, 0x2), (0x2, 0x4), (0x4, 0x8), (0x8, 0x10)];
+    /// Flags of `pam_sm_chauthtok`: `PAM_PRELIM_CHECK`, `PAM_UPDATE_AUTHTOK` and
+    /// `PAM_CHANGE_EXPIRED_AUTHTOK`.
+    const CHAUTHTOK_FLAGS: &[(PamFlag, PamFlag)] =
+        &[PAM_SILENT, (0x1, 0x4000), (0x2, 0x2000), (0x4, 0x20)];
+    /// Flags of the session hooks.
+    const SESSION_FLAGS: &[(PamFlag, PamFlag)] = &[PAM_SILENT];
+
+    /// The Linux-PAM bits of the OpenPAM `flags`, by the `(OpenPAM, Linux-PAM)` pairs of the
+    /// hook.
+    fn flags(flags: PamFlag, bits: &[(PamFlag, PamFlag)]) -> PamFlag {
+        bits.iter()
+            .filter(|(openpam, _)| flags & openpam != 0)
+            .fold(0, |linux, (_, bit)| linux | bit)
+    }
+
+    /// The OpenPAM number of `code`, from `<security/pam_constants.h>`.
+    fn to_openpam(code: PamResultCode) -> c_int {
+        use PamResultCode::*;
+        match code {
+            PAM_SUCCESS => 0,
+            PAM_OPEN_ERR => 1,
+            PAM_SYMBOL_ERR => 2,
+            PAM_SERVICE_ERR => 3,
+            PAM_SYSTEM_ERR | PAM_INCOMPLETE => 4,
+            PAM_BUF_ERR => 5,
+            PAM_CONV_ERR | PAM_CONV_AGAIN => 6,
+            PAM_PERM_DENIED => 7,
+            PAM_MAXTRIES => 8,
+            PAM_AUTH_ERR => 9,
+            PAM_NEW_AUTHTOK_REQD => 10,
+            PAM_CRED_INSUFFICIENT => 11,
+            PAM_AUTHINFO_UNAVAIL => 12,
+            PAM_USER_UNKNOWN => 13,
+            PAM_CRED_UNAVAIL => 14,
+            PAM_CRED_EXPIRED => 15,
+            PAM_CRED_ERR => 16,
+            PAM_ACCT_EXPIRED => 17,
+            PAM_AUTHTOK_EXPIRED => 18,
+            PAM_SESSION_ERR => 19,
+            PAM_AUTHTOK_ERR => 20,
+            PAM_AUTHTOK_RECOVERY_ERR => 21,
+            PAM_AUTHTOK_LOCK_BUSY => 22,
+            PAM_AUTHTOK_DISABLE_AGING => 23,
+            PAM_NO_MODULE_DATA => 24,
+            PAM_IGNORE => 25,
+            PAM_ABORT => 26,
+            PAM_TRY_AGAIN => 27,
+            PAM_MODULE_UNKNOWN => 28,
+            PAM_BAD_ITEM => 31,
+        }
+    }
+
+    /// The result of the OpenPAM number `code`; the ones Linux-PAM lacks are system errors.
+    pub(super) fn from_openpam(code: c_int) -> PamResultCode {
+        use PamResultCode::*;
+        match code {
+            0 => PAM_SUCCESS,
+            1 => PAM_OPEN_ERR,
+            2 => PAM_SYMBOL_ERR,
+            3 => PAM_SERVICE_ERR,
+            5 => PAM_BUF_ERR,
+            6 => PAM_CONV_ERR,
+            7 => PAM_PERM_DENIED,
+            8 => PAM_MAXTRIES,
+            9 => PAM_AUTH_ERR,
+            10 => PAM_NEW_AUTHTOK_REQD,
+            11 => PAM_CRED_INSUFFICIENT,
+            12 => PAM_AUTHINFO_UNAVAIL,
+            13 => PAM_USER_UNKNOWN,
+            14 => PAM_CRED_UNAVAIL,
+            15 => PAM_CRED_EXPIRED,
+            16 => PAM_CRED_ERR,
+            17 => PAM_ACCT_EXPIRED,
+            18 => PAM_AUTHTOK_EXPIRED,
+            19 => PAM_SESSION_ERR,
+            20 => PAM_AUTHTOK_ERR,
+            21 => PAM_AUTHTOK_RECOVERY_ERR,
+            22 => PAM_AUTHTOK_LOCK_BUSY,
+            23 => PAM_AUTHTOK_DISABLE_AGING,
+            24 => PAM_NO_MODULE_DATA,
+            25 => PAM_IGNORE,
+            26 => PAM_ABORT,
+            27 => PAM_TRY_AGAIN,
+            28 => PAM_MODULE_UNKNOWN,
+            31 => PAM_BAD_ITEM,
+            _ => PAM_SYSTEM_ERR,
+        }
+    }
+
+    fn args<'a>(argc: c_int, argv: *const *const c_char) -> Vec<&'a CStr> {
+        // SAFETY: libpam passes `argc` NUL-terminated arguments that outlive the hook.
+        (0..argc as usize)
+            .map(|i| unsafe { CStr::from_ptr(*argv.add(i)) })
+            .collect()
+    }
+
+    /// The entry points in place of those of `pam::pam_hooks!`.
+    macro_rules! entry_points {
+        ($($name:ident => $hook:ident, $flags:expr;)*) => {$(
+            #[no_mangle]
+            pub extern "C" fn $name(
+                pamh: &mut PamHandle,
+                flags: PamFlag,
+                argc: c_int,
+                argv: *const *const c_char,
+            ) -> c_int {
+                to_openpam(PamOauth2::$hook(pamh, args(argc, argv), self::flags(flags, $flags)))
+            }
+        )*};
+    }
+
+    entry_points! {
+        pam_sm_authenticate => sm_authenticate, AUTH_FLAGS;
+        pam_sm_acct_mgmt => acct_mgmt, AUTH_FLAGS;
+        pam_sm_setcred => sm_setcred, CRED_FLAGS;
+        pam_sm_chauthtok => sm_chauthtok, CHAUTHTOK_FLAGS;
+        pam_sm_open_session => sm_open_session, SESSION_FLAGS;
+        pam_sm_close_session => sm_close_session, SESSION_FLAGS;
+    }
+}
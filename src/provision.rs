@@ -0,0 +1,194 @@
+//! Creation of the local account of a user who logs in with a valid token and has none yet,
+//! with `useradd` or a program that takes the same arguments, and the check of the existing
+//! accounts against the UID and GID claims.
+
+use crate::{
+    accounts,
+    config::{self, Config},
+    error::{Error, Result},
+    template,
+};
+use log::info;
+use serde_json::Value;
+use std::{
+    fs, io,
+    path::{Component, Path},
+    process::Stdio,
+};
+use tokio::process::Command;
+
+/// UIDs that the created accounts may get without `provision_uid_range`: those `useradd` gives
+/// to users by default.
+const DEFAULT_UID_MIN: u32 = 1000;
+const DEFAULT_UID_MAX: u32 = 60000;
+
+/// Creates the account of `user` when `provision` is set and there is none yet, or checks that
+/// the existing one has the IDs of `claims`.
+pub(crate) async fn ensure_account(config: &Config, user: &str, claims: &Value) -> Result<()> {
+    let failed = |err: &dyn std::fmt::Display| {
+        Error::Provision(format!("failed to create the account {}: {}", user, err))
+    };
+    let uid = id_claim(claims, config.uid_claim.as_deref())?;
+    let gid = id_claim(claims, config.gid_claim.as_deref())?;
+    if let Some(account) = accounts::lookup(user).map_err(|err| failed(&err))? {
+        return check_ids(user, &account, uid, gid);
+    }
+    if !config.provision {
+        return Ok(());
+    }
+    // The name comes from the token, so it must not pass for an option.
+    if user.starts_with('-') {
+        return Err(failed(&"invalid user name"));
+    }
+    // useradd only applies UID_MIN and UID_MAX to the UIDs it picks itself.
+    let min = config.provision_uid_min.unwrap_or(DEFAULT_UID_MIN);
+    let max = config.provision_uid_max.unwrap_or(DEFAULT_UID_MAX);
+    for (name, id) in [("UID", uid), ("GID", gid)] {
+        if let Some(id) = id.filter(|id| *id == 0 || !(min..=max).contains(id)) {
+            return Err(Error::Authorization(format!(
+                "{} {} of {} is outside the range {}-{} of created accounts",
+                name, id, user, min, max
+            )));
+        }
+    }
+    let field = |claim: &Option<String>, fallback: Option<&str>| {
+        passwd_field(claims, user, claim.as_deref(), fallback).map_err(|err| failed(&err))
+    };
+    let home = field(&config.home_claim, Some(&config.provision_home))?;
+    let shell = field(&config.shell_claim, Some(&config.provision_shell))?;
+    let gecos = field(&config.gecos_claim, config.provision_gecos.as_deref())?;
+    if !home.starts_with('/') || !shell.starts_with('/') {
+        return Err(failed(&"home and shell must be absolute paths"));
+    }
+    if !in_home_base(&config.provision_home, &home) {
+        return Err(failed(&format!(
+            "home {} is not below the directory of {}",
+            home, config.provision_home
+        )));
+    }
+    if !login_shells(&config.provision_shells)
+        .map_err(|err| failed(&format!("{}: {}", config.provision_shells, err)))?
+        .contains(&shell)
+    {
+        return Err(failed(&format!(
+            "shell {} is not listed in {}",
+            shell, config.provision_shells
+        )));
+    }
+    let mut command = Command::new(&config.provision_command);
+    command.args(["-m", "-d", &home, "-s", &shell]);
+    if !gecos.is_empty() {
+        command.args(["-c", &gecos]);
+    }
+    if let Some(uid) = uid {
+        command.args(["-u", &uid.to_string()]);
+    }
+    let group = gid.map(|gid| gid.to_string());
+    if let Some(group) = group.as_ref().or(config.provision_group.as_ref()) {
+        command.args(["-g", group]);
+    }
+    if let Some(uid_min) = config.provision_uid_min {
+        command.args(["-K", &format!("UID_MIN={}", uid_min)]);
+    }
+    if let Some(uid_max) = config.provision_uid_max {
+        command.args(["-K", &format!("UID_MAX={}", uid_max)]);
+    }
+    let output = command
+        .arg(user)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|err| failed(&err))?;
+    if !output.status.success() {
+        return Err(failed(&format!(
+            "{} exited with {}: {}",
+            config.provision_command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    info!("Created the local account {}", user);
+    Ok(())
+}
+
+/// Whether `home` is below the directory of the `template` of the homes up to `{user}`, e.g.
+/// `/home` of `/home/{user}`, without going up with `..`.
+fn in_home_base(template: &str, home: &str) -> bool {
+    let prefix = template.split('{').next().unwrap_or_default();
+    let base = &prefix[..prefix.rfind('/').map_or(0, |slash| slash + 1)];
+    Path::new(home).strip_prefix(base).is_ok_and(|rest| {
+        rest.components().next().is_some()
+            && rest
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+    })
+}
+
+/// The shells listed in the `shells` file, in the format of `/etc/shells`.
+fn login_shells(shells: &str) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(shells)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// The string in the claim `field`, or else the `fallback` template; either way fit for a
+/// field of `/etc/passwd`.
+fn passwd_field(
+    claims: &Value,
+    user: &str,
+    field: Option<&str>,
+    fallback: Option<&str>,
+) -> std::result::Result<String, String> {
+    let value = match field.and_then(|field| config::claim(claims, field)) {
+        Some(Value::String(value)) if !value.is_empty() => value.clone(),
+        Some(Value::String(_)) | Some(Value::Null) | None => {
+            template::render(fallback.unwrap_or_default(), &[("user", user)])
+        }
+        Some(_) => return Err(format!("invalid {} claim", field.unwrap_or_default())),
+    };
+    if value.contains([':', '\n']) {
+        return Err(format!("invalid passwd field: {}", value));
+    }
+    Ok(value)
+}
+
+/// The ID in the claim `field`, a number or a decimal string as LDAP-backed providers send.
+fn id_claim(claims: &Value, field: Option<&str>) -> Result<Option<u32>> {
+    let Some(field) = field else {
+        return Ok(None);
+    };
+    let id = match config::claim(claims, field) {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::Number(id)) => id.as_u64().and_then(|id| u32::try_from(id).ok()),
+        Some(Value::String(id)) => id.parse().ok(),
+        Some(_) => None,
+    };
+    id.map(Some)
+        .ok_or_else(|| Error::Token(format!("invalid {} claim", field)))
+}
+
+/// Refuses an account whose IDs differ from those the provider has for the user, so that a
+/// name reused on one host does not get another identity's files elsewhere.
+fn check_ids(
+    user: &str,
+    account: &accounts::Account,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<()> {
+    if let Some(uid) = uid.filter(|uid| *uid != account.uid) {
+        return Err(Error::Authorization(format!(
+            "UID of {} is {}, not {} of the token",
+            user, account.uid, uid
+        )));
+    }
+    if let Some(gid) = gid.filter(|gid| *gid != account.gid) {
+        return Err(Error::Authorization(format!(
+            "GID of {} is {}, not {} of the token",
+            user, account.gid, gid
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+//! Terminal rendering of the verification URI as a QR code, from the `qr` feature. The options
+//! are parsed either way so that a configuration works with both builds.
+
+use crate::error::{Error, Result};
+#[cfg(feature = "qr")]
+use qrcode::{
+    render::{unicode, Renderer},
+    EcLevel, QrCode,
+};
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// Error correction level; higher levels survive more damage at the cost of a larger code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QrEcLevel {
+    L,
+    #[default]
+    M,
+    Q,
+    H,
+}
+
+impl FromStr for QrEcLevel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "l" => Ok(Self::L),
+            "m" => Ok(Self::M),
+            "q" => Ok(Self::Q),
+            "h" => Ok(Self::H),
+            _ => Err(Error::Config(format!("unknown qr_ec_level: {}", s))),
+        }
+    }
+}
+
+#[cfg(feature = "qr")]
+impl From<QrEcLevel> for EcLevel {
+    fn from(level: QrEcLevel) -> Self {
+        match level {
+            QrEcLevel::L => Self::L,
+            QrEcLevel::M => Self::M,
+            QrEcLevel::Q => Self::Q,
+            QrEcLevel::H => Self::H,
+        }
+    }
+}
+
+/// Characters used per module.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QrDensity {
+    /// Two modules per character with half blocks; compact, but some fonts leave gaps between
+    /// the lines.
+    #[default]
+    Half,
+    /// A line per module row with two full block characters per module, twice the height of
+    /// `half`.
+    Full,
+}
+
+impl FromStr for QrDensity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "half" => Ok(Self::Half),
+            "full" => Ok(Self::Full),
+            _ => Err(Error::Config(format!("unknown qr_density: {}", s))),
+        }
+    }
+}
+
+/// Rendering parameters of [`DeviceAuthResponse::qr_code_with`].
+///
+/// [`DeviceAuthResponse::qr_code_with`]: crate::DeviceAuthResponse::qr_code_with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QrOptions {
+    pub ec_level: QrEcLevel,
+    /// Width of the light border in modules; scanners expect 4, smaller values save space.
+    pub quiet_zone: u32,
+    pub density: QrDensity,
+}
+
+impl Default for QrOptions {
+    fn default() -> Self {
+        Self {
+            ec_level: QrEcLevel::default(),
+            quiet_zone: 4,
+            density: QrDensity::default(),
+        }
+    }
+}
+
+/// Renders `data` for a terminal with a dark background, so light modules are drawn as blocks.
+/// Returns `None` when `data` is too long to encode.
+#[cfg(feature = "qr")]
+pub(crate) fn render(data: &str, options: &QrOptions) -> Option<String> {
+    let code = QrCode::with_error_correction_level(data, options.ec_level.into()).ok()?;
+    let colors = code.to_colors();
+    Some(match options.density {
+        QrDensity::Half => {
+            Renderer::<unicode::Dense1x2>::new(&colors, code.width(), options.quiet_zone)
+                .dark_color(unicode::Dense1x2::Light)
+                .light_color(unicode::Dense1x2::Dark)
+                .build()
+        }
+        QrDensity::Full => Renderer::<char>::new(&colors, code.width(), options.quiet_zone)
+            .dark_color(' ')
+            .light_color('\u{2588}')
+            .module_dimensions(2, 1)
+            .build(),
+    })
+}
+
+#[cfg(not(feature = "qr"))]
+pub(crate) fn render(_data: &str, _options: &QrOptions) -> Option<String> {
+    None
+}
@@ -0,0 +1,68 @@
+//! Limit on the failed logins from each remote host, so that it cannot keep starting device
+//! flows to spam the identity provider or try codes on the host.
+
+use crate::{config::Config, hooks, state_file};
+use log::warn;
+use std::{collections::BTreeMap, path::PathBuf};
+
+/// Unix times of the recent failures, by `PAM_RHOST`.
+type Failures = BTreeMap<String, Vec<u64>>;
+
+/// `max_failures` failures of a remote host per `failure_window`, counted in `cache_dir`.
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimit {
+    state: PathBuf,
+    rhost: String,
+    max_failures: usize,
+    window: u64,
+}
+
+impl RateLimit {
+    /// The limit of `config` for `rhost`, or `None` when there is none.
+    pub(crate) fn from_config(config: &Config, rhost: &str) -> Option<Self> {
+        Some(Self {
+            state: PathBuf::from(&config.cache_dir).join("rate_limit.json"),
+            rhost: rhost.to_string(),
+            max_failures: config.max_failures?,
+            window: config.failure_window,
+        })
+    }
+
+    pub(crate) fn rhost(&self) -> &str {
+        &self.rhost
+    }
+
+    /// Whether the host has used up its failures of the window. An unreadable state lets the
+    /// login go ahead.
+    pub(crate) fn exceeded(&self) -> bool {
+        self.update(|failures| failures.get(&self.rhost).map_or(0, Vec::len) >= self.max_failures)
+            .unwrap_or(false)
+    }
+
+    /// Counts a failed login from the host, or forgets its failures after a successful one.
+    pub(crate) fn record(&self, success: bool) {
+        let now = hooks::unix_time();
+        self.update(|failures| {
+            if success {
+                failures.remove(&self.rhost);
+            } else {
+                failures.entry(self.rhost.clone()).or_default().push(now);
+            }
+        });
+    }
+
+    /// Applies `f` to the failures within the window, dropping the older ones.
+    fn update<R>(&self, f: impl FnOnce(&mut Failures) -> R) -> Option<R> {
+        let since = hooks::unix_time().saturating_sub(self.window);
+        let result = state_file::update(&self.state, |failures: &mut Failures| {
+            failures.retain(|_, times| {
+                times.retain(|time| *time > since);
+                !times.is_empty()
+            });
+            f(failures)
+        });
+        result
+            .inspect_err(|err| warn!("Failed to update {}: {}", self.state.display(), err))
+            .ok()
+    }
+}
@@ -0,0 +1,58 @@
+use serde_json::Value;
+
+/// Request and response fields that are never written to the log.
+const SECRET_FIELDS: [&str; 14] = [
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "id_token_hint",
+    "client_secret",
+    "device_code",
+    "code",
+    "code_verifier",
+    "auth_req_id",
+    "subject_token",
+    // Token being introspected or revoked.
+    "token",
+    "client_token",
+    "jwt",
+    "ott",
+];
+
+/// Masks secret parameters of a form-encoded request body for tracing.
+pub(crate) fn redact_form(body: &str) -> String {
+    body.split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if SECRET_FIELDS.contains(&key) => format!("{}=<redacted>", key),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Masks secret fields of a JSON response body for tracing, at any depth.
+pub(crate) fn redact_json(text: &str) -> String {
+    match serde_json::from_str::<Value>(text) {
+        Ok(mut value @ Value::Object(_)) => {
+            redact_value(&mut value);
+            value.to_string()
+        }
+        _ => text.to_string(),
+    }
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_FIELDS.contains(&key.as_str()) {
+                    *value = Value::from("<redacted>");
+                } else {
+                    redact_value(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
@@ -0,0 +1,236 @@
+use crate::{
+    accounts::{passwd, Account},
+    config,
+    hooks::CachedToken,
+    pam_abi,
+};
+use pam::{
+    constants::PamResultCode,
+    module::{PamHandle, PamResult},
+};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, CStr, CString},
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::{fchown, lchown, DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+const ACCESS_TOKEN_ENV: &str = "OAUTH2_ACCESS_TOKEN";
+const ID_TOKEN_ENV: &str = "OAUTH2_ID_TOKEN";
+const TOKEN_EXPIRY_ENV: &str = "OAUTH2_TOKEN_EXPIRY";
+
+/// Directory below `$XDG_RUNTIME_DIR` holding the token file.
+const TOKEN_FILE_DIR: &str = "oauth2";
+const TOKEN_FILE: &str = "token";
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_putenv(pamh: *mut PamHandle, name_value: *const c_char) -> PamResultCode;
+    fn pam_getenv(pamh: *mut PamHandle, name: *const c_char) -> *const c_char;
+}
+
+/// Publishes the tokens to the PAM environment of the session.
+pub(crate) fn export(pamh: &mut PamHandle, cached: &CachedToken) -> PamResult<()> {
+    putenv(pamh, ACCESS_TOKEN_ENV, Some(&cached.token.access_token))?;
+    putenv(pamh, ID_TOKEN_ENV, cached.token.id_token.as_deref())?;
+    match cached.expires_at {
+        Some(expires_at) => putenv(pamh, TOKEN_EXPIRY_ENV, Some(&expires_at.to_string())),
+        None => putenv(pamh, TOKEN_EXPIRY_ENV, None),
+    }
+}
+
+/// Removes the variables set by [`export`].
+pub(crate) fn clear(pamh: &mut PamHandle) -> PamResult<()> {
+    for name in [ACCESS_TOKEN_ENV, ID_TOKEN_ENV, TOKEN_EXPIRY_ENV] {
+        putenv(pamh, name, None)?;
+    }
+    Ok(())
+}
+
+/// Publishes the `claim_env` claims of the session's token to the PAM environment. A claim the
+/// token does not have leaves its variable unset.
+pub(crate) fn export_claims(
+    pamh: &mut PamHandle,
+    claim_env: &BTreeMap<String, String>,
+    claims: &Value,
+) -> PamResult<()> {
+    for (claim, name) in claim_env {
+        putenv(pamh, name, claim_var(claims, claim).as_deref())?;
+    }
+    Ok(())
+}
+
+/// Removes the variables set by [`export_claims`].
+pub(crate) fn clear_claims(
+    pamh: &mut PamHandle,
+    claim_env: &BTreeMap<String, String>,
+) -> PamResult<()> {
+    for name in claim_env.values() {
+        putenv(pamh, name, None)?;
+    }
+    Ok(())
+}
+
+/// The `claim` of `claims` as the value of an environment variable, `None` when it is missing.
+pub(crate) fn claim_var(claims: &Value, claim: &str) -> Option<String> {
+    config::claim(claims, claim)
+        .filter(|value| !value.is_null())
+        .map(env_value)
+}
+
+/// A claim as an environment variable: strings as they are, lists joined with `,` and other
+/// values as JSON.
+fn env_value(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        Value::Array(values) => values.iter().map(env_value).collect::<Vec<_>>().join(","),
+        value => value.to_string(),
+    }
+}
+
+/// Writes the access token to `$XDG_RUNTIME_DIR/oauth2/token`, readable only by `user`. The
+/// runtime directory comes from the PAM environment, as set up by `pam_systemd`, or defaults to
+/// `/run/user/<uid>`; it must already exist.
+pub(crate) fn write_token_file(
+    pamh: &mut PamHandle,
+    user: &str,
+    cached: &CachedToken,
+) -> io::Result<()> {
+    let account = passwd(user)?;
+    let dir = runtime_dir(pamh, account.uid).join(TOKEN_FILE_DIR);
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        // The user may have replaced the directory meanwhile; a link is not followed.
+        Ok(()) => lchown(&dir, Some(account.uid), Some(account.gid))?,
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err),
+    }
+    write_user_file(
+        &account,
+        &dir.join(TOKEN_FILE),
+        cached.token.access_token.as_bytes(),
+        0o600,
+    )
+}
+
+/// Reads the OpenSSH public key of `user` from `path`, where `~` stands for the home
+/// directory, and returns the path of its certificate with it.
+pub(crate) fn read_public_key(user: &str, path: &str) -> io::Result<(PathBuf, String)> {
+    let account = passwd(user)?;
+    let path = match path.strip_prefix("~/") {
+        Some(rest) => account.home.join(rest),
+        None => PathBuf::from(path),
+    };
+    // Only the user's own regular file, so that no other file is sent to the CA. The checks
+    // are of the opened file, as the user can replace the path at any time; a FIFO must not
+    // block the open either.
+    let mut file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(&path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.uid() != account.uid {
+        return Err(io::Error::other(format!(
+            "{} is not a file of {}",
+            path.display(),
+            user
+        )));
+    }
+    let mut key = String::new();
+    file.read_to_string(&mut key)?;
+    let name = path.to_string_lossy();
+    let cert = PathBuf::from(format!(
+        "{}-cert.pub",
+        name.strip_suffix(".pub").unwrap_or(&name)
+    ));
+    Ok((cert, key.trim().to_string()))
+}
+
+/// Installs the SSH certificate `cert` of `user` at `path`.
+pub(crate) fn install_ssh_cert(user: &str, path: &Path, cert: &str) -> io::Result<()> {
+    write_user_file(
+        &passwd(user)?,
+        path,
+        format!("{}\n", cert).as_bytes(),
+        0o644,
+    )
+}
+
+/// Removes the file written by [`write_token_file`].
+pub(crate) fn remove_token_file(pamh: &mut PamHandle, user: &str) -> io::Result<()> {
+    let path = runtime_dir(pamh, passwd(user)?.uid)
+        .join(TOKEN_FILE_DIR)
+        .join(TOKEN_FILE);
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn runtime_dir(pamh: &mut PamHandle, uid: libc::uid_t) -> PathBuf {
+    // SAFETY: `pamh` is the live handle passed to the hook and the name is NUL-terminated; the
+    // returned string belongs to the handle and is copied right away.
+    let value = unsafe { pam_getenv(pamh, c"XDG_RUNTIME_DIR".as_ptr()) };
+    if value.is_null() {
+        return PathBuf::from(format!("/run/user/{}", uid));
+    }
+    // SAFETY: checked for NULL above; libpam returns a NUL-terminated string.
+    PathBuf::from(
+        unsafe { CStr::from_ptr(value) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// Replaces `path` with a file of `account` holding `contents`. The directory must belong to
+/// the account and is checked first, so that it cannot redirect the write elsewhere.
+fn write_user_file(account: &Account, path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != account.uid {
+        return Err(io::Error::other(format!(
+            "{} is not a directory of the user",
+            dir.display()
+        )));
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dir.join(format!(".{}.{}", name, process::id()));
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()?;
+            // Through the open file, as the user can replace the path in their directory.
+            fchown(&file, Some(account.uid), Some(account.gid))
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Sets `name` to `value`, or deletes it when `value` is `None`.
+fn putenv(pamh: &mut PamHandle, name: &str, value: Option<&str>) -> PamResult<()> {
+    let name_value = match value {
+        Some(value) => format!("{}={}", name, value),
+        // OpenPAM cannot delete variables, so they are left empty.
+        None if cfg!(any(target_os = "freebsd", target_os = "macos")) => format!("{}=", name),
+        None => name.to_string(),
+    };
+    let name_value = CString::new(name_value).map_err(|_| PamResultCode::PAM_BUF_ERR)?;
+    // SAFETY: `pamh` is the live handle passed to the hook and `name_value` is NUL-terminated;
+    // libpam copies the string.
+    match pam_abi::native(unsafe { pam_putenv(pamh, name_value.as_ptr()) }) {
+        // Deleting a variable that was never set is not an error.
+        PamResultCode::PAM_SUCCESS | PamResultCode::PAM_BAD_ITEM if value.is_none() => Ok(()),
+        PamResultCode::PAM_SUCCESS => Ok(()),
+        code => Err(code),
+    }
+}
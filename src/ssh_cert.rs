@@ -0,0 +1,213 @@
+//! Short-lived SSH user certificates signed by an SSH CA with the login's id_token, so that
+//! onward hops can use certificate authentication.
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    http::HttpConfig,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{str::FromStr, time::Duration};
+
+/// Kind of SSH CA that signs the certificates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SshCaKind {
+    /// HashiCorp Vault SSH secrets engine, logged into with the JWT auth method.
+    Vault,
+    /// Smallstep step-ca with an OIDC provisioner for the identity provider.
+    StepCa,
+}
+
+impl FromStr for SshCaKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "vault" => Ok(Self::Vault),
+            "step_ca" => Ok(Self::StepCa),
+            _ => Err(Error::Config(format!("unknown ssh_ca: {}", s))),
+        }
+    }
+}
+
+/// Client of the SSH CA.
+#[derive(Debug, Clone)]
+pub struct SshCa {
+    kind: SshCaKind,
+    url: String,
+    http: HttpConfig,
+    /// Vault signing role.
+    role: Option<String>,
+    vault_ssh_mount: String,
+    vault_auth_mount: String,
+    vault_auth_role: Option<String>,
+    ttl: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+struct VaultLogin {
+    auth: VaultAuth,
+}
+
+#[derive(Deserialize)]
+struct VaultAuth {
+    client_token: String,
+}
+
+#[derive(Deserialize)]
+struct VaultSigned {
+    data: VaultSignedData,
+}
+
+#[derive(Deserialize)]
+struct VaultSignedData {
+    signed_key: String,
+}
+
+#[derive(Deserialize)]
+struct StepSigned {
+    crt: String,
+}
+
+impl SshCa {
+    pub fn new(kind: SshCaKind, url: &str, http: HttpConfig) -> Self {
+        Self {
+            kind,
+            url: url.trim_end_matches('/').to_string(),
+            http,
+            role: None,
+            vault_ssh_mount: "ssh".to_string(),
+            vault_auth_mount: "jwt".to_string(),
+            vault_auth_role: None,
+            ttl: None,
+        }
+    }
+
+    /// Builds the client from the `ssh_ca` options; `None` when no CA is configured.
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let (Some(kind), Some(url)) = (config.ssh_ca, &config.ssh_ca_url) else {
+            return Ok(None);
+        };
+        let mut ca = Self::new(kind, url, HttpConfig::from_config(config)?)
+            .with_vault_mounts(&config.vault_ssh_mount, &config.vault_auth_mount);
+        if let Some(role) = &config.ssh_ca_role {
+            ca = ca.with_role(role);
+        }
+        if let Some(role) = &config.vault_auth_role {
+            ca = ca.with_vault_auth_role(role);
+        }
+        if let Some(ttl) = config.ssh_cert_ttl {
+            ca = ca.with_ttl(Duration::from_secs(ttl));
+        }
+        Ok(Some(ca))
+    }
+
+    /// Vault role that signs the certificates.
+    pub fn with_role(mut self, role: &str) -> Self {
+        self.role = Some(role.to_string());
+        self
+    }
+
+    /// Mount points of the SSH secrets engine and of the JWT auth method, `ssh` and `jwt` by
+    /// default.
+    pub fn with_vault_mounts(mut self, ssh: &str, auth: &str) -> Self {
+        self.vault_ssh_mount = ssh.to_string();
+        self.vault_auth_mount = auth.to_string();
+        self
+    }
+
+    /// Role of the JWT auth method; its default role when unset.
+    pub fn with_vault_auth_role(mut self, role: &str) -> Self {
+        self.vault_auth_role = Some(role.to_string());
+        self
+    }
+
+    /// Requested validity of the certificates; the CA bounds and defaults it.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Signs the OpenSSH `public_key` for `principal`, authenticating with the `id_token` of the
+    /// login, and returns the certificate in OpenSSH format.
+    pub async fn sign(&self, id_token: &str, public_key: &str, principal: &str) -> Result<String> {
+        match self.kind {
+            SshCaKind::Vault => self.sign_vault(id_token, public_key, principal).await,
+            SshCaKind::StepCa => self.sign_step(id_token, public_key, principal).await,
+        }
+    }
+
+    async fn sign_vault(
+        &self,
+        id_token: &str,
+        public_key: &str,
+        principal: &str,
+    ) -> Result<String> {
+        let role = self
+            .role
+            .as_deref()
+            .ok_or_else(|| Error::Config("ssh_ca_role is required for Vault".to_string()))?;
+        let mut login = json!({ "jwt": id_token });
+        if let Some(auth_role) = &self.vault_auth_role {
+            login["role"] = Value::from(auth_role.as_str());
+        }
+        let url = format!("{}/v1/auth/{}/login", self.url, self.vault_auth_mount);
+        let login: VaultLogin = self.post(&url, &login, &[]).await?;
+        let mut request = json!({
+            "public_key": public_key,
+            "valid_principals": principal,
+            "cert_type": "user",
+        });
+        if let Some(ttl) = self.ttl {
+            request["ttl"] = Value::from(format!("{}s", ttl.as_secs()));
+        }
+        let url = format!("{}/v1/{}/sign/{}", self.url, self.vault_ssh_mount, role);
+        let signed: VaultSigned = self
+            .post(
+                &url,
+                &request,
+                &[("X-Vault-Token", login.auth.client_token.as_str())],
+            )
+            .await?;
+        Ok(signed.data.signed_key.trim_end().to_string())
+    }
+
+    async fn sign_step(&self, id_token: &str, public_key: &str, principal: &str) -> Result<String> {
+        let mut fields = public_key.split_whitespace();
+        let (Some(key_type), Some(key)) = (fields.next(), fields.next()) else {
+            return Err(Error::SshCa("invalid SSH public key".to_string()));
+        };
+        // The JSON byte string of the key in wire format is the base64 of the OpenSSH line.
+        let mut request = json!({
+            "publicKey": key,
+            "ott": id_token,
+            "certType": "user",
+            "principals": [principal],
+        });
+        if let Some(ttl) = self.ttl {
+            request["validity"] = Value::from(format!("{}s", ttl.as_secs()));
+        }
+        let signed: StepSigned = self
+            .post(&format!("{}/1.0/sign-ssh", self.url), &request, &[])
+            .await?;
+        Ok(format!("{}-cert-v01@openssh.com {}", key_type, signed.crt))
+    }
+
+    async fn post<R: DeserializeOwned>(
+        &self,
+        url: &str,
+        body: &Value,
+        headers: &[(&str, &str)],
+    ) -> Result<R> {
+        let (status, text) = self.http.post_json(url, body, headers).await?;
+        if !status.is_success() {
+            return Err(Error::SshCa(format!(
+                "{} answered {}: {}",
+                url, status, text
+            )));
+        }
+        Ok(serde_json::from_str(&text)?)
+    }
+}
@@ -0,0 +1,50 @@
+//! Small JSON state shared by the logins on the host, such as the metrics counters, updated
+//! under an exclusive lock as each login runs in its own process.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::{DirBuilder, File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    os::unix::{
+        fs::{DirBuilderExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::Path,
+};
+
+/// Applies `f` to the state in `path` and writes it back, with no other process reading or
+/// writing it in between. A missing or damaged file starts from the default state.
+pub(crate) fn update<T, R, F>(path: &Path, f: F) -> io::Result<R>
+where
+    T: Default + Serialize + DeserializeOwned,
+    F: FnOnce(&mut T) -> R,
+{
+    if let Some(dir) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o755).create(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o644)
+        .open(path)?;
+    lock(&file)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let mut state = serde_json::from_slice(&data).unwrap_or_default();
+    let result = f(&mut state);
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(&serde_json::to_vec(&state)?)?;
+    Ok(result)
+}
+
+/// Blocks until this process holds the only lock of `file`, released when it is closed.
+fn lock(file: &File) -> io::Result<()> {
+    // SAFETY: the descriptor stays open for the duration of the call.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
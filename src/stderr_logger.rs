@@ -0,0 +1,29 @@
+//! Logger of the command line tools, which report on stderr rather than to syslog like the
+//! module.
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+struct StderrLogger;
+
+static LOGGER: StderrLogger = StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Writes the messages up to `level` to stderr, as `<level>: <message>`.
+pub fn init_stderr_logger(level: LevelFilter) {
+    // Only the first logger of the process is installed; the level applies all the same.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
@@ -0,0 +1,216 @@
+use crate::error::{Error, Result};
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    AeadCore, Aes256Gcm, Key, Nonce,
+};
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    ffi::CString,
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, ErrorKind, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+const TOKEN_FILE: &str = "tokens";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Per-user token cache laid out as `<dir>/<uid>/tokens`, or another file name for other
+/// entries of the user.
+///
+/// Each file holds a random nonce followed by the AES-256-GCM encrypted JSON payload. The uid is
+/// bound as associated data, so a file copied into another user's directory fails to decrypt.
+pub(crate) struct TokenStore {
+    dir: PathBuf,
+    key_path: PathBuf,
+    file: String,
+}
+
+impl TokenStore {
+    pub(crate) fn new<P: Into<PathBuf>, K: Into<PathBuf>>(dir: P, key_path: K) -> Self {
+        Self {
+            dir: dir.into(),
+            key_path: key_path.into(),
+            file: TOKEN_FILE.to_string(),
+        }
+    }
+
+    /// Keeps the entry in `<dir>/<uid>/<file>` instead of the token cache.
+    pub(crate) fn with_file(mut self, file: &str) -> Self {
+        self.file = file.to_string();
+        self
+    }
+
+    /// Loads the cached value for `user`. Entries that cannot be decrypted or parsed are
+    /// discarded and reported as absent.
+    pub(crate) fn load<T: DeserializeOwned>(&self, user: &str) -> Result<Option<T>> {
+        let uid = uid(user)?;
+        let path = self.user_dir(uid).join(&self.file);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(io_error("read", &path, err)),
+        };
+        match self.decrypt(uid, &data) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => {
+                warn!(
+                    "Discarding corrupted token cache {}: {}",
+                    path.display(),
+                    err
+                );
+                self.remove(user)?;
+                Ok(None)
+            }
+        }
+    }
+
+    pub(crate) fn store<T: Serialize>(&self, user: &str, value: &T) -> Result<()> {
+        let uid = uid(user)?;
+        let dir = self.user_dir(uid);
+        create_dir(&dir)?;
+        let data = self.encrypt(uid, value)?;
+        write_atomic(&dir.join(&self.file), &data)
+    }
+
+    pub(crate) fn remove(&self, user: &str) -> Result<()> {
+        let path = self.user_dir(uid(user)?).join(&self.file);
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(io_error("remove", &path, err)),
+            _ => Ok(()),
+        }
+    }
+
+    fn user_dir(&self, uid: libc::uid_t) -> PathBuf {
+        self.dir.join(uid.to_string())
+    }
+
+    fn encrypt<T: Serialize>(&self, uid: libc::uid_t, value: &T) -> Result<Vec<u8>> {
+        let cipher = Aes256Gcm::new(&self.key()?);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let plaintext = serde_json::to_vec(value).map_err(|err| Error::Cache(err.to_string()))?;
+        let aad = uid.to_string();
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::Cache("failed to encrypt token cache".to_string()))?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn decrypt<T: DeserializeOwned>(&self, uid: libc::uid_t, data: &[u8]) -> Result<T> {
+        if data.len() < NONCE_LEN {
+            return Err(Error::Cache("token cache is truncated".to_string()));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let cipher = Aes256Gcm::new(&self.key()?);
+        let aad = uid.to_string();
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::Cache("failed to decrypt token cache".to_string()))?;
+        serde_json::from_slice(&plaintext).map_err(|err| Error::Cache(err.to_string()))
+    }
+
+    /// Reads the host-local key, generating it on first use.
+    fn key(&self) -> Result<Key<Aes256Gcm>> {
+        let path = &self.key_path;
+        match fs::read(path) {
+            Ok(key) if key.len() == KEY_LEN => return Ok(*Key::<Aes256Gcm>::from_slice(&key)),
+            Ok(_) => {
+                return Err(Error::Cache(format!(
+                    "invalid token cache key: {}",
+                    path.display()
+                )))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(io_error("read", path, err)),
+        }
+        if let Some(parent) = path.parent() {
+            create_dir(parent)?;
+        }
+        let key = Aes256Gcm::generate_key(OsRng);
+        match create_atomic(path, &key) {
+            Ok(()) => Ok(key),
+            // Another login created the key concurrently.
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => self.key(),
+            Err(err) => Err(io_error("create", path, err)),
+        }
+    }
+}
+
+fn create_dir(dir: &Path) -> Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(|err| io_error("create", dir, err))
+}
+
+/// Writes `data` to a temporary file next to `path` and links it into place, failing with
+/// `AlreadyExists` when `path` exists. Unlike a file created in place, it is never seen half
+/// written by a login that starts meanwhile.
+fn create_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    // Logins of the same process may create the file at the same time as well.
+    let tmp = path.with_extension(format!("tmp.{}.{:016x}", process::id(), OsRng.next_u64()));
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::hard_link(&tmp, path));
+    let _ = fs::remove_file(&tmp);
+    result
+}
+
+/// Writes `data` to a temporary file next to `path` and renames it into place.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension(format!("tmp.{}", process::id()));
+    let result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result.map_err(|err| io_error("write", path, err))
+}
+
+fn uid(user: &str) -> Result<libc::uid_t> {
+    let name = CString::new(user).map_err(|err| Error::Cache(err.to_string()))?;
+    // SAFETY: `name` is a valid NUL-terminated string and the returned entry is only read
+    // before any other passwd lookup can overwrite it.
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(Error::Cache(format!("unknown user: {}", user)));
+    }
+    // SAFETY: checked for NULL above.
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+fn io_error(action: &str, path: &Path, err: io::Error) -> Error {
+    Error::Cache(format!("failed to {} {}: {}", action, path.display(), err))
+}
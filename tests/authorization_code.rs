@@ -0,0 +1,144 @@
+mod common;
+
+use common::{MockIdp, Scenario, AUTHORIZATION_CODE, CLIENT_ID};
+use pam_oauth2_df::{AuthorizationRequest, DeviceFlowClient, Endpoints, Error, Result};
+use reqwest::Url;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+async fn client(idp: &MockIdp) -> DeviceFlowClient {
+    DeviceFlowClient::new(Endpoints::discover(idp.url()).await.unwrap(), CLIENT_ID)
+}
+
+fn query(url: &str) -> HashMap<String, String> {
+    Url::parse(url)
+        .unwrap()
+        .query_pairs()
+        .into_owned()
+        .collect()
+}
+
+/// Plays the browser: the provider redirects back with `params`, `state` taken from `request`
+/// unless given.
+fn redirect(request: &AuthorizationRequest, params: &[(&str, &str)]) -> JoinHandle<()> {
+    let authorize = query(&request.authorize_url);
+    let mut url = Url::parse(&authorize["redirect_uri"]).unwrap();
+    url.query_pairs_mut().extend_pairs(params);
+    if !params.iter().any(|(name, _)| *name == "state") {
+        url.query_pairs_mut()
+            .append_pair("state", &authorize["state"]);
+    }
+    tokio::spawn(async move {
+        reqwest::get(url).await.unwrap();
+    })
+}
+
+async fn complete(client: &DeviceFlowClient, request: &AuthorizationRequest) -> Result<()> {
+    let token = client
+        .complete_authorization(request, Duration::from_secs(10))
+        .await?;
+    client.verify_id_token(&token).await.map(|_| ())
+}
+
+#[tokio::test]
+async fn builds_authorize_url() {
+    let idp = MockIdp::start(Scenario::default());
+    let request = client(&idp).await.authorize_browser(0).unwrap();
+    assert!(request
+        .authorize_url
+        .starts_with(&format!("{}/authorize?", idp.url())));
+    let query = query(&request.authorize_url);
+    assert_eq!(query["response_type"], "code");
+    assert_eq!(query["client_id"], CLIENT_ID);
+    assert_eq!(query["scope"], "openid");
+    assert!(query["redirect_uri"].starts_with("http://127.0.0.1:"));
+    assert!(query["state"].len() >= 43);
+    assert_eq!(query["code_challenge_method"], "S256");
+    assert_eq!(query["code_challenge"].len(), 43);
+}
+
+#[tokio::test]
+async fn adds_auth_params_to_authorize_url() {
+    let idp = MockIdp::start(Scenario::default());
+    let request = client(&idp)
+        .await
+        .with_auth_params([("prompt", "login"), ("resource", "urn:ssh")])
+        .authorize_browser(0)
+        .unwrap();
+    let query = query(&request.authorize_url);
+    assert_eq!(query["prompt"], "login");
+    assert_eq!(query["resource"], "urn:ssh");
+}
+
+#[tokio::test]
+async fn exchanges_code_from_redirect() {
+    let idp = MockIdp::start(Scenario::default());
+    let client = client(&idp).await;
+    let request = client.authorize_browser(0).unwrap();
+    idp.expect_code_challenge(&query(&request.authorize_url)["code_challenge"]);
+    let browser = redirect(&request, &[("code", AUTHORIZATION_CODE)]);
+    complete(&client, &request).await.unwrap();
+    browser.await.unwrap();
+}
+
+#[tokio::test]
+async fn token_endpoint_checks_pkce_verifier() {
+    let idp = MockIdp::start(Scenario::default());
+    let client = client(&idp).await;
+    let request = client.authorize_browser(0).unwrap();
+    // The challenge of another authorization request.
+    let other = client.authorize_browser(0).unwrap();
+    idp.expect_code_challenge(&query(&other.authorize_url)["code_challenge"]);
+    let browser = redirect(&request, &[("code", AUTHORIZATION_CODE)]);
+    match complete(&client, &request).await {
+        Err(Error::IdP { error, .. }) => assert_eq!(error, "invalid_grant"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    browser.await.unwrap();
+}
+
+#[tokio::test]
+async fn ignores_redirect_with_wrong_state() {
+    let idp = MockIdp::start(Scenario::default());
+    let client = client(&idp).await;
+    let request = client.authorize_browser(0).unwrap();
+    // The forged redirect neither ends the wait nor has its code exchanged.
+    let (result, ()) = tokio::join!(complete(&client, &request), async {
+        redirect(&request, &[("code", "forged"), ("state", "forged")])
+            .await
+            .unwrap();
+        redirect(&request, &[("code", AUTHORIZATION_CODE)])
+            .await
+            .unwrap();
+    });
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn reports_authorization_error() {
+    let idp = MockIdp::start(Scenario::default());
+    let client = client(&idp).await;
+    let request = client.authorize_browser(0).unwrap();
+    let browser = redirect(&request, &[("error", "access_denied")]);
+    match complete(&client, &request).await {
+        Err(Error::IdP { error, .. }) => assert_eq!(error, "access_denied"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    browser.await.unwrap();
+}
+
+#[tokio::test]
+async fn times_out_without_redirect() {
+    let idp = MockIdp::start(Scenario::default());
+    let client = client(&idp).await;
+    let request = client.authorize_browser(0).unwrap();
+    let started = Instant::now();
+    assert!(client
+        .complete_authorization(&request, Duration::from_secs(1))
+        .await
+        .is_err());
+    assert!(started.elapsed() < Duration::from_secs(3));
+}
@@ -0,0 +1,65 @@
+#![cfg(feature = "pam")]
+
+mod common;
+
+use common::{MockIdp, Scenario, CLIENT_ID, SSH_KEY};
+use pam_oauth2_df::{authorized_keys, authorized_principals, Config, Error};
+
+fn config(idp: &MockIdp, args: &[(&str, &str)]) -> Config {
+    let mut args = args.to_vec();
+    args.extend([("issuer", idp.url()), ("client_id", CLIENT_ID)]);
+    Config::from_args(&args.into_iter().collect()).unwrap()
+}
+
+#[tokio::test]
+async fn prints_keys_of_access_token() {
+    let idp = MockIdp::start(Scenario::default());
+    let keys = authorized_keys(&config(&idp, &[]), "alice", Some("access-token"))
+        .await
+        .unwrap();
+    assert_eq!(keys, [SSH_KEY]);
+}
+
+#[tokio::test]
+async fn prints_user_or_principals_claim() {
+    let idp = MockIdp::start(Scenario::default());
+    let principals = authorized_principals(&config(&idp, &[]), "alice", Some("access-token"))
+        .await
+        .unwrap();
+    assert_eq!(principals, ["alice"]);
+    let config = config(&idp, &[("principals_claim", "groups")]);
+    let principals = authorized_principals(&config, "alice", Some("access-token"))
+        .await
+        .unwrap();
+    assert_eq!(principals, ["users"]);
+}
+
+#[tokio::test]
+async fn rejects_token_of_another_user() {
+    let idp = MockIdp::start(Scenario::default());
+    let result = authorized_keys(&config(&idp, &[]), "bob", Some("access-token")).await;
+    assert!(
+        matches!(result, Err(Error::Authorization(_))),
+        "{:?}",
+        result
+    );
+}
+
+#[tokio::test]
+async fn rejects_inactive_access_token() {
+    let idp = MockIdp::start(Scenario::default());
+    let result = authorized_keys(&config(&idp, &[]), "alice", Some("revoked-token")).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn applies_authorization_policy() {
+    let idp = MockIdp::start(Scenario::default());
+    let config = config(&idp, &[("required_groups", "admins")]);
+    let result = authorized_keys(&config, "alice", Some("access-token")).await;
+    assert!(
+        matches!(result, Err(Error::Authorization(_))),
+        "{:?}",
+        result
+    );
+}
@@ -0,0 +1,538 @@
+//! Mock identity provider serving the discovery, device authorization, token and JWKS endpoints
+//! on a local port, with a scripted sequence of token endpoint replies.
+//!
+//! The authorization endpoint is not served; tests act as the browser and follow the redirect
+//! with [`AUTHORIZATION_CODE`].
+
+// Each test crate only uses part of the harness.
+#![allow(dead_code)]
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use ring::digest::{digest, SHA256};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub const CLIENT_ID: &str = "pam-test";
+
+/// Public key in the `ssh_public_keys` claim of the issued tokens.
+pub const SSH_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKey alice@example.com";
+
+/// Certificate the mock SSH CAs issue.
+pub const SSH_CERT: &str = "ssh-ed25519-cert-v01@openssh.com AAAAIHNzaC1lZDI1NTE5LWNlcnQ=";
+
+/// The only authorization code the token endpoint accepts.
+pub const AUTHORIZATION_CODE: &str = "auth-code";
+
+const SIGNING_KEY: &[u8] = include_bytes!("../data/idp_key.pem");
+const JWKS: &str = include_str!("../data/jwks.json");
+const KEY_ID: &str = "test";
+
+/// Reply of the token endpoint to a device code poll.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenReply {
+    Pending,
+    SlowDown,
+    Denied,
+    Issue,
+}
+
+pub struct Scenario {
+    /// Replies to successive polls; the last one is repeated once the others are used up.
+    pub replies: Vec<TokenReply>,
+    /// `preferred_username` of the issued id_token.
+    pub username: &'static str,
+    pub expires_in: u64,
+    pub interval: u64,
+    /// `iss` of the issued id_token, relative to the mock URL.
+    pub issuer_path: &'static str,
+    /// `iat` of the issued id_token relative to now; it expires 300 seconds later.
+    pub issued_at: i64,
+    /// Seconds before `iat` that the user authenticated, as `auth_time` of the id_token.
+    pub auth_age: u64,
+    /// Leave out the token response fields that RFC 6749 does not require, except the id_token.
+    pub minimal_token: bool,
+    /// Leave out the id_token, so that the claims have to come from the userinfo endpoint.
+    pub omit_id_token: bool,
+    /// Number of device authorization requests answered with 503 before the endpoint recovers.
+    pub unavailable: usize,
+    /// Number of token requests answered with 503 before the endpoint recovers.
+    pub token_unavailable: usize,
+    /// Leave out `verification_uri_complete`, so the user code has to be entered by hand.
+    pub omit_complete_uri: bool,
+    /// Answer token requests whose DPoP proof lacks this nonce with `use_dpop_nonce`.
+    pub dpop_nonce: Option<&'static str>,
+    /// Leave the groups out of the id_token like the groups overage of Azure AD; `/groups` lists
+    /// them on two pages.
+    pub groups_overage: bool,
+    /// Claims of the issued id_token added to or replacing the usual ones.
+    pub extra_claims: Vec<(&'static str, Value)>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            replies: vec![TokenReply::Pending, TokenReply::Issue],
+            username: "alice",
+            expires_in: 600,
+            interval: 1,
+            issuer_path: "",
+            issued_at: 0,
+            auth_age: 0,
+            minimal_token: false,
+            omit_id_token: false,
+            unavailable: 0,
+            token_unavailable: 0,
+            omit_complete_uri: false,
+            dpop_nonce: None,
+            groups_overage: false,
+            extra_claims: Vec::new(),
+        }
+    }
+}
+
+struct State {
+    url: String,
+    scenario: Scenario,
+    replies: Mutex<VecDeque<TokenReply>>,
+    polls: Mutex<Vec<Instant>>,
+    proxied: Mutex<usize>,
+    unavailable: Mutex<usize>,
+    token_unavailable: Mutex<usize>,
+    code_challenge: Mutex<Option<String>>,
+    bodies: Mutex<HashMap<String, String>>,
+    headers: Mutex<HashMap<String, Vec<(String, String)>>>,
+    dpop_proofs: Mutex<Vec<(String, String)>>,
+    requests: Mutex<HashMap<String, usize>>,
+    stopped: AtomicBool,
+    webhooks: Mutex<Vec<(String, String)>>,
+}
+
+pub struct MockIdp {
+    state: Arc<State>,
+}
+
+impl MockIdp {
+    pub fn start(scenario: Scenario) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let state = Arc::new(State {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            replies: Mutex::new(scenario.replies.iter().copied().collect()),
+            polls: Mutex::new(Vec::new()),
+            proxied: Mutex::new(0),
+            unavailable: Mutex::new(scenario.unavailable),
+            token_unavailable: Mutex::new(scenario.token_unavailable),
+            code_challenge: Mutex::new(None),
+            bodies: Mutex::new(HashMap::new()),
+            headers: Mutex::new(HashMap::new()),
+            dpop_proofs: Mutex::new(Vec::new()),
+            requests: Mutex::new(HashMap::new()),
+            stopped: AtomicBool::new(false),
+            webhooks: Mutex::new(Vec::new()),
+            scenario,
+        });
+        let server = Arc::clone(&state);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = handle(stream, &server);
+            }
+        });
+        Self { state }
+    }
+
+    /// Issuer URL, from which the other endpoints are discovered.
+    pub fn url(&self) -> &str {
+        &self.state.url
+    }
+
+    /// Times at which the token endpoint was polled with the device code.
+    pub fn polls(&self) -> Vec<Instant> {
+        self.state.polls.lock().unwrap().clone()
+    }
+
+    /// Requires the `code_verifier` of the authorization code exchange to match the S256
+    /// `challenge`, as the authorization endpoint would have recorded it.
+    pub fn expect_code_challenge(&self, challenge: &str) {
+        *self.state.code_challenge.lock().unwrap() = Some(challenge.to_string());
+    }
+
+    /// Body of the last request to `path`.
+    pub fn last_body(&self, path: &str) -> Option<String> {
+        self.state.bodies.lock().unwrap().get(path).cloned()
+    }
+
+    /// Value of the header `name` in the last request to `path`.
+    pub fn last_header(&self, path: &str, name: &str) -> Option<String> {
+        let headers = self.state.headers.lock().unwrap();
+        headers
+            .get(path)?
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Path and DPoP proof of the requests that carried one.
+    pub fn dpop_proofs(&self) -> Vec<(String, String)> {
+        self.state.dpop_proofs.lock().unwrap().clone()
+    }
+
+    /// Number of requests to `path`.
+    pub fn requests(&self, path: &str) -> usize {
+        self.state
+            .requests
+            .lock()
+            .unwrap()
+            .get(path)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Drops every later connection without an answer, as during an outage.
+    pub fn stop(&self) {
+        self.state.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Path and JSON body of the requests to `/hooks/...`.
+    pub fn webhooks(&self) -> Vec<(String, String)> {
+        self.state.webhooks.lock().unwrap().clone()
+    }
+
+    /// Number of requests that came in through an HTTP proxy, i.e. with an absolute URL.
+    pub fn proxied(&self) -> usize {
+        *self.state.proxied.lock().unwrap()
+    }
+}
+
+fn handle(mut stream: TcpStream, state: &State) -> io::Result<()> {
+    if state.stopped.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut content_length = 0;
+    let mut authorization = String::new();
+    let mut dpop = None;
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.to_string(), value.trim().to_string()));
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = value.trim().to_string();
+            } else if name.eq_ignore_ascii_case("dpop") {
+                dpop = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("x-vault-token") {
+                authorization = format!("Vault {}", value.trim());
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let mut path = request_line.split_whitespace().nth(1).unwrap_or("");
+    if let Some(target) = path.strip_prefix(state.url.as_str()) {
+        *state.proxied.lock().unwrap() += 1;
+        path = target;
+    }
+    let body = String::from_utf8_lossy(&body);
+    *state
+        .requests
+        .lock()
+        .unwrap()
+        .entry(path.to_string())
+        .or_default() += 1;
+    state
+        .bodies
+        .lock()
+        .unwrap()
+        .insert(path.to_string(), body.to_string());
+    state
+        .headers
+        .lock()
+        .unwrap()
+        .insert(path.to_string(), headers);
+    if let Some(proof) = &dpop {
+        let proofs = &state.dpop_proofs;
+        proofs
+            .lock()
+            .unwrap()
+            .push((path.to_string(), proof.clone()));
+    }
+    if let (Some(nonce), "/token") = (state.scenario.dpop_nonce, path) {
+        if dpop.as_deref().and_then(proof_nonce).as_deref() != Some(nonce) {
+            let body = json!({"error": "use_dpop_nonce"}).to_string();
+            return write!(
+                stream,
+                "HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nDPoP-Nonce: {}\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                nonce,
+                body.len(),
+                body
+            );
+        }
+    }
+    let (status, body) = route(path, &authorization, &body, state);
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn route(path: &str, authorization: &str, body: &str, state: &State) -> (&'static str, String) {
+    let url = &state.url;
+    match path {
+        // An issuer that does not exist at the provider.
+        _ if path.starts_with("/missing/") => ("404 Not Found", "Not Found".to_string()),
+        // Served below any path, always describing the root issuer.
+        _ if path.ends_with("/.well-known/openid-configuration") => ok(json!({
+            "issuer": url,
+            "authorization_endpoint": format!("{}/authorize", url),
+            "device_authorization_endpoint": format!("{}/device", url),
+            "backchannel_authentication_endpoint": format!("{}/bc-authorize", url),
+            "token_endpoint": format!("{}/token", url),
+            "jwks_uri": format!("{}/jwks", url),
+            "userinfo_endpoint": format!("{}/userinfo", url),
+            "revocation_endpoint": format!("{}/revoke", url),
+            "end_session_endpoint": format!("{}/logout", url),
+        })),
+        "/device" if take(&state.unavailable) => ("503 Service Unavailable", String::new()),
+        "/device" => {
+            let mut response = json!({
+                "device_code": "device-code",
+                "user_code": "ABCD-EFGH",
+                "verification_uri": format!("{}/activate", url),
+                "verification_uri_complete": format!("{}/activate?user_code=ABCD-EFGH", url),
+                "expires_in": state.scenario.expires_in,
+                "interval": state.scenario.interval,
+            });
+            if state.scenario.omit_complete_uri {
+                response
+                    .as_object_mut()
+                    .unwrap()
+                    .remove("verification_uri_complete");
+            }
+            ok(response)
+        }
+        _ if path.starts_with("/hooks/") => {
+            let request = (path.to_string(), body.to_string());
+            state.webhooks.lock().unwrap().push(request);
+            ("204 No Content", String::new())
+        }
+        // Vault with the JWT auth method and the SSH secrets engine at their default mounts.
+        "/v1/auth/jwt/login" if body.contains("\"jwt\":\"ey") => ok(json!({
+            "auth": { "client_token": "vault-token" },
+        })),
+        "/v1/ssh/sign/users" if authorization == "Vault vault-token" => ok(json!({
+            "data": { "signed_key": format!("{}\n", SSH_CERT) },
+        })),
+        "/1.0/sign-ssh" if body.contains("\"ott\":\"ey") => ok(json!({
+            "crt": SSH_CERT.split_whitespace().nth(1),
+        })),
+        "/v1/auth/jwt/login" | "/v1/ssh/sign/users" | "/1.0/sign-ssh" => (
+            "403 Forbidden",
+            json!({ "errors": ["permission denied"] }).to_string(),
+        ),
+        "/bc-authorize" if body.contains("login_hint=") => ok(json!({
+            "auth_req_id": "auth-req",
+            "expires_in": state.scenario.expires_in,
+            "interval": state.scenario.interval,
+        })),
+        "/bc-authorize" => oauth_error("invalid_request"),
+        "/token" if take(&state.token_unavailable) => ("503 Service Unavailable", String::new()),
+        "/token" if body.contains("grant_type=refresh_token") => ok(token(state)),
+        // The Azure AD v1 endpoints, which take a `resource`, know only their own device grant.
+        "/token" if body.contains("&resource=") && !body.contains("&grant_type=device_code&") => {
+            oauth_error("unsupported_grant_type")
+        }
+        "/token" if body.contains("grant-type%3Atoken-exchange") => {
+            if body.contains("subject_token=access-token&") {
+                ok(json!({
+                    "access_token": "exchanged-token",
+                    "issued_token_type": "urn:ietf:params:oauth:token-type:access_token",
+                    "token_type": "Bearer",
+                    "expires_in": 60,
+                }))
+            } else {
+                oauth_error("invalid_grant")
+            }
+        }
+        "/token" if body.contains("grant_type=authorization_code") => {
+            if body.contains(&format!("code={}&", AUTHORIZATION_CODE))
+                && verifies_code_challenge(body, state)
+            {
+                ok(token(state))
+            } else {
+                oauth_error("invalid_grant")
+            }
+        }
+        "/token" => {
+            state.polls.lock().unwrap().push(Instant::now());
+            let reply = {
+                let mut replies = state.replies.lock().unwrap();
+                match replies.len() {
+                    0 => TokenReply::Pending,
+                    1 => replies[0],
+                    _ => replies.pop_front().unwrap(),
+                }
+            };
+            match reply {
+                TokenReply::Pending => oauth_error("authorization_pending"),
+                TokenReply::SlowDown => oauth_error("slow_down"),
+                TokenReply::Denied => oauth_error("access_denied"),
+                TokenReply::Issue => ok(token(state)),
+            }
+        }
+        "/jwks" => ("200 OK", JWKS.to_string()),
+        "/userinfo" if authorization == "Bearer access-token" => ok(json!({
+            "sub": "user-1",
+            "preferred_username": state.scenario.username,
+            "groups": ["users"],
+            "ssh_public_keys": [SSH_KEY],
+        })),
+        "/userinfo" => ("401 Unauthorized", String::new()),
+        "/revoke" => ("200 OK", String::new()),
+        "/logout" if body.starts_with("id_token_hint=ey") => ("200 OK", String::new()),
+        "/logout" => ("400 Bad Request", String::new()),
+        "/groups" if authorization == "Bearer access-token" => ok(json!({
+            "value": [{"id": "0001", "displayName": "users"}, {"id": "0002"}],
+            "@odata.nextLink": format!("{}/groups?page=2", url),
+        })),
+        "/groups?page=2" if authorization == "Bearer access-token" => ok(json!({
+            "value": [{"id": "0003", "displayName": "admins"}],
+        })),
+        "/groups" | "/groups?page=2" => ("401 Unauthorized", String::new()),
+        "/introspect" if body.contains("token=access-token") => ok(json!({
+            "active": true,
+            "username": state.scenario.username,
+            "scope": "openid profile",
+            "client_id": CLIENT_ID,
+        })),
+        "/introspect" => ok(json!({ "active": false })),
+        _ => ("404 Not Found", "{}".to_string()),
+    }
+}
+
+/// Counts `counter` down, returning whether it was still positive.
+fn take(counter: &Mutex<usize>) -> bool {
+    let mut counter = counter.lock().unwrap();
+    let positive = *counter > 0;
+    *counter = counter.saturating_sub(1);
+    positive
+}
+
+fn verifies_code_challenge(body: &str, state: &State) -> bool {
+    let challenge = state.code_challenge.lock().unwrap();
+    let verifier = body
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("code_verifier="));
+    match (&*challenge, verifier) {
+        (None, _) => true,
+        (Some(challenge), Some(verifier)) => {
+            URL_SAFE_NO_PAD.encode(digest(&SHA256, verifier.as_bytes())) == *challenge
+        }
+        (Some(_), None) => false,
+    }
+}
+
+/// `nonce` claim of a DPoP proof, whose signature the token endpoint does not check.
+fn proof_nonce(proof: &str) -> Option<String> {
+    let claims = URL_SAFE_NO_PAD.decode(proof.split('.').nth(1)?).ok()?;
+    let claims: Value = serde_json::from_slice(&claims).ok()?;
+    Some(claims.get("nonce")?.as_str()?.to_string())
+}
+
+fn ok(value: Value) -> (&'static str, String) {
+    ("200 OK", value.to_string())
+}
+
+fn oauth_error(error: &str) -> (&'static str, String) {
+    ("400 Bad Request", json!({ "error": error }).to_string())
+}
+
+fn token(state: &State) -> Value {
+    let issued_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .saturating_add_signed(state.scenario.issued_at);
+    let mut claims = json!({
+        "iss": format!("{}{}", state.url, state.scenario.issuer_path),
+        "aud": CLIENT_ID,
+        "sub": "user-1",
+        "preferred_username": state.scenario.username,
+        "email": format!("{}@example.com", state.scenario.username),
+        "email_verified": true,
+        "groups": ["users"],
+        "realm_access": {"roles": ["ssh-user", "offline_access"]},
+        "acr": "urn:example:mfa",
+        "amr": ["pwd", "otp"],
+        "name": "Test User",
+        "loginShell": "/bin/zsh",
+        "uidNumber": 20001,
+        "gidNumber": "20001",
+        "ssh_public_keys": [SSH_KEY],
+        "auth_time": issued_at - state.scenario.auth_age,
+        "iat": issued_at,
+        "exp": issued_at + 300,
+    });
+    for (name, value) in &state.scenario.extra_claims {
+        claims[*name] = value.clone();
+    }
+    if state.scenario.groups_overage {
+        let claims = claims.as_object_mut().unwrap();
+        claims.remove("groups");
+        claims.insert("_claim_names".to_string(), json!({"groups": "src1"}));
+    }
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(KEY_ID.to_string());
+    let id_token = encode(
+        &header,
+        &claims,
+        &EncodingKey::from_rsa_pem(SIGNING_KEY).unwrap(),
+    )
+    .unwrap();
+    if state.scenario.omit_id_token {
+        return json!({
+            "access_token": "access-token",
+            "token_type": "Bearer",
+            "expires_in": 300,
+        });
+    }
+    if state.scenario.minimal_token {
+        return json!({
+            "access_token": "access-token",
+            "token_type": "Bearer",
+            "id_token": id_token,
+        });
+    }
+    json!({
+        "access_token": "access-token",
+        "refresh_token": "refresh-token",
+        "token_type": "Bearer",
+        "id_token": id_token,
+        "scope": "openid profile",
+        "session_state": "session",
+        "expires_in": 300,
+    })
+}
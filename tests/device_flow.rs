@@ -0,0 +1,989 @@
+mod common;
+
+use common::{MockIdp, Scenario, TokenReply, CLIENT_ID};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use pam_oauth2_df::{
+    ClientAssertion, ClientAuthMethod, Config, DeviceAuthResponse, DeviceFlowClient, DpopKey,
+    Endpoints, Error, HttpConfig, ProviderConfig, QrOptions, Result, TokenResponse,
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde_json::{json, Value};
+use std::{
+    env, fs,
+    net::TcpListener,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    process::{self, Command},
+    time::{Duration, Instant},
+};
+
+async fn client(idp: &MockIdp, client_id: &str) -> DeviceFlowClient {
+    DeviceFlowClient::new(Endpoints::discover(idp.url()).await.unwrap(), client_id)
+}
+
+async fn run(idp: &MockIdp) -> Result<TokenResponse> {
+    let client = client(idp, CLIENT_ID).await;
+    let device_auth = client.authorize_device().await?;
+    client.poll_token(&device_auth).await
+}
+
+#[tokio::test]
+async fn issues_token_after_pending() {
+    let idp = MockIdp::start(Scenario {
+        replies: vec![TokenReply::Pending, TokenReply::Pending, TokenReply::Issue],
+        ..Scenario::default()
+    });
+    let token = run(&idp).await.unwrap();
+    assert_eq!(token.access_token, "access-token");
+    assert_eq!(idp.polls().len(), 3);
+}
+
+#[tokio::test]
+async fn stops_polling_when_dropped() {
+    let idp = MockIdp::start(Scenario {
+        replies: vec![TokenReply::Pending; 10],
+        interval: 1,
+        ..Scenario::default()
+    });
+    let client = client(&idp, CLIENT_ID).await;
+    let device_auth = client.authorize_device().await.unwrap();
+    let started = Instant::now();
+    let polled =
+        tokio::time::timeout(Duration::from_millis(1500), client.poll_token(&device_auth)).await;
+    assert!(polled.is_err());
+    assert!(started.elapsed() < Duration::from_secs(2));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(idp.polls().len(), 2);
+}
+
+#[tokio::test]
+async fn encodes_reserved_characters_of_parameters() {
+    let idp = MockIdp::start(Scenario::default());
+    let client = client(&idp, CLIENT_ID)
+        .await
+        .with_client_secret("s3cr&t=+%", ClientAuthMethod::ClientSecretPost)
+        .with_scope(["openid", "a&b=c"]);
+    let device_auth = client.authorize_device().await.unwrap();
+    client.poll_token(&device_auth).await.unwrap();
+
+    for path in ["/device", "/token"] {
+        let body = idp.last_body(path).unwrap();
+        let form: Vec<(String, String)> = form_urlencoded::parse(body.as_bytes())
+            .into_owned()
+            .collect();
+        assert!(form.contains(&("client_secret".to_string(), "s3cr&t=+%".to_string())));
+    }
+    let body = idp.last_body("/device").unwrap();
+    assert!(body.contains("&scope=openid+a%26b%3Dc"), "{}", body);
+}
+
+#[tokio::test]
+async fn polls_every_five_seconds_for_zero_interval() {
+    let idp = MockIdp::start(Scenario {
+        interval: 0,
+        ..Scenario::default()
+    });
+    run(&idp).await.unwrap();
+    let polls = idp.polls();
+    assert!(polls[1] - polls[0] >= Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn enforces_min_interval() {
+    let idp = MockIdp::start(Scenario::default());
+    let client = client(&idp, CLIENT_ID)
+        .await
+        .with_min_interval(Duration::from_secs(2));
+    let device_auth = client.authorize_device().await.unwrap();
+    client.poll_token(&device_auth).await.unwrap();
+    let polls = idp.polls();
+    assert!(polls[1] - polls[0] >= Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn verifies_id_token() {
+    let idp = MockIdp::start(Scenario::default());
+    let token = run(&idp).await.unwrap();
+    let claims = client(&idp, CLIENT_ID)
+        .await
+        .verify_id_token(&token)
+        .await
+        .unwrap();
+    assert_eq!(claims["preferred_username"], "alice");
+}
+
+#[tokio::test]
+async fn accepts_minimal_token_response() {
+    let idp = MockIdp::start(Scenario {
+        minimal_token: true,
+        ..Scenario::default()
+    });
+    let token = run(&idp).await.unwrap();
+    assert_eq!(token.refresh_token, None);
+    assert_eq!(token.session_state, None);
+    let refreshed = client(&idp, CLIENT_ID)
+        .await
+        .refresh_token("refresh-token")
+        .await
+        .unwrap();
+    assert_eq!(refreshed.refresh_token.as_deref(), Some("refresh-token"));
+}
+
+#[test]
+fn bounds_expires_in_of_device_code() {
+    let parse = |expires_in: Option<Value>| {
+        let mut response = json!({
+            "device_code": "device-code",
+            "user_code": "ABCD-EFGH",
+            "verification_uri": "https://idp.example/device",
+        });
+        if let Some(expires_in) = expires_in {
+            response["expires_in"] = expires_in;
+        }
+        serde_json::from_value::<DeviceAuthResponse>(response)
+            .unwrap()
+            .expires_in
+    };
+    assert_eq!(parse(None), 300);
+    assert_eq!(parse(Some(json!(0))), 300);
+    assert_eq!(parse(Some(json!(900))), 900);
+    assert_eq!(parse(Some(json!(86400))), 1800);
+}
+
+#[test]
+fn accepts_numbers_sent_as_strings() {
+    let device_auth: DeviceAuthResponse = serde_json::from_value(json!({
+        "device_code": "device-code",
+        "user_code": "ABCD-EFGH",
+        "verification_uri": "https://idp.example/device",
+        "expires_in": "900",
+        "interval": "10",
+    }))
+    .unwrap();
+    assert_eq!((device_auth.expires_in, device_auth.interval), (900, 10));
+    let token: TokenResponse = serde_json::from_value(json!({
+        "access_token": "access-token",
+        "token_type": "Bearer",
+        "expires_in": "3600",
+    }))
+    .unwrap();
+    assert_eq!(token.expires_in, Some(3600));
+    let invalid = json!({"access_token": "a", "token_type": "Bearer", "expires_in": "soon"});
+    assert!(serde_json::from_value::<TokenResponse>(invalid).is_err());
+}
+
+#[test]
+fn parses_device_response_of_azure_ad_v1() {
+    let device_auth: DeviceAuthResponse = serde_json::from_value(json!({
+        "device_code": "device-code",
+        "user_code": "ABCDEFGH",
+        "verification_url": "https://microsoft.com/devicelogin",
+        "expires_in": "900",
+        "interval": "5",
+        "message": "To sign in, use a web browser to open the page ...",
+    }))
+    .unwrap();
+    assert_eq!(
+        device_auth.verification_uri,
+        "https://microsoft.com/devicelogin"
+    );
+    assert_eq!(device_auth.login_uri(), "https://microsoft.com/devicelogin");
+}
+
+#[test]
+fn fills_in_tenant_of_issuer() {
+    let issuer = |args: &[(&str, &str)]| {
+        let mut args = args.to_vec();
+        args.push(("client_id", CLIENT_ID));
+        Config::from_args(&args.into_iter().collect())
+            .unwrap()
+            .issuer()
+    };
+    assert_eq!(
+        issuer(&[("tenant", "contoso.onmicrosoft.com")]).unwrap(),
+        "https://login.microsoftonline.com/contoso.onmicrosoft.com/v2.0"
+    );
+    assert_eq!(
+        issuer(&[
+            ("tenant", "contoso"),
+            (
+                "issuer",
+                "https://{tenant}.b2clogin.com/{tenant}.onmicrosoft.com/v2.0/"
+            ),
+        ])
+        .unwrap(),
+        "https://contoso.b2clogin.com/contoso.onmicrosoft.com/v2.0/"
+    );
+    assert_eq!(
+        issuer(&[("issuer", "https://idp.example")]).unwrap(),
+        "https://idp.example"
+    );
+}
+
+#[tokio::test]
+async fn sends_resource_to_device_and_token_endpoints() {
+    let idp = MockIdp::start(Scenario::default());
+    let config = Config::from_args(
+        &[
+            ("issuer", idp.url()),
+            ("client_id", CLIENT_ID),
+            ("resource", "https://graph.microsoft.com"),
+        ]
+        .into_iter()
+        .collect(),
+    )
+    .unwrap();
+    let client = DeviceFlowClient::from_config(&config).await.unwrap();
+    let device_auth = client.authorize_device().await.unwrap();
+    client.poll_token(&device_auth).await.unwrap();
+    let device = idp.last_body("/device").unwrap();
+    assert!(
+        device.contains("resource=https%3A%2F%2Fgraph.microsoft.com"),
+        "{}",
+        device
+    );
+    let token = idp.last_body("/token").unwrap();
+    assert!(
+        token.contains(
+            "&grant_type=device_code&resource=https%3A%2F%2Fgraph.microsoft.com&code=device-code"
+        ),
+        "{}",
+        token
+    );
+}
+
+#[test]
+fn keeps_extra_fields_of_token_response() {
+    let response = json!({
+        "access_token": "access-token",
+        "token_type": "Bearer",
+        "refresh_expires_in": 1800,
+        "not-before-policy": 0,
+        "resource_token": {"aud": "api"},
+    });
+    let token: TokenResponse = serde_json::from_value(response.clone()).unwrap();
+    assert_eq!(token.extra["refresh_expires_in"], 1800);
+    assert_eq!(token.extra["resource_token"]["aud"], "api");
+    assert!(!token.extra.contains_key("access_token"));
+    let cached = serde_json::to_value(&token).unwrap();
+    assert_eq!(cached["not-before-policy"], 0);
+    assert_eq!(
+        serde_json::from_value::<TokenResponse>(cached).unwrap(),
+        token
+    );
+}
+
+#[tokio::test]
+async fn formats_user_code() {
+    let idp = MockIdp::start(Scenario {
+        omit_complete_uri: true,
+        ..Scenario::default()
+    });
+    let mut device_auth = client(&idp, CLIENT_ID)
+        .await
+        .authorize_device()
+        .await
+        .unwrap();
+    assert_eq!(device_auth.verification_uri_complete, None);
+    assert_eq!(device_auth.login_uri(), format!("{}/activate", idp.url()));
+    assert_eq!(device_auth.formatted_user_code(), "ABCD-EFGH");
+    device_auth.user_code = "WDJBMJHTX".to_string();
+    assert_eq!(device_auth.formatted_user_code(), "WDJB-MJHT-X");
+    device_auth.user_code = "123 456".to_string();
+    assert_eq!(device_auth.formatted_user_code(), "123-456");
+}
+
+#[cfg(feature = "qr")]
+#[tokio::test]
+async fn renders_qr_code_options() {
+    let idp = MockIdp::start(Scenario::default());
+    let device_auth = client(&idp, CLIENT_ID)
+        .await
+        .authorize_device()
+        .await
+        .unwrap();
+    let half = device_auth.qr_code().unwrap();
+    let full = device_auth
+        .qr_code_with(&QrOptions {
+            quiet_zone: 1,
+            density: pam_oauth2_df::QrDensity::Full,
+            ..QrOptions::default()
+        })
+        .unwrap();
+    let rows = |qr: &str| qr.lines().count();
+    // 4 + 4 quiet zone modules around the code, two per line, against 1 + 1, one per line.
+    assert_eq!(rows(&half), (rows(&full) - 2 + 8).div_ceil(2));
+}
+
+#[cfg(not(feature = "qr"))]
+#[tokio::test]
+async fn shows_only_uri_without_qr_feature() {
+    let idp = MockIdp::start(Scenario::default());
+    let device_auth = client(&idp, CLIENT_ID)
+        .await
+        .authorize_device()
+        .await
+        .unwrap();
+    assert_eq!(device_auth.qr_code(), None);
+    let instructions = device_auth
+        .instructions(
+            &pam_oauth2_df::Messages::default(),
+            Some(&QrOptions::default()),
+        )
+        .unwrap();
+    assert!(instructions.contains(device_auth.login_uri()));
+    assert!(!instructions.contains('\u{2588}'));
+}
+
+#[tokio::test]
+async fn falls_back_to_userinfo_without_id_token() {
+    let idp = MockIdp::start(Scenario {
+        omit_id_token: true,
+        ..Scenario::default()
+    });
+    let token = run(&idp).await.unwrap();
+    let claims = client(&idp, CLIENT_ID).await.claims(&token).await.unwrap();
+    assert_eq!(claims["preferred_username"], "alice");
+}
+
+#[tokio::test]
+async fn introspects_opaque_access_token() {
+    let idp = MockIdp::start(Scenario {
+        omit_id_token: true,
+        ..Scenario::default()
+    });
+    let mut endpoints = Endpoints::discover(idp.url()).await.unwrap();
+    endpoints.introspection_url = Some(format!("{}/introspect", idp.url()));
+    let client = DeviceFlowClient::new(endpoints, CLIENT_ID);
+    let mut token = run(&idp).await.unwrap();
+    assert_eq!(client.claims(&token).await.unwrap()["username"], "alice");
+
+    token.access_token = "revoked".to_string();
+    assert!(matches!(client.claims(&token).await, Err(Error::Token(_))));
+}
+
+#[tokio::test]
+async fn sends_requests_through_proxy() {
+    let idp = MockIdp::start(Scenario::default());
+    // The mock also answers proxy requests for its own URL.
+    let http = HttpConfig::default().with_proxy(idp.url()).unwrap();
+    let client = DeviceFlowClient::new(
+        Endpoints::discover_with(idp.url(), &http).await.unwrap(),
+        CLIENT_ID,
+    )
+    .with_http(http);
+    let device_auth = client.authorize_device().await.unwrap();
+    let token = client.poll_token(&device_auth).await.unwrap();
+    assert!(client.verify_id_token(&token).await.is_ok());
+    assert!(idp.proxied() >= 4);
+}
+
+#[tokio::test]
+async fn max_auth_time_ends_polling() {
+    let idp = MockIdp::start(Scenario {
+        replies: vec![TokenReply::Pending],
+        interval: 1,
+        ..Scenario::default()
+    });
+    let client = client(&idp, CLIENT_ID)
+        .await
+        .with_max_auth_time(Duration::from_secs(2));
+    let started = Instant::now();
+    let device_auth = client.authorize_device().await.unwrap();
+    match client.poll_token(&device_auth).await {
+        Err(Error::IdP { error, .. }) => assert_eq!(error, "expired_token"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn retries_unavailable_endpoint() {
+    let idp = MockIdp::start(Scenario {
+        unavailable: 2,
+        ..Scenario::default()
+    });
+    assert!(run(&idp).await.is_ok());
+
+    let idp = MockIdp::start(Scenario {
+        unavailable: 1,
+        ..Scenario::default()
+    });
+    let client = client(&idp, CLIENT_ID)
+        .await
+        .with_http(HttpConfig::default().with_retries(0));
+    assert!(client.authorize_device().await.is_err());
+}
+
+#[tokio::test]
+async fn unresponsive_provider_times_out() {
+    // Connections are accepted by the kernel but never answered.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let http = HttpConfig::default().with_timeout(Duration::from_secs(1));
+    let started = Instant::now();
+    assert!(matches!(
+        Endpoints::discover_with(&url, &http).await,
+        Err(Error::Network(_))
+    ));
+    assert!(started.elapsed() < Duration::from_secs(10));
+}
+
+/// URL at which nothing listens, so connections are refused.
+fn unreachable_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("http://{}", listener.local_addr().unwrap())
+}
+
+fn provider(name: &str, issuer: &str) -> ProviderConfig {
+    ProviderConfig {
+        name: name.to_string(),
+        issuer: Some(issuer.to_string()),
+        ..ProviderConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn falls_back_to_next_provider() {
+    let idp = MockIdp::start(Scenario::default());
+    let config = Config {
+        client_id: CLIENT_ID.to_string(),
+        providers: vec![
+            provider("corp", &unreachable_url()),
+            ProviderConfig {
+                client_id: Some("backup-client".to_string()),
+                ..provider("backup", idp.url())
+            },
+        ],
+        http_retries: 0,
+        ..Config::default()
+    };
+    let (config, client) = DeviceFlowClient::from_providers(&config).await.unwrap();
+    assert_eq!(config.provider.as_deref(), Some("backup"));
+    assert_eq!(config.client_id, "backup-client");
+    assert_eq!(client.endpoints().token_url, format!("{}/token", idp.url()));
+}
+
+#[tokio::test]
+async fn skips_provider_without_discovery_document() {
+    let idp = MockIdp::start(Scenario::default());
+    let config = Config {
+        client_id: CLIENT_ID.to_string(),
+        providers: vec![
+            // The mock answers 404 below this path.
+            provider("corp", &format!("{}/missing", idp.url())),
+            provider("backup", idp.url()),
+        ],
+        http_retries: 0,
+        ..Config::default()
+    };
+    let (chosen, _) = DeviceFlowClient::from_providers(&config).await.unwrap();
+    assert_eq!(chosen.provider.as_deref(), Some("backup"));
+    // Without another provider, the misconfiguration is what is reported.
+    let config = Config {
+        providers: vec![provider("corp", &format!("{}/missing", idp.url()))],
+        ..config
+    };
+    let err = DeviceFlowClient::from_providers(&config)
+        .await
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("404"), "{}", err);
+}
+
+#[tokio::test]
+async fn selects_provider_by_name() {
+    let idp = MockIdp::start(Scenario::default());
+    let mut config = Config {
+        client_id: CLIENT_ID.to_string(),
+        providers: vec![
+            provider("corp", idp.url()),
+            provider("backup", &unreachable_url()),
+        ],
+        provider: Some("backup".to_string()),
+        http_retries: 0,
+        ..Config::default()
+    };
+    assert!(matches!(
+        DeviceFlowClient::from_providers(&config).await,
+        Err(Error::Network(_))
+    ));
+    config.provider = Some("other".to_string());
+    assert!(matches!(
+        config.provider_configs(),
+        Err(Error::Config(message)) if message == "unknown provider: other"
+    ));
+}
+
+#[tokio::test]
+async fn assigns_providers_by_user_and_group() {
+    let config = Config {
+        providers: vec![
+            provider("corp", "https://corp.example.com"),
+            ProviderConfig {
+                users: vec!["carol".to_string()],
+                groups: vec!["contractors".to_string()],
+                ..provider("tenant", "https://tenant.example.com")
+            },
+        ],
+        ..Config::default()
+    };
+    let names = |user: &str, groups: &[&str]| {
+        let groups: Vec<_> = groups.iter().map(|group| group.to_string()).collect();
+        config
+            .for_user(user, &groups)
+            .unwrap()
+            .providers
+            .into_iter()
+            .map(|provider| provider.name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names("alice", &["staff"]), ["corp"]);
+    assert_eq!(names("bob", &["staff", "contractors"]), ["tenant"]);
+    assert_eq!(names("carol", &[]), ["tenant"]);
+
+    let restricted = Config {
+        providers: config.providers[1..].to_vec(),
+        ..Config::default()
+    };
+    assert!(matches!(
+        restricted.for_user("alice", &[]),
+        Err(Error::Authorization(_))
+    ));
+}
+
+#[tokio::test]
+async fn rejects_id_token_for_another_client() {
+    let idp = MockIdp::start(Scenario::default());
+    let token = run(&idp).await.unwrap();
+    assert!(matches!(
+        client(&idp, "other-client")
+            .await
+            .verify_id_token(&token)
+            .await,
+        Err(Error::Token(_))
+    ));
+}
+
+#[tokio::test]
+async fn accepts_configured_audience() {
+    let idp = MockIdp::start(Scenario::default());
+    let token = run(&idp).await.unwrap();
+    let client = client(&idp, "other-client").await.with_audience(CLIENT_ID);
+    assert!(client.verify_id_token(&token).await.is_ok());
+}
+
+#[tokio::test]
+async fn rejects_id_token_from_another_issuer() {
+    let idp = MockIdp::start(Scenario {
+        issuer_path: "/realms/other",
+        ..Scenario::default()
+    });
+    let token = run(&idp).await.unwrap();
+    assert!(matches!(
+        client(&idp, CLIENT_ID).await.verify_id_token(&token).await,
+        Err(Error::Token(_))
+    ));
+}
+
+#[tokio::test]
+async fn rejects_discovery_for_another_issuer() {
+    let idp = MockIdp::start(Scenario::default());
+    assert!(matches!(
+        Endpoints::discover(&format!("{}/realms/other", idp.url())).await,
+        Err(Error::Config(_))
+    ));
+}
+
+#[tokio::test]
+async fn rejects_expired_id_token() {
+    let idp = MockIdp::start(Scenario {
+        issued_at: -400,
+        ..Scenario::default()
+    });
+    let token = run(&idp).await.unwrap();
+    assert!(matches!(
+        client(&idp, CLIENT_ID).await.verify_id_token(&token).await,
+        Err(Error::Token(_))
+    ));
+    let client = client(&idp, CLIENT_ID)
+        .await
+        .with_clock_skew(Duration::from_secs(200));
+    assert!(client.verify_id_token(&token).await.is_ok());
+}
+
+#[tokio::test]
+async fn rejects_id_token_issued_in_the_future() {
+    let idp = MockIdp::start(Scenario {
+        issued_at: 120,
+        ..Scenario::default()
+    });
+    let token = run(&idp).await.unwrap();
+    assert!(matches!(
+        client(&idp, CLIENT_ID).await.verify_id_token(&token).await,
+        Err(Error::Token(_))
+    ));
+}
+
+#[tokio::test]
+async fn ciba_polls_with_auth_req_id() {
+    let idp = MockIdp::start(Scenario {
+        replies: vec![TokenReply::Pending, TokenReply::SlowDown, TokenReply::Issue],
+        ..Scenario::default()
+    });
+    let client = client(&idp, CLIENT_ID).await;
+    let auth = client.authorize_backchannel("alice", None).await.unwrap();
+    assert_eq!(auth.auth_req_id, "auth-req");
+    let token = client.poll_backchannel_token(&auth).await.unwrap();
+    assert_eq!(token.access_token, "access-token");
+    assert_eq!(idp.polls().len(), 3);
+}
+
+#[tokio::test]
+async fn exchanges_access_token_for_audience() {
+    let idp = MockIdp::start(Scenario::default());
+    let token = run(&idp).await.unwrap();
+    let exchanged = client(&idp, CLIENT_ID)
+        .await
+        .exchange_token(
+            &token,
+            Some("https://api.example.com"),
+            &["read".to_string()],
+        )
+        .await
+        .unwrap();
+    assert_eq!(exchanged.access_token, "exchanged-token");
+    assert_eq!(exchanged.expires_in, Some(60));
+    assert_eq!(exchanged.id_token, token.id_token);
+    assert_eq!(exchanged.refresh_token, token.refresh_token);
+    let request = idp.last_body("/token").unwrap();
+    assert!(request.ends_with("&audience=https%3A%2F%2Fapi.example.com&scope=read"));
+}
+
+#[tokio::test]
+async fn access_denied_ends_flow() {
+    let idp = MockIdp::start(Scenario {
+        replies: vec![TokenReply::Pending, TokenReply::Denied],
+        ..Scenario::default()
+    });
+    match run(&idp).await {
+        Err(Error::IdP { error, .. }) => assert_eq!(error, "access_denied"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(idp.polls().len(), 2);
+}
+
+#[tokio::test]
+async fn device_code_expires_while_pending() {
+    let idp = MockIdp::start(Scenario {
+        replies: vec![TokenReply::Pending],
+        expires_in: 1,
+        interval: 1,
+        ..Scenario::default()
+    });
+    match run(&idp).await {
+        Err(Error::IdP { error, .. }) => assert_eq!(error, "expired_token"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+/// Empty directory for a disk cache, removed by the caller.
+fn cache_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("pam_oauth2_df-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[tokio::test]
+async fn caches_jwks_between_clients() {
+    let idp = MockIdp::start(Scenario::default());
+    let dir = cache_dir("jwks");
+    let token = run(&idp).await.unwrap();
+    for _ in 0..2 {
+        let client = client(&idp, CLIENT_ID)
+            .await
+            .with_jwks_cache(&dir, Duration::from_secs(300));
+        client.verify_id_token(&token).await.unwrap();
+    }
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(idp.requests("/jwks"), 1);
+}
+
+#[tokio::test]
+async fn uses_stale_jwks_while_provider_is_down() {
+    let idp = MockIdp::start(Scenario::default());
+    let dir = cache_dir("stale-jwks");
+    let token = run(&idp).await.unwrap();
+    let client = client(&idp, CLIENT_ID)
+        .await
+        .with_http(HttpConfig::default().with_retries(0))
+        .with_jwks_cache(&dir, Duration::ZERO);
+    client.verify_id_token(&token).await.unwrap();
+    idp.stop();
+    let claims = client.verify_id_token(&token).await;
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(claims.unwrap()["preferred_username"], "alice");
+    assert_eq!(idp.requests("/jwks"), 1);
+}
+
+#[tokio::test]
+async fn caches_discovery_document() {
+    let idp = MockIdp::start(Scenario::default());
+    let dir = cache_dir("discovery");
+    let config = |idp: &MockIdp| {
+        let dir = dir.display().to_string();
+        let args = [
+            ("issuer", idp.url()),
+            ("client_id", CLIENT_ID),
+            ("cache_dir", &dir),
+            ("discovery_cache_ttl", "1"),
+            ("http_retries", "0"),
+        ];
+        Config::from_args(&args.into_iter().collect()).unwrap()
+    };
+    DeviceFlowClient::from_config(&config(&idp)).await.unwrap();
+    DeviceFlowClient::from_config(&config(&idp)).await.unwrap();
+    let fetched = idp.requests("/.well-known/openid-configuration");
+    // Past the TTL, the stale document stands in for the unreachable issuer.
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    idp.stop();
+    let stale = DeviceFlowClient::from_config(&config(&idp)).await;
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(fetched, 1);
+    assert!(stale.is_ok(), "{:?}", stale.err());
+}
+
+#[tokio::test]
+async fn sends_dpop_proofs_with_provider_nonce() {
+    let idp = MockIdp::start(Scenario {
+        dpop_nonce: Some("nonce-1"),
+        ..Scenario::default()
+    });
+    let key = DpopKey::generate().unwrap();
+    let jwk = key.jwk();
+    let client = client(&idp, CLIENT_ID).await.with_dpop(key);
+    let device_auth = client.authorize_device().await.unwrap();
+    client.poll_token(&device_auth).await.unwrap();
+
+    let proofs = idp.dpop_proofs();
+    assert!(!proofs.is_empty());
+    let key =
+        DecodingKey::from_ec_components(jwk["x"].as_str().unwrap(), jwk["y"].as_str().unwrap())
+            .unwrap();
+    let mut validation = Validation::new(Algorithm::ES256);
+    validation.required_spec_claims.clear();
+    validation.validate_exp = false;
+    for (path, proof) in &proofs {
+        assert_eq!(path, "/token");
+        let header = decode_header(proof).unwrap();
+        assert_eq!(header.typ.as_deref(), Some("dpop+jwt"));
+        let claims = decode::<Value>(proof, &key, &validation).unwrap().claims;
+        assert_eq!(claims["htm"], "POST");
+        assert_eq!(claims["htu"], client.endpoints().token_url);
+    }
+    // The first proof is answered with the nonce, which all the later ones carry.
+    let nonces: Vec<Value> = proofs
+        .iter()
+        .map(|(_, proof)| {
+            decode::<Value>(proof, &key, &validation).unwrap().claims["nonce"].clone()
+        })
+        .collect();
+    assert_eq!(nonces[0], Value::Null);
+    assert!(
+        nonces[1..].iter().all(|nonce| nonce == "nonce-1"),
+        "{:?}",
+        nonces
+    );
+}
+
+#[tokio::test]
+async fn sends_new_dpop_proof_on_retry() {
+    let idp = MockIdp::start(Scenario {
+        token_unavailable: 1,
+        ..Scenario::default()
+    });
+    let key = DpopKey::generate().unwrap();
+    let jwk = key.jwk();
+    let client = client(&idp, CLIENT_ID).await.with_dpop(key);
+    let device_auth = client.authorize_device().await.unwrap();
+    client.poll_token(&device_auth).await.unwrap();
+
+    let key =
+        DecodingKey::from_ec_components(jwk["x"].as_str().unwrap(), jwk["y"].as_str().unwrap())
+            .unwrap();
+    let mut validation = Validation::new(Algorithm::ES256);
+    validation.required_spec_claims.clear();
+    validation.validate_exp = false;
+    // The proof of the request answered with 503 must not be sent again.
+    let jtis: Vec<Value> = idp
+        .dpop_proofs()
+        .iter()
+        .map(|(_, proof)| decode::<Value>(proof, &key, &validation).unwrap().claims["jti"].clone())
+        .collect();
+    assert!(jtis.len() > 1);
+    assert!(
+        jtis.iter()
+            .enumerate()
+            .all(|(i, jti)| !jtis[..i].contains(jti)),
+        "{:?}",
+        jtis
+    );
+}
+
+#[tokio::test]
+async fn keeps_dpop_key_between_loads() {
+    let dir = cache_dir("dpop");
+    let path = dir.join("dpop.key");
+    let jwk = DpopKey::load_or_generate(&path).unwrap().jwk();
+    let reloaded = DpopKey::load_or_generate(&path).unwrap().jwk();
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(jwk, reloaded);
+}
+
+/// A P-256 PKCS#8 key in `dir`, with its public point for verifying the signatures.
+fn client_key(dir: &PathBuf) -> (PathBuf, Vec<u8>) {
+    let pkcs8 =
+        EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+            .unwrap();
+    let key_pair = EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_FIXED_SIGNING,
+        pkcs8.as_ref(),
+        &SystemRandom::new(),
+    )
+    .unwrap();
+    fs::create_dir_all(dir).unwrap();
+    let path = dir.join("client.der");
+    fs::write(&path, pkcs8.as_ref()).unwrap();
+    (path, key_pair.public_key().as_ref().to_vec())
+}
+
+/// Runs the flow with `assertion` and checks the client assertion of the last token request
+/// against `public_key`.
+async fn assert_signed_by(assertion: ClientAssertion, public_key: &[u8]) {
+    let idp = MockIdp::start(Scenario::default());
+    let client = client(&idp, CLIENT_ID)
+        .await
+        .with_client_assertion(assertion);
+    let device_auth = client.authorize_device().await.unwrap();
+    client.poll_token(&device_auth).await.unwrap();
+
+    let body = idp.last_body("/token").unwrap();
+    let form: Vec<(String, String)> = form_urlencoded::parse(body.as_bytes())
+        .into_owned()
+        .collect();
+    let param = |name: &str| {
+        form.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+            .unwrap_or_else(|| panic!("{} missing in {}", name, body))
+    };
+    assert_eq!(
+        param("client_assertion_type"),
+        "urn:ietf:params:oauth:client-assertion-type:jwt-bearer"
+    );
+    let mut validation = Validation::new(Algorithm::ES256);
+    validation.set_audience(&[&client.endpoints().token_url]);
+    let assertion = param("client_assertion");
+    let header = decode_header(&assertion).unwrap();
+    assert_eq!(header.kid.as_deref(), Some("key-1"));
+    let claims = decode::<Value>(
+        &assertion,
+        &DecodingKey::from_ec_der(public_key),
+        &validation,
+    )
+    .unwrap()
+    .claims;
+    assert_eq!(claims["iss"], CLIENT_ID);
+    assert_eq!(claims["sub"], CLIENT_ID);
+}
+
+#[tokio::test]
+async fn authenticates_with_private_key_jwt() {
+    let dir = cache_dir("client-key");
+    let (path, public_key) = client_key(&dir);
+    let assertion = ClientAssertion::from_file(path.to_str().unwrap(), Some("key-1")).unwrap();
+    assert_signed_by(assertion, &public_key).await;
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn signs_client_assertion_in_tpm() {
+    let dir = cache_dir("client-key-tpm");
+    let (path, public_key) = client_key(&dir);
+    let pem = dir.join("client.pem");
+    let status = Command::new("openssl")
+        .args(["pkey", "-inform", "DER", "-in"])
+        .arg(&path)
+        .arg("-out")
+        .arg(&pem)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    // Stands in for tpm2_sign, writing the DER signature of stdin as it does with -f plain.
+    let tpm2_sign = dir.join("tpm2_sign");
+    fs::write(
+        &tpm2_sign,
+        format!(
+            "#!/bin/sh\n[ \"$2\" = 0x81000001 ] || exit 1\nexec openssl dgst -sha256 -sign {}\n",
+            pem.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&tpm2_sign, fs::Permissions::from_mode(0o755)).unwrap();
+    let assertion = ClientAssertion::tpm("0x81000001", tpm2_sign.to_str().unwrap(), Some("key-1"));
+    assert_signed_by(assertion, &public_key).await;
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn sends_configured_user_agent_and_headers() {
+    let idp = MockIdp::start(Scenario::default());
+    let args = [
+        ("issuer", idp.url()),
+        ("client_id", CLIENT_ID),
+        ("user_agent", "corp-sshd/1.0"),
+        ("http_headers", "X-Api-Key=k3y=,X-Tenant=ops"),
+    ];
+    let config = Config::from_args(&args.into_iter().collect()).unwrap();
+    let client = DeviceFlowClient::from_config(&config).await.unwrap();
+    let device_auth = client.authorize_device().await.unwrap();
+    client.poll_token(&device_auth).await.unwrap();
+
+    for path in ["/.well-known/openid-configuration", "/device", "/token"] {
+        assert_eq!(
+            idp.last_header(path, "user-agent").as_deref(),
+            Some("corp-sshd/1.0")
+        );
+        assert_eq!(idp.last_header(path, "x-api-key").as_deref(), Some("k3y="));
+        assert_eq!(idp.last_header(path, "x-tenant").as_deref(), Some("ops"));
+    }
+}
+
+#[test]
+fn rejects_tpm_key_for_mutual_tls() {
+    let args = [
+        ("issuer", "https://idp.example"),
+        ("client_id", CLIENT_ID),
+        ("tls_client_cert", "/etc/pam_oauth2/client.pem"),
+        ("tls_client_key", "tpm:0x81000001"),
+    ];
+    assert!(matches!(
+        Config::from_args(&args.into_iter().collect()),
+        Err(Error::Config(message)) if message.contains("TPM")
+    ));
+}
+
+#[tokio::test]
+async fn rejects_invalid_http_header() {
+    let idp = MockIdp::start(Scenario::default());
+    let args = [
+        ("issuer", idp.url()),
+        ("client_id", CLIENT_ID),
+        ("http_headers", "Bad Name=value"),
+    ];
+    let config = Config::from_args(&args.into_iter().collect()).unwrap();
+    assert!(DeviceFlowClient::from_config(&config).await.is_err());
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use common::{MockIdp, Scenario};
+use pam_oauth2_df::{DeviceAuthResponse, Email, HttpConfig, Notification, Notifier, Webhook};
+use serde_json::Value;
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    thread,
+};
+
+fn device_auth() -> DeviceAuthResponse {
+    serde_json::from_value(serde_json::json!({
+        "device_code": "device-code",
+        "user_code": "ABCD-EFGH",
+        "verification_uri": "https://idp.example.com/activate",
+        "verification_uri_complete": "https://idp.example.com/activate?user_code=ABCD-EFGH",
+        "expires_in": 600,
+        "interval": 5,
+    }))
+    .unwrap()
+}
+
+/// Plays an SMTP relay for one message and returns the session as the client sent it.
+fn smtp_relay(listener: TcpListener, reject_recipient: bool) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut session = String::new();
+        writer.write_all(b"220 mx.example.com ESMTP\r\n").unwrap();
+        let mut data = false;
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 {
+            session += &line;
+            let reply: &[u8] = if data {
+                if line != ".\r\n" {
+                    line.clear();
+                    continue;
+                }
+                data = false;
+                b"250 queued\r\n"
+            } else if line.starts_with("EHLO") {
+                b"250-mx.example.com\r\n250 8BITMIME\r\n"
+            } else if line.starts_with("RCPT") && reject_recipient {
+                b"550 no such user\r\n"
+            } else if line.starts_with("DATA") {
+                data = true;
+                b"354 go ahead\r\n"
+            } else if line.starts_with("QUIT") {
+                writer.write_all(b"221 bye\r\n").unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            writer.write_all(reply).unwrap();
+            line.clear();
+        }
+        session
+    })
+}
+
+#[tokio::test]
+async fn webhook_posts_notification_for_user() {
+    let idp = MockIdp::start(Scenario::default());
+    let device_auth = device_auth();
+    Webhook::new(
+        &format!("{}/hooks/{{user}}", idp.url()),
+        HttpConfig::default(),
+    )
+    .notify(&Notification::new("alice", &device_auth))
+    .await
+    .unwrap();
+    let webhooks = idp.webhooks();
+    assert_eq!(webhooks.len(), 1);
+    assert_eq!(webhooks[0].0, "/hooks/alice");
+    let body: Value = serde_json::from_str(&webhooks[0].1).unwrap();
+    assert_eq!(body["user"], "alice");
+    assert_eq!(body["url"], device_auth.login_uri());
+    assert_eq!(body["user_code"], "ABCD-EFGH");
+}
+
+#[tokio::test]
+async fn webhook_keeps_user_within_its_part_of_url() {
+    let idp = MockIdp::start(Scenario::default());
+    let webhook = Webhook::new(
+        &format!("{}/hooks/{{user}}", idp.url()),
+        HttpConfig::default(),
+    );
+    for user in ["../admin?x=", "a@evil/"] {
+        webhook
+            .notify(&Notification::new(user, &device_auth()))
+            .await
+            .unwrap();
+    }
+    assert!(webhook
+        .notify(&Notification::new("..", &device_auth()))
+        .await
+        .is_err());
+    let paths: Vec<_> = idp.webhooks().into_iter().map(|(path, _)| path).collect();
+    assert_eq!(paths, ["/hooks/..%2Fadmin%3Fx%3D", "/hooks/a%40evil%2F"]);
+}
+
+#[tokio::test]
+async fn email_is_sent_through_smtp_relay() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = listener.local_addr().unwrap().to_string();
+    let relay = smtp_relay(listener, false);
+    let email = Email::new(&server, "pam@example.com", "{user}@example.com");
+    email
+        .notify(&Notification::new("alice", &device_auth()))
+        .await
+        .unwrap();
+    let session = relay.join().unwrap();
+    assert!(session.contains("RCPT TO:<alice@example.com>\r\n"));
+    assert!(session.contains("To: alice@example.com\r\n"));
+    assert!(session.contains("https://idp.example.com/activate?user_code=ABCD-EFGH"));
+}
+
+#[tokio::test]
+async fn rejected_email_is_an_error() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = listener.local_addr().unwrap().to_string();
+    let _relay = smtp_relay(listener, true);
+    let email = Email::new(&server, "pam@example.com", "{user}@example.com");
+    let err = email
+        .notify(&Notification::new("alice", &device_auth()))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("550 no such user"), "{}", err);
+}
+
+#[tokio::test]
+async fn refuses_to_email_user_with_line_breaks() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = listener.local_addr().unwrap().to_string();
+    let email = Email::new(&server, "pam@example.com", "{user}@example.com");
+    for user in [
+        "alice>\r\nRCPT TO:<mallory",
+        "alice\nBcc: mallory@example.com",
+    ] {
+        let err = email
+            .notify(&Notification::new(user, &device_auth()))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not a plain address"), "{}", err);
+    }
+    // The relay never saw a connection.
+    listener.set_nonblocking(true).unwrap();
+    assert!(listener.accept().is_err());
+}
@@ -0,0 +1,1598 @@
+//! Drives the built module through libpam with a private configuration directory.
+
+#![cfg(feature = "pam")]
+
+mod common;
+
+use common::{MockIdp, Scenario, TokenReply, CLIENT_ID, SSH_CERT, SSH_KEY};
+use pam::constants::{PamResultCode, PAM_PROMPT_ECHO_OFF, PAM_PROMPT_ECHO_ON};
+use pam_oauth2_df::{authorized_keys, Config};
+use std::{
+    collections::HashMap,
+    env,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    fs, mem,
+    net::TcpListener,
+    os::unix::fs::{symlink, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process, ptr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv: extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "pam")]
+extern "C" {
+    #[cfg(not(any(target_os = "freebsd", target_os = "macos")))]
+    fn pam_start_confdir(
+        service: *const c_char,
+        user: *const c_char,
+        conv: *const PamConv,
+        confdir: *const c_char,
+        pamh: *mut *mut c_void,
+    ) -> c_int;
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    fn pam_start(
+        service: *const c_char,
+        user: *const c_char,
+        conv: *const PamConv,
+        pamh: *mut *mut c_void,
+    ) -> c_int;
+    fn pam_authenticate(pamh: *mut c_void, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(pamh: *mut c_void, flags: c_int) -> c_int;
+    fn pam_open_session(pamh: *mut c_void, flags: c_int) -> c_int;
+    fn pam_close_session(pamh: *mut c_void, flags: c_int) -> c_int;
+    fn pam_chauthtok(pamh: *mut c_void, flags: c_int) -> c_int;
+    fn pam_getenv(pamh: *mut c_void, name: *const c_char) -> *const c_char;
+    fn pam_putenv(pamh: *mut c_void, name_value: *const c_char) -> c_int;
+    fn pam_set_item(pamh: *mut c_void, item_type: c_int, item: *const c_void) -> c_int;
+    fn pam_end(pamh: *mut c_void, status: c_int) -> c_int;
+}
+
+/// What the user does in the conversation.
+struct User {
+    messages: Mutex<Vec<String>>,
+    /// Answer to prompts; `None` leaves the response empty as on end of input.
+    answer: Mutex<Option<CString>>,
+}
+
+/// Records every message and answers prompts with the answer of the `User`.
+extern "C" fn conversation(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    // SAFETY: libpam passes `num_msg` valid messages and the appdata registered in `Pam::start`;
+    // the responses are allocated with malloc because libpam frees them.
+    unsafe {
+        let user = &*(appdata_ptr as *const User);
+        let responses =
+            libc::calloc(num_msg as usize, mem::size_of::<PamResponse>()) as *mut PamResponse;
+        for i in 0..num_msg as usize {
+            let message = &**msg.add(i);
+            user.messages
+                .lock()
+                .unwrap()
+                .push(CStr::from_ptr(message.msg).to_string_lossy().into_owned());
+            if message.msg_style == PAM_PROMPT_ECHO_OFF || message.msg_style == PAM_PROMPT_ECHO_ON {
+                if let Some(answer) = &*user.answer.lock().unwrap() {
+                    (*responses.add(i)).resp = libc::strdup(answer.as_ptr());
+                }
+            }
+        }
+        *resp = responses;
+    }
+    PamResultCode::PAM_SUCCESS as c_int
+}
+
+struct Pam {
+    handle: *mut c_void,
+    confdir: PathBuf,
+    service: String,
+    user: Box<User>,
+    _conv: Box<PamConv>,
+}
+
+impl Pam {
+    /// Starts a transaction for `user` with the module configured against `idp`.
+    fn start(idp: &MockIdp, user: &str, args: &[&str]) -> Self {
+        let issuer = format!("issuer={}", idp.url());
+        let client_id = format!("client_id={}", CLIENT_ID);
+        Self::start_with(user, &[&[issuer.as_str(), &client_id], args].concat())
+    }
+
+    /// Starts a transaction for `user` with the module configured by `args` alone.
+    fn start_with(user: &str, args: &[&str]) -> Self {
+        static SERVICES: AtomicUsize = AtomicUsize::new(0);
+        let service = format!("oauth2-test-{}", SERVICES.fetch_add(1, Ordering::Relaxed));
+        let confdir = env::temp_dir().join(format!("pam_oauth2_df-{}-{}", process::id(), service));
+        fs::create_dir_all(&confdir).unwrap();
+        // English messages whatever the locale of the test run, unless `args` pick a language.
+        let args = format!("lang=C {}", args.join(" "));
+        let module = module_path();
+        fs::write(
+            confdir.join(&service),
+            format!(
+                "auth required {0} {1}\naccount required {0} {1}\nsession required {0} {1}\n\
+                 password required {0} {1}\n",
+                module.display(),
+                args
+            ),
+        )
+        .unwrap();
+
+        let user_data = Box::new(User {
+            messages: Mutex::new(Vec::new()),
+            answer: Mutex::new(Some(CString::default())),
+        });
+        let conv = Box::new(PamConv {
+            conv: conversation,
+            appdata_ptr: &*user_data as *const _ as *mut c_void,
+        });
+        let user = CString::new(user).unwrap();
+        let mut handle = ptr::null_mut();
+        let result = start_transaction(&confdir, &service, &user, &conv, &mut handle);
+        assert_eq!(result, PamResultCode::PAM_SUCCESS as c_int);
+        Self {
+            handle,
+            confdir,
+            service,
+            user: user_data,
+            _conv: conv,
+        }
+    }
+
+    fn authenticate(&self) -> c_int {
+        // SAFETY: `handle` is a live transaction.
+        unsafe { pam_authenticate(self.handle, 0) }
+    }
+
+    fn acct_mgmt(&self) -> c_int {
+        // SAFETY: `handle` is a live transaction.
+        unsafe { pam_acct_mgmt(self.handle, 0) }
+    }
+
+    fn open_session(&self) -> c_int {
+        // SAFETY: `handle` is a live transaction.
+        unsafe { pam_open_session(self.handle, 0) }
+    }
+
+    fn close_session(&self) -> c_int {
+        // SAFETY: `handle` is a live transaction.
+        unsafe { pam_close_session(self.handle, 0) }
+    }
+
+    fn chauthtok(&self) -> c_int {
+        // SAFETY: `handle` is a live transaction.
+        unsafe { pam_chauthtok(self.handle, 0) }
+    }
+
+    fn getenv(&self, name: &str) -> Option<String> {
+        let name = CString::new(name).unwrap();
+        // SAFETY: `handle` is a live transaction; the returned string belongs to it and is
+        // copied before the next call.
+        let value = unsafe { pam_getenv(self.handle, name.as_ptr()) };
+        // SAFETY: checked for NULL; libpam returns a NUL-terminated string.
+        (!value.is_null()).then(|| {
+            unsafe { CStr::from_ptr(value) }
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+
+    fn putenv(&self, name_value: &str) {
+        let name_value = CString::new(name_value).unwrap();
+        // SAFETY: `handle` is a live transaction; libpam copies the string.
+        let result = unsafe { pam_putenv(self.handle, name_value.as_ptr()) };
+        assert_eq!(result, code(PamResultCode::PAM_SUCCESS));
+    }
+
+    /// Sets the remote host, as sshd does for its logins.
+    fn set_rhost(&self, rhost: &str) {
+        /// `PAM_RHOST` of `<security/_pam_types.h>`.
+        const PAM_RHOST: c_int = 4;
+        let rhost = CString::new(rhost).unwrap();
+        // SAFETY: `handle` is a live transaction; libpam copies the string.
+        let result = unsafe { pam_set_item(self.handle, PAM_RHOST, rhost.as_ptr().cast()) };
+        assert_eq!(result, code(PamResultCode::PAM_SUCCESS));
+    }
+
+    fn messages(&self) -> Vec<String> {
+        self.user.messages.lock().unwrap().clone()
+    }
+
+    fn answer(&self, answer: Option<&str>) {
+        *self.user.answer.lock().unwrap() = answer.map(|answer| CString::new(answer).unwrap());
+    }
+}
+
+impl Drop for Pam {
+    fn drop(&mut self) {
+        // SAFETY: `handle` is a live transaction and is not used afterwards.
+        unsafe { pam_end(self.handle, PamResultCode::PAM_SUCCESS as c_int) };
+        let _ = fs::remove_dir_all(&self.confdir);
+    }
+}
+
+/// Starts a transaction of `service`, whose policy is in `confdir`.
+#[cfg(not(any(target_os = "freebsd", target_os = "macos")))]
+fn start_transaction(
+    confdir: &Path,
+    service: &str,
+    user: &CStr,
+    conv: &PamConv,
+    handle: &mut *mut c_void,
+) -> c_int {
+    let service = CString::new(service).unwrap();
+    let confdir = CString::new(confdir.to_str().unwrap()).unwrap();
+    // SAFETY: all strings are NUL-terminated and `conv` outlives the handle.
+    unsafe {
+        pam_start_confdir(
+            service.as_ptr(),
+            user.as_ptr(),
+            conv,
+            confdir.as_ptr(),
+            handle,
+        )
+    }
+}
+
+/// Starts a transaction of `service`, whose policy is in `confdir`. OpenPAM has no
+/// `pam_start_confdir`, but takes a service name with a slash for the path of its policy.
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+fn start_transaction(
+    confdir: &Path,
+    service: &str,
+    user: &CStr,
+    conv: &PamConv,
+    handle: &mut *mut c_void,
+) -> c_int {
+    let policy = CString::new(confdir.join(service).to_str().unwrap()).unwrap();
+    // SAFETY: all strings are NUL-terminated and `conv` outlives the handle.
+    unsafe { pam_start(policy.as_ptr(), user.as_ptr(), conv, handle) }
+}
+
+/// The cdylib that cargo builds for the tests next to the test binary in `target/<profile>/deps`.
+fn module_path() -> PathBuf {
+    env::current_exe()
+        .unwrap()
+        .with_file_name(format!("libpam_oauth2_df{}", env::consts::DLL_SUFFIX))
+}
+
+#[cfg(not(any(target_os = "freebsd", target_os = "macos")))]
+fn code(code: PamResultCode) -> c_int {
+    code as c_int
+}
+
+/// The OpenPAM number of the results the tests expect.
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+fn code(code: PamResultCode) -> c_int {
+    match code {
+        PamResultCode::PAM_SUCCESS => 0,
+        PamResultCode::PAM_SERVICE_ERR => 3,
+        PamResultCode::PAM_SYSTEM_ERR => 4,
+        PamResultCode::PAM_PERM_DENIED => 7,
+        PamResultCode::PAM_MAXTRIES => 8,
+        PamResultCode::PAM_AUTH_ERR => 9,
+        PamResultCode::PAM_AUTHINFO_UNAVAIL => 12,
+        PamResultCode::PAM_AUTHTOK_ERR => 20,
+        PamResultCode::PAM_IGNORE => 25,
+        PamResultCode::PAM_ABORT => 26,
+        code => panic!("no OpenPAM number for {:?}", code),
+    }
+}
+
+#[test]
+fn authenticates_after_pending() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &[]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.acct_mgmt(), code(PamResultCode::PAM_SUCCESS));
+    assert!(pam
+        .messages()
+        .iter()
+        .any(|message| message.contains("/activate?user_code=ABCD-EFGH")));
+}
+
+#[test]
+fn authenticates_with_userinfo_claims() {
+    let idp = MockIdp::start(Scenario {
+        omit_id_token: true,
+        ..Scenario::default()
+    });
+    let pam = Pam::start(&idp, "alice", &[]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.acct_mgmt(), code(PamResultCode::PAM_SUCCESS));
+}
+
+#[test]
+fn takes_username_from_userinfo_field() {
+    let idp = MockIdp::start(Scenario {
+        omit_id_token: true,
+        ..Scenario::default()
+    });
+    let pam = Pam::start(&idp, "user-1", &["userinfo_username_field=/sub"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.acct_mgmt(), code(PamResultCode::PAM_SUCCESS));
+}
+
+#[test]
+fn reads_overage_groups_from_groups_url() {
+    let idp = MockIdp::start(Scenario {
+        groups_overage: true,
+        ..Scenario::default()
+    });
+    let groups_url = format!("groups_url={}/groups", idp.url());
+    let login = |args: &[&str]| {
+        let pam = Pam::start(&idp, "alice", args);
+        (pam.authenticate(), pam.acct_mgmt())
+    };
+    let success = code(PamResultCode::PAM_SUCCESS);
+    assert_eq!(
+        login(&[&groups_url, "required_groups=0003"]),
+        (success, success)
+    );
+    assert_eq!(
+        login(&[
+            &groups_url,
+            "groups_field=displayName",
+            "required_groups=admins"
+        ]),
+        (success, success)
+    );
+    assert_eq!(
+        login(&["required_groups=users"]).0,
+        code(PamResultCode::PAM_PERM_DENIED)
+    );
+}
+
+#[test]
+fn takes_first_present_of_username_claims() {
+    let idp = MockIdp::start(Scenario::default());
+    let login = |user: &str, claims: &str| {
+        Pam::start(&idp, user, &[&format!("username_claims={}", claims)]).authenticate()
+    };
+    let success = code(PamResultCode::PAM_SUCCESS);
+    assert_eq!(
+        login("alice@example.com", "upn,email,preferred_username"),
+        success
+    );
+    assert_eq!(login("alice", "upn,preferred_username,email"), success);
+    assert_eq!(
+        login("alice", "upn,unique_name"),
+        code(PamResultCode::PAM_AUTH_ERR)
+    );
+}
+
+#[test]
+fn matches_user_by_email() {
+    let idp = MockIdp::start(Scenario::default());
+    let email_map = env::temp_dir().join(format!("pam_oauth2_df-{}-emails", process::id()));
+    fs::write(&email_map, "# user email\nbob ALICE@example.com\n").unwrap();
+    let args = [
+        "match_by=email",
+        &format!("email_map={}", email_map.display()),
+    ];
+    let bob = Pam::start(&idp, "bob", &args);
+    let results = (bob.authenticate(), bob.acct_mgmt());
+    let carol = Pam::start(&idp, "carol", &args).authenticate();
+    let _ = fs::remove_file(&email_map);
+    let success = code(PamResultCode::PAM_SUCCESS);
+    assert_eq!(results, (success, success));
+    assert_eq!(carol, code(PamResultCode::PAM_AUTH_ERR));
+}
+
+#[test]
+fn compares_usernames_case_insensitively_and_normalized() {
+    let login = |username: &'static str, args: &[&str]| {
+        let idp = MockIdp::start(Scenario {
+            username,
+            ..Scenario::default()
+        });
+        Pam::start(&idp, "alice", args).authenticate()
+    };
+    let success = code(PamResultCode::PAM_SUCCESS);
+    assert_eq!(login("Alice", &["ignore_case"]), success);
+    assert_eq!(login("Alice", &[]), code(PamResultCode::PAM_AUTH_ERR));
+    // Full-width letters, as some input methods produce them.
+    assert_eq!(
+        login("ＡＬＩＣＥ", &["ignore_case", "normalize_username=nfkc"]),
+        success
+    );
+}
+
+#[test]
+fn transforms_username_claim() {
+    let login = |username: &'static str, user: &str, args: &[&str]| {
+        let idp = MockIdp::start(Scenario {
+            username,
+            ..Scenario::default()
+        });
+        Pam::start(&idp, user, args).authenticate()
+    };
+    let success = code(PamResultCode::PAM_SUCCESS);
+    let args = [
+        "strip_domains=corp.example",
+        "lowercase_username",
+        "username_prefix=ext_",
+    ];
+    assert_eq!(login("Alice@corp.example", "ext_alice", &args), success);
+    assert_eq!(
+        login("alice@other.example", "ext_alice", &args),
+        code(PamResultCode::PAM_AUTH_ERR)
+    );
+    let args = [r"username_regex=^(.+)\.admin$"];
+    assert_eq!(login("alice.admin", "alice", &args), success);
+    assert_eq!(
+        login("alice", "alice", &args),
+        code(PamResultCode::PAM_AUTH_ERR)
+    );
+}
+
+#[test]
+fn slow_down_increases_interval() {
+    let idp = MockIdp::start(Scenario {
+        replies: vec![TokenReply::SlowDown, TokenReply::Issue],
+        ..Scenario::default()
+    });
+    let pam = Pam::start(&idp, "alice", &["no_prompt"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let polls = idp.polls();
+    assert_eq!(polls.len(), 2);
+    assert!(polls[1] - polls[0] >= Duration::from_secs(5));
+}
+
+#[test]
+fn access_denied_fails_authentication() {
+    let idp = MockIdp::start(Scenario {
+        replies: vec![TokenReply::Pending, TokenReply::Denied],
+        ..Scenario::default()
+    });
+    let pam = Pam::start(&idp, "alice", &[]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_AUTH_ERR));
+    assert_eq!(
+        pam.messages().last().unwrap(),
+        "Access was denied by your identity provider."
+    );
+}
+
+#[test]
+fn rejects_token_of_another_user() {
+    let idp = MockIdp::start(Scenario {
+        username: "mallory",
+        ..Scenario::default()
+    });
+    let pam = Pam::start(&idp, "alice", &[]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_AUTH_ERR));
+}
+
+#[test]
+fn missing_group_denies_permission() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &["required_groups=admins"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_PERM_DENIED));
+    assert_eq!(
+        pam.messages().last().unwrap(),
+        "Your account is not allowed to log in to this host."
+    );
+}
+
+#[test]
+fn requires_one_of_required_roles() {
+    let idp = MockIdp::start(Scenario::default());
+    let login = |args: &[&str]| Pam::start(&idp, "alice", args).authenticate();
+    let denied = code(PamResultCode::PAM_PERM_DENIED);
+    assert_eq!(
+        login(&["required_roles=admin,ssh-user"]),
+        code(PamResultCode::PAM_SUCCESS)
+    );
+    assert_eq!(login(&["required_roles=admin"]), denied);
+    assert_eq!(
+        login(&["required_roles=ssh-user", "roles_claims=roles"]),
+        denied
+    );
+}
+
+#[test]
+fn requires_acr_and_amr_of_mfa() {
+    let idp = MockIdp::start(Scenario::default());
+    let login = |args: &[&str]| Pam::start(&idp, "alice", args).authenticate();
+    let denied = code(PamResultCode::PAM_PERM_DENIED);
+    let success = code(PamResultCode::PAM_SUCCESS);
+    assert_eq!(login(&["required_acr=urn:example:mfa"]), success);
+    assert_eq!(login(&["required_acr=urn:example:phr"]), denied);
+    assert_eq!(login(&["required_amr=mfa,otp"]), success);
+    assert_eq!(login(&["required_amr=hwk"]), denied);
+}
+
+#[test]
+fn refuses_authentication_older_than_max_age() {
+    let login = |auth_age: u64| {
+        let idp = MockIdp::start(Scenario {
+            auth_age,
+            ..Scenario::default()
+        });
+        let pam = Pam::start(&idp, "alice", &["max_age=600"]);
+        let result = pam.authenticate();
+        assert!(
+            idp.last_body("/device").unwrap().contains("max_age=600"),
+            "max_age not requested"
+        );
+        result
+    };
+    assert_eq!(login(30), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(login(3600), code(PamResultCode::PAM_AUTH_ERR));
+}
+
+#[test]
+fn invalid_argument_is_a_service_error() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &["no_such_option"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SERVICE_ERR));
+}
+
+#[test]
+fn misconfigured_issuer_is_a_service_error() {
+    let idp = MockIdp::start(Scenario::default());
+    for issuer in [
+        "issuer=idp.example.com".to_string(),
+        "issuer=ftp://idp.example.com".to_string(),
+        // The provider is up, but has no discovery document there.
+        format!("issuer={}/missing", idp.url()),
+    ] {
+        let pam = Pam::start(&idp, "alice", &[&issuer]);
+        assert_eq!(
+            pam.authenticate(),
+            code(PamResultCode::PAM_SERVICE_ERR),
+            "{}",
+            issuer
+        );
+    }
+}
+
+#[test]
+fn unreadable_ca_cert_is_a_service_error() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &["ca_cert=/nonexistent/ca.pem"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SERVICE_ERR));
+}
+
+#[test]
+fn client_cert_without_key_is_a_service_error() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(
+        &idp,
+        "alice",
+        &["tls_client_cert=/etc/pam_oauth2/client.pem"],
+    );
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SERVICE_ERR));
+}
+
+#[test]
+fn no_qr_shows_user_code() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &["no_qr"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let messages = pam.messages();
+    assert!(messages
+        .iter()
+        .any(|message| message.contains("enter the code\n\n    ABCD-EFGH")));
+    assert!(!messages.iter().any(|message| message.contains('\u{2588}')));
+}
+
+#[test]
+fn shows_user_code_without_complete_uri() {
+    let idp = MockIdp::start(Scenario {
+        omit_complete_uri: true,
+        ..Scenario::default()
+    });
+    let pam = Pam::start(&idp, "alice", &[]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert!(pam
+        .messages()
+        .iter()
+        .any(|message| message.contains(&format!(
+            "{}/activate and enter the code\n\n    ABCD-EFGH",
+            idp.url()
+        ))));
+}
+
+#[test]
+fn renders_message_template() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(
+        &idp,
+        "alice",
+        &[
+            "[message_template=Open {url}\\nand confirm {user_code} within {expires_min} minutes]",
+            "prompt=Done?",
+        ],
+    );
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let messages = pam.messages();
+    assert!(messages.contains(&format!(
+        "Open {}/activate?user_code=ABCD-EFGH\nand confirm ABCD-EFGH within 10 minutes",
+        idp.url()
+    )));
+    assert!(messages.contains(&"Done?".to_string()));
+}
+
+#[test]
+fn translates_messages() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &["lang=ja_JP.UTF-8", "no_qr"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert!(pam
+        .messages()
+        .iter()
+        .any(|message| message.contains("次のコードを入力してください")));
+}
+
+#[test]
+fn loads_message_catalog_from_locale_dir() {
+    let idp = MockIdp::start(Scenario::default());
+    let locale_dir = env::temp_dir().join(format!("pam_oauth2_df-{}-locale", process::id()));
+    fs::create_dir_all(&locale_dir).unwrap();
+    fs::write(
+        locale_dir.join("fr.toml"),
+        "prompt = \"Appuyez sur Entrée :\"\n",
+    )
+    .unwrap();
+    let pam = Pam::start(
+        &idp,
+        "alice",
+        &[
+            "lang=fr_FR",
+            &format!("locale_dir={}", locale_dir.display()),
+        ],
+    );
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let messages = pam.messages();
+    let _ = fs::remove_dir_all(&locale_dir);
+    assert!(messages.contains(&"Appuyez sur Entrée :".to_string()));
+    assert!(messages
+        .iter()
+        .any(|message| message.contains("Please login at")));
+}
+
+#[test]
+fn reports_progress_while_polling() {
+    let idp = MockIdp::start(Scenario {
+        replies: vec![TokenReply::Pending, TokenReply::Pending, TokenReply::Issue],
+        interval: 1,
+        ..Scenario::default()
+    });
+    let pam = Pam::start(&idp, "alice", &["no_prompt", "progress_interval=1"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert!(pam
+        .messages()
+        .iter()
+        .any(|message| message.starts_with("Waiting for approval, 9:5")));
+}
+
+#[test]
+fn cancel_at_prompt_aborts() {
+    for answer in [Some("cancel"), None] {
+        let idp = MockIdp::start(Scenario {
+            replies: vec![TokenReply::Pending],
+            interval: 5,
+            ..Scenario::default()
+        });
+        let pam = Pam::start(&idp, "alice", &[]);
+        pam.answer(answer);
+        let started = Instant::now();
+        assert_eq!(pam.authenticate(), code(PamResultCode::PAM_ABORT));
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+}
+
+#[test]
+fn confirm_user_code_checks_code_typed_after_approval() {
+    let idp = MockIdp::start(Scenario::default());
+    let login = |answer: &str| {
+        let pam = Pam::start(&idp, "alice", &["confirm_user_code"]);
+        pam.answer(Some(answer));
+        (pam.authenticate(), pam.messages())
+    };
+    let (result, messages) = login("abcd efgh");
+    assert_eq!(result, code(PamResultCode::PAM_SUCCESS));
+    assert!(messages
+        .iter()
+        .any(|message| message == "Enter the code shown by your identity provider:"));
+    assert_eq!(login("WXYZ-1234").0, code(PamResultCode::PAM_AUTH_ERR));
+}
+
+#[test]
+fn ciba_sends_login_hint_of_pam_user() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(
+        &idp,
+        "alice",
+        &[
+            "flow=ciba",
+            "login_hint={user}@example.com",
+            "binding_message=ssh:{user}",
+        ],
+    );
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let request = idp.last_body("/bc-authorize").unwrap();
+    assert!(request.contains("login_hint=alice%40example.com"));
+    assert!(request.contains("binding_message=ssh%3Aalice"));
+    assert!(pam
+        .messages()
+        .iter()
+        .any(|message| message.contains("approve the login request on your device. ssh:alice")));
+    assert_eq!(idp.polls().len(), 2);
+}
+
+#[test]
+fn notifies_webhook_of_pam_user() {
+    let idp = MockIdp::start(Scenario::default());
+    let webhook = format!("notify_webhook={}/hooks/{{user}}", idp.url());
+    let pam = Pam::start(&idp, "alice", &[&webhook]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let webhooks = idp.webhooks();
+    assert_eq!(webhooks.len(), 1);
+    assert_eq!(webhooks[0].0, "/hooks/alice");
+}
+
+#[test]
+fn falls_back_to_reachable_provider() {
+    let idp = MockIdp::start(Scenario::default());
+    let unreachable = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = env::temp_dir().join(format!("pam_oauth2_df-{}-providers.toml", process::id()));
+    fs::write(
+        &config,
+        format!(
+            "[[providers]]\nname = \"corp\"\nissuer = \"http://{}\"\n\n\
+             [[providers]]\nname = \"backup\"\nissuer = \"{}\"\n",
+            unreachable,
+            idp.url()
+        ),
+    )
+    .unwrap();
+    let pam = Pam::start(
+        &idp,
+        "alice",
+        &[&format!("config={}", config.display()), "http_retries=0"],
+    );
+    let result = pam.authenticate();
+    let _ = fs::remove_file(&config);
+    assert_eq!(result, code(PamResultCode::PAM_SUCCESS));
+}
+
+#[test]
+fn uses_provider_assigned_to_user() {
+    let idp = MockIdp::start(Scenario::default());
+    let unreachable = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = env::temp_dir().join(format!("pam_oauth2_df-{}-users.toml", process::id()));
+    fs::write(
+        &config,
+        format!(
+            "[[providers]]\nname = \"corp\"\nissuer = \"http://{}\"\n\n\
+             [[providers]]\nname = \"tenant\"\nissuer = \"{}\"\nusers = [\"alice\"]\n",
+            unreachable,
+            idp.url()
+        ),
+    )
+    .unwrap();
+    let pam = Pam::start(&idp, "alice", &[&format!("config={}", config.display())]);
+    let result = pam.authenticate();
+    let _ = fs::remove_file(&config);
+    assert_eq!(result, code(PamResultCode::PAM_SUCCESS));
+}
+
+#[test]
+fn sends_auth_params_with_device_request() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(
+        &idp,
+        "alice",
+        &["auth_params=resource=urn:ssh,prompt=login"],
+    );
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let request = idp.last_body("/device").unwrap();
+    assert!(
+        request.ends_with("scope=openid+profile&prompt=login&resource=urn%3Assh"),
+        "{}",
+        request
+    );
+}
+
+#[test]
+fn fills_pam_context_into_auth_params() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(
+        &idp,
+        "alice",
+        &["auth_params=device_description={user}@{rhost}/{service}"],
+    );
+    pam.set_rhost("192.0.2.1");
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let request = idp.last_body("/device").unwrap();
+    let expected = format!("&device_description=alice%40192.0.2.1%2F{}", pam.service);
+    assert!(request.ends_with(&expected), "{}", request);
+}
+
+#[test]
+fn exchanges_token_after_login() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &["exchange_audience=ssh-api"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let request = idp.last_body("/token").unwrap();
+    assert!(
+        request.contains("grant-type%3Atoken-exchange"),
+        "{}",
+        request
+    );
+    assert!(request.contains("&audience=ssh-api"), "{}", request);
+}
+
+#[test]
+fn exports_tokens_to_session_environment() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &["export_tokens"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.open_session(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(
+        pam.getenv("OAUTH2_ACCESS_TOKEN").as_deref(),
+        Some("access-token")
+    );
+    assert!(pam.getenv("OAUTH2_ID_TOKEN").is_some());
+    assert!(pam
+        .getenv("OAUTH2_TOKEN_EXPIRY")
+        .is_some_and(|expiry| expiry.parse::<u64>().is_ok()));
+    assert_eq!(pam.close_session(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.getenv("OAUTH2_ACCESS_TOKEN"), None);
+}
+
+#[test]
+fn exports_claims_to_session_environment() {
+    let idp = MockIdp::start(Scenario::default());
+    let args = ["claim_env=email:OIDC_EMAIL,groups:OIDC_GROUPS,department:OIDC_DEPT"];
+    let pam = Pam::start(&idp, "alice", &args);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.open_session(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(
+        pam.getenv("OIDC_EMAIL").as_deref(),
+        Some("alice@example.com")
+    );
+    assert_eq!(pam.getenv("OIDC_GROUPS").as_deref(), Some("users"));
+    assert_eq!(pam.getenv("OIDC_DEPT"), None);
+    assert_eq!(pam.getenv("OAUTH2_ACCESS_TOKEN"), None);
+    assert_eq!(pam.close_session(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.getenv("OIDC_EMAIL"), None);
+}
+
+#[test]
+fn revokes_tokens_on_logout() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &[]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.close_session(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(idp.requests("/revoke"), 0);
+
+    let pam = Pam::start(&idp, "alice", &["revoke_on_logout"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.open_session(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.close_session(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(idp.requests("/revoke"), 2);
+    let body = idp.last_body("/revoke").unwrap();
+    assert!(
+        body.starts_with("client_id=")
+            && body.ends_with("&token=access-token&token_type_hint=access_token"),
+        "{}",
+        body
+    );
+}
+
+#[test]
+fn ends_provider_session_on_logout() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &["end_session_on_logout"]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.close_session(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(idp.requests("/logout"), 1);
+    assert_eq!(idp.requests("/revoke"), 0);
+    let body = idp.last_body("/logout").unwrap();
+    assert!(
+        body.ends_with(&format!("&client_id={}", CLIENT_ID)),
+        "{}",
+        body
+    );
+}
+
+#[test]
+fn keeps_tokens_out_of_environment_by_default() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &[]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.open_session(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.getenv("OAUTH2_ACCESS_TOKEN"), None);
+}
+
+#[test]
+fn writes_token_file_for_session() {
+    // The file is handed to the user, which only root can do for other users.
+    if fs::metadata("/proc/self").unwrap().uid() != 0 {
+        return;
+    }
+    let idp = MockIdp::start(Scenario {
+        username: "root",
+        ..Scenario::default()
+    });
+    let runtime_dir = env::temp_dir().join(format!("pam_oauth2_df-{}-runtime", process::id()));
+    fs::create_dir_all(&runtime_dir).unwrap();
+    let pam = Pam::start(&idp, "root", &["token_file"]);
+    pam.putenv(&format!("XDG_RUNTIME_DIR={}", runtime_dir.display()));
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(pam.open_session(), code(PamResultCode::PAM_SUCCESS));
+    let path = runtime_dir.join("oauth2/token");
+    let token = fs::read_to_string(&path);
+    let mode = fs::metadata(&path).map(|metadata| metadata.mode() & 0o777);
+    assert_eq!(pam.close_session(), code(PamResultCode::PAM_SUCCESS));
+    let removed = !path.exists();
+    let _ = fs::remove_dir_all(&runtime_dir);
+    assert_eq!(token.unwrap(), "access-token");
+    assert_eq!(mode.unwrap(), 0o600);
+    assert!(removed);
+}
+
+#[test]
+fn installs_ssh_certificate_next_to_key() {
+    // The certificate is handed to the user, which only root can do for other users.
+    if fs::metadata("/proc/self").unwrap().uid() != 0 {
+        return;
+    }
+    let idp = MockIdp::start(Scenario {
+        username: "root",
+        ..Scenario::default()
+    });
+    let dir = env::temp_dir().join(format!("pam_oauth2_df-{}-ssh", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("id_ed25519.pub"),
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKey\n",
+    )
+    .unwrap();
+    // A link could point the module at a file the user cannot read.
+    symlink(dir.join("id_ed25519.pub"), dir.join("id_link.pub")).unwrap();
+    let login = |key: &str| {
+        Pam::start(
+            &idp,
+            "root",
+            &[
+                "ssh_ca=vault",
+                &format!("ssh_ca_url={}", idp.url()),
+                "ssh_ca_role=users",
+                &format!("ssh_public_key={}/{}", dir.display(), key),
+            ],
+        )
+        .authenticate()
+    };
+    let result = login("id_ed25519.pub");
+    let cert = fs::read_to_string(dir.join("id_ed25519-cert.pub"));
+    let linked = login("id_link.pub");
+    let linked_cert = dir.join("id_link-cert.pub").exists();
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(result, code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(cert.unwrap(), format!("{}\n", SSH_CERT));
+    assert_eq!(linked, code(PamResultCode::PAM_SUCCESS));
+    assert!(!linked_cert);
+}
+
+#[tokio::test]
+async fn authorized_keys_come_from_cached_login() {
+    // The token cache is per uid, so the PAM user has to be a local account.
+    if fs::metadata("/proc/self").unwrap().uid() != 0 {
+        return;
+    }
+    let idp = MockIdp::start(Scenario {
+        username: "root",
+        ..Scenario::default()
+    });
+    let cache_dir = env::temp_dir().join(format!("pam_oauth2_df-{}-keys", process::id()));
+    let cache_args = [
+        "token_cache".to_string(),
+        format!("token_cache_dir={}", cache_dir.display()),
+        format!("token_cache_key={}/key", cache_dir.display()),
+    ];
+    let pam = Pam::start(
+        &idp,
+        "root",
+        &cache_args.iter().map(String::as_str).collect::<Vec<_>>(),
+    );
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let args = cache_args
+        .iter()
+        .map(|arg| arg.split_once('=').unwrap_or((arg, "")))
+        .chain([("issuer", idp.url()), ("client_id", CLIENT_ID)])
+        .collect();
+    let keys = authorized_keys(&Config::from_args(&args).unwrap(), "root", None).await;
+    let _ = fs::remove_dir_all(&cache_dir);
+    assert_eq!(keys.unwrap(), [SSH_KEY]);
+}
+
+#[test]
+fn grace_period_skips_flow_from_same_host() {
+    // The logins are kept per uid, so the PAM user has to be a local account.
+    if fs::metadata("/proc/self").unwrap().uid() != 0 {
+        return;
+    }
+    let cache_dir = env::temp_dir().join(format!("pam_oauth2_df-{}-grace", process::id()));
+    let args = [
+        "grace_period=300".to_string(),
+        format!("token_cache_dir={}", cache_dir.display()),
+        format!("token_cache_key={}/key", cache_dir.display()),
+    ];
+    let args: Vec<_> = args.iter().map(String::as_str).collect();
+    let login = |rhost: &str, reply: TokenReply, reachable: bool| {
+        let idp = MockIdp::start(Scenario {
+            username: "root",
+            replies: vec![reply],
+            ..Scenario::default()
+        });
+        if !reachable {
+            idp.stop();
+        }
+        let pam = Pam::start(&idp, "root", &args);
+        pam.set_rhost(rhost);
+        (pam.authenticate(), idp.polls().len())
+    };
+    let first = login("192.0.2.1", TokenReply::Issue, true);
+    let again = login("192.0.2.1", TokenReply::Denied, true);
+    let outage = login("192.0.2.1", TokenReply::Denied, false);
+    let elsewhere = login("192.0.2.2", TokenReply::Denied, true);
+    let _ = fs::remove_dir_all(&cache_dir);
+    assert_eq!(first, (code(PamResultCode::PAM_SUCCESS), 1));
+    assert_eq!(again, (code(PamResultCode::PAM_SUCCESS), 0));
+    assert_eq!(outage, (code(PamResultCode::PAM_SUCCESS), 0));
+    assert_eq!(elsewhere.0, code(PamResultCode::PAM_AUTH_ERR));
+}
+
+#[test]
+fn offline_login_uses_cached_login_while_idp_is_unreachable() {
+    // The token cache is per uid, so the PAM user has to be a local account.
+    if fs::metadata("/proc/self").unwrap().uid() != 0 {
+        return;
+    }
+    let cache_dir = env::temp_dir().join(format!("pam_oauth2_df-{}-offline", process::id()));
+    let unreachable = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    // The provider documents stay cached, while its token and device authorization endpoints
+    // are down.
+    let args = [
+        "token_cache".to_string(),
+        format!("token_cache_dir={}", cache_dir.display()),
+        format!("token_cache_key={}/key", cache_dir.display()),
+        format!("cache_dir={}", cache_dir.display()),
+        "jwks_cache_ttl=3600".to_string(),
+        "discovery_cache_ttl=3600".to_string(),
+        "http_retries=0".to_string(),
+    ];
+    let down = [
+        format!("token_url=http://{}/token", unreachable),
+        format!("device_authorize_url=http://{}/device", unreachable),
+    ];
+    let down: Vec<_> = down.iter().map(String::as_str).collect();
+    let logins = |idp: &MockIdp, online: &[&str], offline: &[&str]| {
+        let login = |extra: &[&str]| {
+            let mut args: Vec<_> = args.iter().map(String::as_str).collect();
+            args.extend(extra);
+            let pam = Pam::start(idp, "root", &args);
+            let result = pam.authenticate();
+            (result, pam.acct_mgmt())
+        };
+        login(online);
+        let extra = [&down[..], &["offline_login=3600"], offline].concat();
+        (login(&extra), login(&down))
+    };
+    let idp = MockIdp::start(Scenario {
+        username: "root",
+        ..Scenario::default()
+    });
+    let (offline, without) = logins(&idp, &[], &[]);
+    // The id_tokens of these logins expired 100 seconds ago, within a clock_skew of 200 but not
+    // of the default 60.
+    let expired = MockIdp::start(Scenario {
+        username: "root",
+        issued_at: -400,
+        ..Scenario::default()
+    });
+    let (expired_offline, _) = logins(&expired, &["clock_skew=200"], &[]);
+    let (skewed_offline, _) = logins(&expired, &["clock_skew=200"], &["clock_skew=200"]);
+    let _ = fs::remove_dir_all(&cache_dir);
+    let success = code(PamResultCode::PAM_SUCCESS);
+    assert_eq!(offline, (success, success));
+    assert_eq!(without.0, code(PamResultCode::PAM_AUTHINFO_UNAVAIL));
+    assert_eq!(expired_offline.0, code(PamResultCode::PAM_AUTHINFO_UNAVAIL));
+    assert_eq!(skewed_offline, (success, success));
+}
+
+#[test]
+fn on_network_error_picks_result_of_unreachable_idp() {
+    let idp = MockIdp::start(Scenario::default());
+    let unreachable = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let issuer = format!("issuer=http://{}", unreachable);
+    let login = |extra: &[&str]| {
+        let mut args = vec![issuer.as_str(), "http_retries=0"];
+        args.extend(extra);
+        Pam::start(&idp, "alice", &args).authenticate()
+    };
+    assert_eq!(login(&[]), code(PamResultCode::PAM_AUTHINFO_UNAVAIL));
+    assert_eq!(
+        login(&["on_network_error=deny"]),
+        code(PamResultCode::PAM_AUTH_ERR)
+    );
+    // A stack of ignoring modules alone is denied by libpam, so the audit record tells what the
+    // module returned.
+    let audit_log = env::temp_dir().join(format!("pam_oauth2_df-{}-ignore.log", process::id()));
+    let _ = fs::remove_file(&audit_log);
+    login(&[
+        "on_network_error=ignore",
+        &format!("audit_log={}", audit_log.display()),
+    ]);
+    let record = fs::read_to_string(&audit_log);
+    let _ = fs::remove_file(&audit_log);
+    let record: serde_json::Value = serde_json::from_str(&record.unwrap()).unwrap();
+    assert_eq!(record["result"], "PAM_IGNORE");
+    assert_eq!(record["error"], "network");
+    assert_eq!(
+        login(&["on_network_error=unavail"]),
+        code(PamResultCode::PAM_AUTHINFO_UNAVAIL)
+    );
+    // Errors of the provider itself are left alone.
+    let denied = MockIdp::start(Scenario {
+        replies: vec![TokenReply::Denied],
+        ..Scenario::default()
+    });
+    assert_eq!(
+        Pam::start(&denied, "alice", &["on_network_error=ignore"]).authenticate(),
+        code(PamResultCode::PAM_AUTH_ERR)
+    );
+}
+
+#[test]
+fn applies_section_of_pam_service() {
+    let idp = MockIdp::start(Scenario::default());
+    let config = env::temp_dir().join(format!("pam_oauth2_df-{}-services.toml", process::id()));
+    let pam = Pam::start(&idp, "alice", &[&format!("config={}", config.display())]);
+    // The module reads the file when it runs, so it can still name the generated service.
+    fs::write(
+        &config,
+        format!(
+            "required_groups = [\"admins\"]\n\n[service.{}]\nrequired_groups = [\"users\"]\n",
+            pam.service
+        ),
+    )
+    .unwrap();
+    let result = pam.authenticate();
+    let sudo = Pam::start(
+        &idp,
+        "alice",
+        &[&format!("config={}", config.display()), "service=sudo"],
+    )
+    .authenticate();
+    let _ = fs::remove_file(&config);
+    assert_eq!(result, code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(sudo, code(PamResultCode::PAM_PERM_DENIED));
+}
+
+#[test]
+fn points_password_change_to_provider() {
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(
+        &idp,
+        "alice",
+        &["password_url={user}@idp.example.com/account"],
+    );
+    assert_eq!(pam.chauthtok(), code(PamResultCode::PAM_AUTHTOK_ERR));
+    assert_eq!(
+        pam.messages(),
+        [
+            "The password of alice is managed by the identity provider. \
+          Please change it at alice@idp.example.com/account."
+        ]
+    );
+}
+
+#[test]
+fn writes_audit_record_of_each_attempt() {
+    let audit_log = env::temp_dir().join(format!("pam_oauth2_df-{}-audit.log", process::id()));
+    let _ = fs::remove_file(&audit_log);
+    let arg = format!("audit_log={}", audit_log.display());
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &[&arg]);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let other = MockIdp::start(Scenario {
+        username: "mallory",
+        ..Scenario::default()
+    });
+    let pam = Pam::start(&other, "alice", &[&arg]);
+    pam.set_rhost("192.0.2.1");
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_AUTH_ERR));
+
+    let records: Vec<serde_json::Value> = fs::read_to_string(&audit_log)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    fs::remove_file(&audit_log).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["user"], "alice");
+    assert_eq!(records[0]["idp"], idp.url());
+    assert_eq!(records[0]["result"], "PAM_SUCCESS");
+    assert_eq!(records[0]["error"], serde_json::Value::Null);
+    assert!(records[0]["service"]
+        .as_str()
+        .unwrap()
+        .starts_with("oauth2-test-"));
+    assert!(records[0]["timestamp"].as_u64().unwrap() > 0);
+    assert!(records[0]["duration_ms"].is_u64());
+    assert_eq!(records[1]["rhost"], "192.0.2.1");
+    assert_eq!(records[1]["result"], "PAM_AUTH_ERR");
+    assert_eq!(records[1]["error"], "username");
+}
+
+#[test]
+fn runs_login_hooks() {
+    let dir = env::temp_dir().join(format!("pam_oauth2_df-{}-hooks", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let hook = dir.join("hook");
+    fs::write(
+        &hook,
+        format!(
+            "#!/bin/sh\necho \"$1 $PAM_USER $OAUTH2_RESULT $OAUTH2_ERROR $OIDC_EMAIL\" > {0}/env\n\
+             cat > {0}/stdin\n",
+            dir.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+    let args = [
+        format!("success_hook={}", hook.display()),
+        format!("failure_hook={}", hook.display()),
+        "hook_claims=email,groups".to_string(),
+        "claim_env=email:OIDC_EMAIL".to_string(),
+    ];
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+
+    let idp = MockIdp::start(Scenario::default());
+    assert_eq!(
+        Pam::start(&idp, "alice", &args).authenticate(),
+        code(PamResultCode::PAM_SUCCESS)
+    );
+    assert_eq!(read("env"), "alice alice PAM_SUCCESS  alice@example.com\n");
+    let input: serde_json::Value = serde_json::from_str(&read("stdin")).unwrap();
+    assert_eq!(input["user"], "alice");
+    assert_eq!(input["claims"]["groups"][0], "users");
+    assert_eq!(input["claims"].as_object().unwrap().len(), 2);
+
+    let other = MockIdp::start(Scenario {
+        username: "mallory",
+        ..Scenario::default()
+    });
+    assert_eq!(
+        Pam::start(&other, "alice", &args).authenticate(),
+        code(PamResultCode::PAM_AUTH_ERR)
+    );
+    let env = read("env");
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        env,
+        "alice alice PAM_AUTH_ERR username mallory@example.com\n"
+    );
+}
+
+#[test]
+fn provisions_missing_account() {
+    let dir = env::temp_dir().join(format!("pam_oauth2_df-{}-provision", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let useradd = dir.join("useradd");
+    fs::write(
+        &useradd,
+        format!(
+            "#!/bin/sh
+echo \"$@\" > {}/args
+[ \"$3\" = /srv/home/alice ]
+",
+            dir.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&useradd, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::write(dir.join("shells"), "# login shells\n/bin/bash\n/bin/zsh\n").unwrap();
+    let args = |home: &str| {
+        [
+            "provision".to_string(),
+            format!("provision_command={}", useradd.display()),
+            format!("provision_shells={}/shells", dir.display()),
+            format!("provision_home={}", home),
+            "provision_group=users".to_string(),
+            "provision_uid_range=20000-29999".to_string(),
+        ]
+    };
+    let idp = MockIdp::start(Scenario::default());
+
+    let ok = args("/srv/home/{user}");
+    let ok: Vec<&str> = ok.iter().map(String::as_str).collect();
+    assert_eq!(
+        Pam::start(&idp, "alice", &ok).authenticate(),
+        code(PamResultCode::PAM_SUCCESS)
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("args")).unwrap(),
+        "-m -d /srv/home/alice -s /bin/bash -g users -K UID_MIN=20000 -K UID_MAX=29999 alice\n"
+    );
+
+    let mut ids = args("/srv/home/{user}").to_vec();
+    ids.extend([
+        "uid_claim=uidNumber".to_string(),
+        "gid_claim=gidNumber".to_string(),
+    ]);
+    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+    assert_eq!(
+        Pam::start(&idp, "alice", &ids).authenticate(),
+        code(PamResultCode::PAM_SUCCESS)
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("args")).unwrap(),
+        "-m -d /srv/home/alice -s /bin/bash -u 20001 -g 20001 -K UID_MIN=20000 -K UID_MAX=29999 \
+         alice\n"
+    );
+    // Claimed IDs outside the range, or of root, are refused rather than passed to useradd.
+    fs::remove_file(dir.join("args")).unwrap();
+    let other_range = [&ids[..], &["provision_uid_range=30000-39999"]].concat();
+    assert_eq!(
+        Pam::start(&idp, "alice", &other_range).authenticate(),
+        code(PamResultCode::PAM_PERM_DENIED)
+    );
+    let root_group = MockIdp::start(Scenario {
+        extra_claims: vec![("gidNumber", 0.into())],
+        ..Scenario::default()
+    });
+    let with_root = [&ids[..], &["provision_uid_range=0-29999"]].concat();
+    assert_eq!(
+        Pam::start(&root_group, "alice", &with_root).authenticate(),
+        code(PamResultCode::PAM_PERM_DENIED)
+    );
+    assert!(!dir.join("args").exists());
+
+    let mut attributes = args("/srv/home/{user}").to_vec();
+    attributes.extend([
+        "shell_claim=loginShell".to_string(),
+        "home_claim=homeDirectory".to_string(),
+        "gecos_claim=name".to_string(),
+    ]);
+    let attributes: Vec<&str> = attributes.iter().map(String::as_str).collect();
+    assert_eq!(
+        Pam::start(&idp, "alice", &attributes).authenticate(),
+        code(PamResultCode::PAM_SUCCESS)
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("args")).unwrap(),
+        "-m -d /srv/home/alice -s /bin/zsh -c Test User -g users -K UID_MIN=20000 \
+         -K UID_MAX=29999 alice\n"
+    );
+
+    // Homes and shells of claims and templates alike that root did not intend are refused.
+    let refused = |claims: Vec<(&'static str, serde_json::Value)>, extra: &[&str]| {
+        let _ = fs::remove_file(dir.join("args"));
+        let idp = MockIdp::start(Scenario {
+            extra_claims: claims,
+            ..Scenario::default()
+        });
+        let result = Pam::start(&idp, "alice", &[&attributes[..], extra].concat()).authenticate();
+        assert_eq!(result, code(PamResultCode::PAM_SYSTEM_ERR));
+        assert!(!dir.join("args").exists());
+    };
+    refused(vec![("homeDirectory", "/etc".into())], &[]);
+    refused(vec![("homeDirectory", "/srv/home/../../root".into())], &[]);
+    refused(vec![("loginShell", "/usr/bin/python3".into())], &[]);
+    refused(vec![], &["provision_home=/srv/home/{user}/../../etc"]);
+    refused(
+        vec![("loginShell", "".into())],
+        &["provision_shell=/bin/fish"],
+    );
+
+    let failing = args("/nonexistent/{user}");
+    let failing: Vec<&str> = failing.iter().map(String::as_str).collect();
+    let result = Pam::start(&idp, "alice", &failing).authenticate();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(result, code(PamResultCode::PAM_SYSTEM_ERR));
+}
+
+#[test]
+fn refuses_account_with_other_uid_than_claim() {
+    let idp = MockIdp::start(Scenario {
+        username: "root",
+        ..Scenario::default()
+    });
+    assert_eq!(
+        Pam::start(&idp, "root", &["uid_claim=uidNumber"]).authenticate(),
+        code(PamResultCode::PAM_PERM_DENIED)
+    );
+    assert_eq!(
+        Pam::start(&idp, "root", &["uid_claim=/missing"]).authenticate(),
+        code(PamResultCode::PAM_SUCCESS)
+    );
+}
+
+#[test]
+fn exports_metrics_to_textfile() {
+    let dir = env::temp_dir().join(format!("pam_oauth2_df-{}-metrics", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let textfile = dir.join("pam_oauth2.prom");
+    let args = [
+        format!("cache_dir={}", dir.display()),
+        format!("metrics_textfile={}", textfile.display()),
+    ];
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let idp = MockIdp::start(Scenario::default());
+    let pam = Pam::start(&idp, "alice", &args);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_SUCCESS));
+    let other = MockIdp::start(Scenario {
+        username: "mallory",
+        ..Scenario::default()
+    });
+    let pam = Pam::start(&other, "alice", &args);
+    assert_eq!(pam.authenticate(), code(PamResultCode::PAM_AUTH_ERR));
+
+    let text = fs::read_to_string(&textfile).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines.contains(&"pam_oauth2_attempts_total 2"), "{}", text);
+    assert!(lines.contains(&"pam_oauth2_successes_total 1"), "{}", text);
+    assert!(
+        lines.contains(&"pam_oauth2_failures_total{error=\"username\"} 1"),
+        "{}",
+        text
+    );
+    assert!(
+        lines.contains(&"pam_oauth2_flow_duration_seconds_bucket{flow=\"device\",le=\"+Inf\"} 2"),
+        "{}",
+        text
+    );
+    assert!(
+        lines.contains(&"pam_oauth2_flow_duration_seconds_count{flow=\"device\"} 2"),
+        "{}",
+        text
+    );
+}
+
+#[test]
+fn refuses_flows_from_host_with_too_many_failures() {
+    let dir = env::temp_dir().join(format!("pam_oauth2_df-{}-rate-limit", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let cache_dir = format!("cache_dir={}", dir.display());
+    let args = [cache_dir.as_str(), "max_failures=2"];
+    let idp = MockIdp::start(Scenario {
+        username: "mallory",
+        ..Scenario::default()
+    });
+    let login = |rhost: &str| {
+        let pam = Pam::start(&idp, "alice", &args);
+        pam.set_rhost(rhost);
+        pam.authenticate()
+    };
+    assert_eq!(login("192.0.2.1"), code(PamResultCode::PAM_AUTH_ERR));
+    assert_eq!(login("192.0.2.1"), code(PamResultCode::PAM_AUTH_ERR));
+    let started = idp.requests("/device");
+    assert_eq!(login("192.0.2.1"), code(PamResultCode::PAM_MAXTRIES));
+    assert_eq!(idp.requests("/device"), started);
+    assert_eq!(login("192.0.2.2"), code(PamResultCode::PAM_AUTH_ERR));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn announces_device_authorization_on_dbus() {
+    let dir = env::temp_dir().join(format!("pam_oauth2_df-{}-dbus", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let dbus_send = dir.join("dbus-send");
+    fs::write(
+        &dbus_send,
+        format!("#!/bin/sh\necho \"$@\" >> {}/signals\n", dir.display()),
+    )
+    .unwrap();
+    fs::set_permissions(&dbus_send, fs::Permissions::from_mode(0o755)).unwrap();
+    let idp = MockIdp::start(Scenario::default());
+    let dbus_send = format!("dbus_send={}", dbus_send.display());
+    let pam = Pam::start(&idp, "alice", &["dbus_signal", &dbus_send]);
+    let result = pam.authenticate();
+    let signals = fs::read_to_string(dir.join("signals"));
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(result, code(PamResultCode::PAM_SUCCESS));
+    let signals = signals.unwrap();
+    let signals: Vec<&str> = signals.lines().collect();
+    let started = format!(
+        "--system --type=signal /io/github/chalharu/PamOauth2 \
+         io.github.chalharu.PamOauth2.DeviceAuthorization string:{} \
+         string:alice string: string:{}/activate string:ABCD-EFGH",
+        pam.service,
+        idp.url()
+    );
+    assert_eq!(signals.len(), 2);
+    assert!(signals[0].starts_with(&started), "{}", signals[0]);
+    assert_eq!(
+        signals[1],
+        format!(
+            "--system --type=signal /io/github/chalharu/PamOauth2 \
+             io.github.chalharu.PamOauth2.DeviceAuthorizationEnded string:{} \
+             string:alice string: string:ABCD-EFGH string:success",
+            pam.service
+        )
+    );
+}
+
+#[test]
+fn logs_in_through_broker() {
+    let idp = MockIdp::start(Scenario::default());
+    // The module gets a provider of its own, which the broker must spare it from using.
+    let other = MockIdp::start(Scenario::default());
+    let socket = env::temp_dir().join(format!("pam_oauth2_df-{}-broker.sock", process::id()));
+    let _ = fs::remove_file(&socket);
+    let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let config = Config::from_args(&HashMap::from([
+        ("issuer", idp.url()),
+        ("client_id", CLIENT_ID),
+    ]))
+    .unwrap();
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let listener = tokio::net::UnixListener::from_std(listener).unwrap();
+                pam_oauth2_df::run_broker(listener, config).await
+            })
+    });
+    let broker = format!("broker={}", socket.display());
+    let pam = Pam::start(&other, "alice", &[&broker]);
+    let result = pam.authenticate();
+    // Nor does the module need a provider at all.
+    let bare = Pam::start_with("alice", &[&broker]).authenticate();
+    let _ = fs::remove_file(&socket);
+    assert_eq!(result, code(PamResultCode::PAM_SUCCESS));
+    assert_eq!(bare, code(PamResultCode::PAM_SUCCESS));
+    assert!(pam
+        .messages()
+        .iter()
+        .any(|message| message.contains("ABCD-EFGH")));
+    assert!(!idp.polls().is_empty());
+    assert!(other.polls().is_empty());
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use common::{MockIdp, Scenario, CLIENT_ID, SSH_CERT};
+use pam_oauth2_df::{DeviceFlowClient, Endpoints, Error, HttpConfig, SshCa, SshCaKind};
+
+const PUBLIC_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKey alice@laptop";
+
+async fn id_token(idp: &MockIdp) -> String {
+    let client = DeviceFlowClient::new(Endpoints::discover(idp.url()).await.unwrap(), CLIENT_ID);
+    let device_auth = client.authorize_device().await.unwrap();
+    let token = client.poll_token(&device_auth).await.unwrap();
+    token.id_token.unwrap()
+}
+
+#[tokio::test]
+async fn vault_signs_key_with_id_token() {
+    let idp = MockIdp::start(Scenario::default());
+    let ca = SshCa::new(SshCaKind::Vault, idp.url(), HttpConfig::default()).with_role("users");
+    let cert = ca
+        .sign(&id_token(&idp).await, PUBLIC_KEY, "alice")
+        .await
+        .unwrap();
+    assert_eq!(cert, SSH_CERT);
+    let request = idp.last_body("/v1/ssh/sign/users").unwrap();
+    assert!(
+        request.contains("\"valid_principals\":\"alice\""),
+        "{}",
+        request
+    );
+}
+
+#[tokio::test]
+async fn step_ca_signs_key_with_id_token() {
+    let idp = MockIdp::start(Scenario::default());
+    let ca = SshCa::new(SshCaKind::StepCa, idp.url(), HttpConfig::default());
+    let cert = ca
+        .sign(&id_token(&idp).await, PUBLIC_KEY, "alice")
+        .await
+        .unwrap();
+    assert_eq!(cert, SSH_CERT);
+    let request = idp.last_body("/1.0/sign-ssh").unwrap();
+    assert!(request.contains("\"publicKey\":\"AAAAC3NzaC1lZDI1NTE5AAAAIKey\""));
+}
+
+#[tokio::test]
+async fn rejected_signing_is_an_error() {
+    let idp = MockIdp::start(Scenario::default());
+    let ca = SshCa::new(SshCaKind::Vault, idp.url(), HttpConfig::default()).with_role("users");
+    match ca.sign("not-a-jwt", PUBLIC_KEY, "alice").await {
+        Err(Error::SshCa(message)) => assert!(message.contains("403"), "{}", message),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}